@@ -1,28 +1,32 @@
+use std::collections::VecDeque;
 use std::ops::DerefMut;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Mutex,
-};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 use cpal::{
     traits::{DeviceTrait, EventLoopTrait, HostTrait},
     StreamData, UnknownTypeOutputBuffer,
 };
-use j2ds::{ElasticPopResult, ElasticRingBuffer};
 use log::info;
 
 use j2gbc::AudioSink;
 
+mod resampler;
+
+use resampler::Resampler;
+
+type Queue = Arc<Mutex<VecDeque<(f32, f32)>>>;
+
+const LOCAL_QUEUE_SIZE: usize = 10;
+
 pub struct CpalSink {
-    queue: Arc<Mutex<ElasticRingBuffer<(f32, f32)>>>,
+    queue: Queue,
+    // Samples held back until there's enough to be worth taking the lock
     local_queue: Vec<(f32, f32)>,
+    resampler: Resampler,
+    capacity: usize,
     rate: u64,
-
-    samples: Vec<f32>,
-    channel_buffers: [Vec<f32>; 4],
-
-    capture_config: Arc<CaptureConfig>,
 }
 
 impl CpalSink {
@@ -40,11 +44,9 @@ impl CpalSink {
             .map_err(|e| e.to_string())?;
         event_loop.play_stream(stream_id).unwrap();
 
-        let queue = Arc::new(Mutex::new(ElasticRingBuffer::new(
-            format.sample_rate.0 as usize / 4,
-            (0., 0.),
-            format.sample_rate.0 as usize / 8,
-        )));
+        // A quarter second, which is kept about half full
+        let capacity = format.sample_rate.0 as usize / 4;
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
         let q2 = queue.clone();
 
         thread::spawn(move || {
@@ -53,120 +55,83 @@ impl CpalSink {
 
         Ok(CpalSink {
             queue,
-            local_queue: Vec::with_capacity(10),
+            local_queue: Vec::with_capacity(LOCAL_QUEUE_SIZE + 1),
+            resampler: Resampler::new(),
+            capacity,
             rate: u64::from(format.sample_rate.0),
-            samples: Vec::new(),
-            channel_buffers: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
-            capture_config: Arc::new(CaptureConfig::default()),
         })
     }
 
-    pub fn get_capture_config(&self) -> Arc<CaptureConfig> {
-        self.capture_config.clone()
-    }
-}
-
-impl AudioSink for CpalSink {
-    fn emit_sample(&mut self, sample: (f32, f32)) {
-        self.local_queue.push(sample);
-        if self.local_queue.len() >= 10 {
-            self.queue
-                .lock()
-                .unwrap()
-                .push_back_slice(self.local_queue.as_slice());
-            self.local_queue.clear();
-        }
-
-        if self.capture_config.mixed.load(Ordering::Relaxed) {
-            self.samples.push(sample.0);
-            self.samples.push(sample.1);
+    /// A handle for pacing emulation off the device's playback
+    pub fn pacer(&self) -> AudioPacer {
+        AudioPacer {
+            queue: self.queue.clone(),
+            rate: self.rate,
+            target: self.capacity / 2,
         }
     }
+}
 
-    fn sample_rate(&self) -> u64 {
-        self.rate
-    }
+/// Tells how much to emulate to keep the device's queue topped up, so the
+/// emulator runs at whatever pace the device actually plays at.
+#[derive(Clone)]
+pub struct AudioPacer {
+    queue: Queue,
+    rate: u64,
+    target: usize,
+}
 
-    fn emit_raw_chans(&mut self, chans: [f32; 4]) {
-        for ((source, dest), config) in chans
-            .iter()
-            .zip(self.channel_buffers.iter_mut())
-            .zip(self.capture_config.channels.iter())
-        {
-            if config.load(Ordering::Relaxed) {
-                dest.push(*source);
-            }
-        }
+impl AudioPacer {
+    pub fn duration_to_run(&self) -> Duration {
+        let queued = self.queue.lock().unwrap().len();
+        let missing = self.target.saturating_sub(queued);
+        Duration::from_secs_f64(missing as f64 / self.rate as f64)
     }
 }
 
-impl Drop for CpalSink {
-    fn drop(&mut self) {
-        let spec = hound::WavSpec {
-            channels: 2,
-            sample_rate: self.rate as u32,
-            bits_per_sample: 32,
-            sample_format: hound::SampleFormat::Float,
-        };
-
-        if !self.samples.is_empty() {
-            let mut writer = hound::WavWriter::create("target/audio.wav", spec).unwrap();
-            for s in &self.samples {
-                writer.write_sample(*s).unwrap();
+impl AudioSink for CpalSink {
+    fn emit_sample(&mut self, sample: (f32, f32)) {
+        self.resampler.push(sample, &mut self.local_queue);
+        if self.local_queue.len() >= LOCAL_QUEUE_SIZE {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(self.local_queue.drain(..));
+            if queue.len() > self.capacity {
+                info!(target: "events", "Audio queue overrun");
+                let excess = queue.len() - self.capacity;
+                queue.drain(..excess);
             }
+            self.resampler
+                .set_fill(queue.len() as f32 / self.capacity as f32);
         }
+    }
 
-        for i in 0..4 {
-            if !self.channel_buffers[i].is_empty() {
-                let mut writer =
-                    hound::WavWriter::create(format!("target/chan{}.wav", i), spec).unwrap();
-                for s in self.channel_buffers[i].iter() {
-                    writer.write_sample(*s).unwrap();
-                    writer.write_sample(*s).unwrap();
-                }
-            }
-        }
+    fn sample_rate(&self) -> u64 {
+        self.rate
     }
 }
 
-fn feed_cpal_events<E: EventLoopTrait>(
-    event_loop: &E,
-    queue: Arc<Mutex<ElasticRingBuffer<(f32, f32)>>>,
-) {
-    let mut temp_buffer = Vec::new();
+fn feed_cpal_events<E: EventLoopTrait>(event_loop: &E, queue: Queue) {
+    // Running dry holds the last sample rather than dropping to silence,
+    // which would click
+    let mut last = (0., 0.);
     event_loop.run(move |_, data| {
         if let Ok(StreamData::Output {
             buffer: UnknownTypeOutputBuffer::F32(mut buffer),
         }) = data
         {
-            temp_buffer.resize(buffer.deref_mut().len() / 2, (0., 0.));
-            let r = queue
-                .lock()
-                .unwrap()
-                .pop_front_slice(temp_buffer.as_mut_slice());
-
-            if r != ElasticPopResult::Exact && r != ElasticPopResult::Empty {
-                info!(target: "events", "Pop front result {:?}", r);
+            let mut queue = queue.lock().unwrap();
+            let wanted = buffer.deref_mut().len() / 2;
+            if queue.len() < wanted && !queue.is_empty() {
+                info!(target: "events", "Audio queue underrun");
             }
 
-            for (i, value) in temp_buffer.iter().enumerate() {
-                buffer.deref_mut()[2 * i] = value.0;
-                buffer.deref_mut()[2 * i + 1] = value.1;
+            for frame in buffer.deref_mut().chunks_mut(2) {
+                if let Some(s) = queue.pop_front() {
+                    last = s;
+                }
+                frame[0] = last.0;
+                frame[1] = last.1;
             }
         }
     });
 }
-
-pub struct CaptureConfig {
-    pub mixed: AtomicBool,
-    pub channels: [AtomicBool; 4],
-}
-
-impl Default for CaptureConfig {
-    fn default() -> CaptureConfig {
-        CaptureConfig {
-            mixed: false.into(),
-            channels: [false.into(), false.into(), false.into(), false.into()],
-        }
-    }
-}
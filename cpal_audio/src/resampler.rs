@@ -0,0 +1,68 @@
+// Largest change to the playback rate, well below what can be heard as a
+// change in pitch
+const MAX_RATE_DELTA: f64 = 0.005;
+
+/// Plays the emulator's samples slightly faster or slower so the device's
+/// queue stays around half full, instead of slowly running dry or
+/// overflowing as the emulator's clock drifts from the device's.
+pub struct Resampler {
+    previous: (f32, f32),
+    // Position of the next output sample between the previous input sample
+    // and the one being pushed
+    phase: f64,
+    step: f64,
+}
+
+impl Default for Resampler {
+    fn default() -> Resampler {
+        Resampler::new()
+    }
+}
+
+impl Resampler {
+    pub fn new() -> Resampler {
+        Resampler {
+            previous: (0., 0.),
+            phase: 0.,
+            step: 1.,
+        }
+    }
+
+    /// Adjusts the rate to how full the queue is, from 0 to 1. Below half
+    /// full every sample gets stretched a little, and above it squeezed.
+    pub fn set_fill(&mut self, fill: f32) {
+        let fill = f64::from(fill).clamp(0., 1.);
+        self.step = 1. / (1. + MAX_RATE_DELTA * (1. - 2. * fill));
+    }
+
+    pub fn push(&mut self, sample: (f32, f32), out: &mut Vec<(f32, f32)>) {
+        while self.phase < 1. {
+            let t = self.phase as f32;
+            out.push((
+                self.previous.0 + (sample.0 - self.previous.0) * t,
+                self.previous.1 + (sample.1 - self.previous.1) * t,
+            ));
+            self.phase += self.step;
+        }
+        self.phase -= 1.;
+        self.previous = sample;
+    }
+}
+
+#[test]
+fn test_rate_follows_fill() {
+    let count = |fill: f32| {
+        let mut resampler = Resampler::new();
+        resampler.set_fill(fill);
+        let mut out = Vec::new();
+        for _ in 0..10000 {
+            resampler.push((0.5, -0.5), &mut out);
+        }
+        // Apart from the start, a constant input stays constant
+        assert!(out[2..].iter().all(|s| *s == (0.5, -0.5)));
+        out.len() as i64
+    };
+    assert_eq!(count(0.5), 10000);
+    assert!((count(0.) - 10050).abs() <= 1);
+    assert!((count(1.) - 9950).abs() <= 1);
+}
@@ -0,0 +1,251 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use gif::SetParameter;
+use j2gbc::{Framebuffer, SCREEN_SIZE};
+
+type Pixel = [u8; 3];
+
+// Most viewers show frames with a shorter delay than this (in hundredths of a
+// second) for far longer, so faster frames get skipped instead
+const MIN_FRAME_DELAY: u64 = 2;
+
+const MAX_PALETTE_SIZE: usize = 256;
+
+/// Records frames as they're displayed into an animated GIF.
+///
+/// Each frame gets its own palette: frames with at most 256 colors, like
+/// every DMG frame, keep their exact colors, while anything more colorful is
+/// reduced with a median cut.
+pub struct GifRecorder<W: Write> {
+    encoder: gif::Encoder<W>,
+    pending: Option<(Vec<Pixel>, Instant)>,
+    // Delays only have a resolution of 10ms, so the remainder is carried over
+    // to keep the clip from drifting
+    leftover: Duration,
+}
+
+impl GifRecorder<BufWriter<File>> {
+    pub fn create(path: &str) -> io::Result<GifRecorder<BufWriter<File>>> {
+        GifRecorder::new(BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write> GifRecorder<W> {
+    pub fn new(out: W) -> io::Result<GifRecorder<W>> {
+        let mut encoder = gif::Encoder::new(out, SCREEN_SIZE.0 as u16, SCREEN_SIZE.1 as u16, &[])?;
+        encoder.set(gif::Repeat::Infinite)?;
+        Ok(GifRecorder {
+            encoder,
+            pending: None,
+            leftover: Duration::default(),
+        })
+    }
+
+    pub fn push_frame(&mut self, fb: &Framebuffer, now: Instant) -> io::Result<()> {
+        let pixels = fb.raw();
+        if let Some((pending, shown_at)) = self.pending.as_ref() {
+            let elapsed = now - *shown_at + self.leftover;
+            if pending.as_slice() == pixels || centiseconds(elapsed) < MIN_FRAME_DELAY {
+                return Ok(());
+            }
+            self.leftover = elapsed - Duration::from_millis(centiseconds(elapsed) * 10);
+            let pending = pending.clone();
+            self.write_frame(&pending, centiseconds(elapsed))?;
+        }
+        self.pending = Some((pixels.to_vec(), now));
+        Ok(())
+    }
+
+    /// Writes out the last frame, the file is complete once the recorder is
+    /// dropped
+    pub fn finish(&mut self, now: Instant) -> io::Result<()> {
+        if let Some((pending, shown_at)) = self.pending.take() {
+            let delay = centiseconds(now - shown_at + self.leftover).max(MIN_FRAME_DELAY);
+            self.write_frame(&pending, delay)?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self, pixels: &[Pixel], delay: u64) -> io::Result<()> {
+        let (palette, indices) = quantize(pixels);
+        let frame = gif::Frame {
+            width: SCREEN_SIZE.0 as u16,
+            height: SCREEN_SIZE.1 as u16,
+            delay: delay.min(u64::from(u16::MAX)) as u16,
+            palette: Some(palette.iter().flatten().cloned().collect()),
+            buffer: Cow::Owned(indices),
+            ..gif::Frame::default()
+        };
+        self.encoder.write_frame(&frame)
+    }
+}
+
+/// Starts and stops clips with a single hotkey, saving them next to the ROM
+#[derive(Default)]
+pub struct ClipToggle {
+    recording: Option<(String, GifRecorder<BufWriter<File>>)>,
+}
+
+impl ClipToggle {
+    pub fn toggle(&mut self, rom_path: &str) {
+        if let Some((path, mut recorder)) = self.recording.take() {
+            match recorder.finish(Instant::now()) {
+                Ok(()) => println!("Saved clip {}", path),
+                Err(e) => println!("Failed to save clip {}: {}", path, e),
+            }
+        } else {
+            let path = crate::timestamped_path(rom_path, "gif");
+            match GifRecorder::create(&path) {
+                Ok(recorder) => {
+                    println!("Recording clip {}", path);
+                    self.recording = Some((path, recorder));
+                }
+                Err(e) => println!("Failed to start clip {}: {}", path, e),
+            }
+        }
+    }
+
+    pub fn push_frame(&mut self, fb: &Framebuffer) {
+        if let Some((path, recorder)) = self.recording.as_mut() {
+            if let Err(e) = recorder.push_frame(fb, Instant::now()) {
+                println!("Stopped recording clip {}: {}", path, e);
+                self.recording = None;
+            }
+        }
+    }
+}
+
+fn centiseconds(d: Duration) -> u64 {
+    d.as_millis() as u64 / 10
+}
+
+fn quantize(pixels: &[Pixel]) -> (Vec<Pixel>, Vec<u8>) {
+    let mut counts = HashMap::new();
+    for p in pixels {
+        *counts.entry(*p).or_insert(0u32) += 1;
+    }
+    let mut colors: Vec<(Pixel, u32)> = counts.into_iter().collect();
+    colors.sort();
+
+    let (palette, lookup): (Vec<Pixel>, HashMap<Pixel, u8>) = if colors.len() <= MAX_PALETTE_SIZE {
+        let lookup = colors
+            .iter()
+            .enumerate()
+            .map(|(i, (c, _))| (*c, i as u8))
+            .collect();
+        (colors.into_iter().map(|(c, _)| c).collect(), lookup)
+    } else {
+        let palette = median_cut(colors.clone());
+        let lookup = colors
+            .iter()
+            .map(|(c, _)| (*c, nearest(&palette, *c)))
+            .collect();
+        (palette, lookup)
+    };
+
+    let indices = pixels.iter().map(|p| lookup[p]).collect();
+    (palette, indices)
+}
+
+// Splits the colors into boxes along their widest channel until there's one
+// box per palette entry, each becoming the average of the colors in it
+fn median_cut(colors: Vec<(Pixel, u32)>) -> Vec<Pixel> {
+    let mut boxes = vec![colors];
+    while boxes.len() < MAX_PALETTE_SIZE {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .max_by_key(|(_, b)| widest_channel(b).1)
+            .map(|(i, _)| i);
+        let i = match widest {
+            Some(i) => i,
+            None => break,
+        };
+
+        let mut b = boxes.swap_remove(i);
+        let channel = widest_channel(&b).0;
+        b.sort_by_key(|(c, _)| c[channel]);
+        let half = b.iter().map(|(_, n)| u64::from(*n)).sum::<u64>() / 2;
+        let mut seen = 0;
+        let split = b
+            .iter()
+            .position(|(_, n)| {
+                seen += u64::from(*n);
+                seen >= half
+            })
+            .unwrap_or(0)
+            .max(1)
+            .min(b.len() - 1);
+        let rest = b.split_off(split);
+        boxes.push(b);
+        boxes.push(rest);
+    }
+
+    boxes
+        .iter()
+        .map(|b| {
+            let total = b.iter().map(|(_, n)| u64::from(*n)).sum::<u64>();
+            let mut avg = [0; 3];
+            for (c, a) in avg.iter_mut().enumerate() {
+                let sum = b
+                    .iter()
+                    .map(|(p, n)| u64::from(p[c]) * u64::from(*n))
+                    .sum::<u64>();
+                *a = (sum / total) as u8;
+            }
+            avg
+        })
+        .collect()
+}
+
+fn widest_channel(colors: &[(Pixel, u32)]) -> (usize, u8) {
+    (0..3)
+        .map(|c| {
+            let min = colors.iter().map(|(p, _)| p[c]).min().unwrap_or(0);
+            let max = colors.iter().map(|(p, _)| p[c]).max().unwrap_or(0);
+            (c, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn nearest(palette: &[Pixel], color: Pixel) -> u8 {
+    let distance = |p: &Pixel| -> i32 {
+        (0..3)
+            .map(|c| (i32::from(p[c]) - i32::from(color[c])).pow(2))
+            .sum()
+    };
+    palette
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, p)| distance(p))
+        .map_or(0, |(i, _)| i as u8)
+}
+
+#[test]
+fn test_quantize_exact() {
+    let pixels = [[0, 0, 0], [255, 0, 0], [0, 0, 0], [1, 2, 3]];
+    let (palette, indices) = quantize(&pixels);
+    assert_eq!(palette.len(), 3);
+    for (p, i) in pixels.iter().zip(indices.iter()) {
+        assert_eq!(palette[*i as usize], *p);
+    }
+}
+
+#[test]
+fn test_quantize_adaptive() {
+    let pixels: Vec<Pixel> = (0..1024)
+        .map(|i| [(i % 32 * 8) as u8, (i / 32 * 8) as u8, 0])
+        .collect();
+    let (palette, indices) = quantize(&pixels);
+    assert_eq!(palette.len(), MAX_PALETTE_SIZE);
+    for (p, i) in pixels.iter().zip(indices.iter()) {
+        let q = palette[*i as usize];
+        assert!((0..3).all(|c| (i32::from(p[c]) - i32::from(q[c])).abs() <= 16));
+    }
+}
@@ -1,9 +1,12 @@
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use j2gbc::System;
+use j2gbc::{AudioSink, ChannelCaptureSink, Framebuffer, NullSink, System, TeeSink, WavFileSink};
+
+pub mod clip;
+pub mod scaler;
 
 pub struct DeltaTimer {
     last_time: Instant,
@@ -26,6 +29,29 @@ impl DeltaTimer {
     }
 }
 
+/// Decides how much to emulate each time a frontend gets around to it
+pub enum Pacer {
+    /// Keep up with the system clock
+    WallClock(DeltaTimer),
+    /// Keep up with something else, like an audio device playing back
+    External(Box<dyn FnMut() -> Duration>),
+}
+
+impl Pacer {
+    pub fn next_duration(&mut self) -> Duration {
+        match self {
+            Pacer::WallClock(timer) => timer.elapsed(),
+            Pacer::External(f) => f(),
+        }
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Pacer {
+        Pacer::WallClock(DeltaTimer::default())
+    }
+}
+
 pub fn parse_args() -> clap::ArgMatches<'static> {
     clap::App::new("j2gbc -- DMG and CGB emulator")
         .author("Jennifer Wilcox <jennifer@nitori.org>")
@@ -34,8 +60,81 @@ pub fn parse_args() -> clap::ArgMatches<'static> {
                 .short("m")
                 .long("mode")
                 .takes_value(true)
-                .help("Operate as a DMG or CGB [default: cgb]")
-                .possible_values(&["dmg", "cgb"]),
+                .help("Operate as a DMG, SGB or CGB [default: cgb]")
+                .possible_values(&["dmg", "sgb", "cgb"]),
+        )
+        .arg(
+            clap::Arg::with_name("color-correction")
+                .long("color-correction")
+                .takes_value(true)
+                .help("Color correction applied to CGB palettes [default: raw]")
+                .possible_values(&["raw", "accurate", "gba", "reduced-contrast"]),
+        )
+        .arg(
+            clap::Arg::with_name("dmg-palette")
+                .long("dmg-palette")
+                .takes_value(true)
+                .help("Colors used for games running in DMG mode [default: green]")
+                .possible_values(&["green", "pocket", "light", "high-contrast", "color-blind"]),
+        )
+        .arg(
+            clap::Arg::with_name("compat-palette")
+                .long("compat-palette")
+                .takes_value(true)
+                .help("Colors used for DMG games running on a CGB, like holding a button combo during boot [default: picked from the game's title]")
+                .possible_values(&[
+                    "up", "up-a", "up-b", "left", "left-a", "left-b", "down", "down-a", "down-b",
+                    "right", "right-a", "right-b",
+                ]),
+        )
+        .arg(
+            clap::Arg::with_name("frame-blend")
+                .long("frame-blend")
+                .takes_value(true)
+                .help("Blend frames together like the slow response of a real LCD [default: off]")
+                .possible_values(&["blend", "dmg", "cgb"]),
+        )
+        .arg(
+            clap::Arg::with_name("scaler")
+                .long("scaler")
+                .takes_value(true)
+                .help("Filter used to upscale the screen [default: none]")
+                .possible_values(&[
+                    "none", "nearest2x", "nearest3x", "nearest4x", "scale2x", "scale3x", "xbr2x",
+                    "dot-matrix",
+                ]),
+        )
+        .arg(
+            clap::Arg::with_name("record-av")
+                .long("record-av")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Record every frame and sample losslessly to FILE.y4m and FILE.wav"),
+        )
+        .arg(
+            clap::Arg::with_name("capture-audio")
+                .long("capture-audio")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Write the audio output to FILE as a WAV"),
+        )
+        .arg(
+            clap::Arg::with_name("capture-channels")
+                .long("capture-channels")
+                .takes_value(true)
+                .value_name("PREFIX")
+                .help("Write each channel's output before mixing to PREFIX-chan1.wav to PREFIX-chan4.wav"),
+        )
+        .arg(
+            clap::Arg::with_name("log-vgm")
+                .long("log-vgm")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Log the music to FILE as a VGM, for playing in other players"),
+        )
+        .arg(clap::Arg::with_name("sgb-border")
+            .long("sgb-border")
+            .help("Show the border uploaded by SGB enhanced games around the screen, when running as an SGB")
         )
         .arg(clap::Arg::with_name("no-pedantic-mmu")
             .long("no-pedantic-mmu")
@@ -45,9 +144,20 @@ pub fn parse_args() -> clap::ArgMatches<'static> {
             .long("no-audio")
             .help("Disable audio")
         )
+        .arg(clap::Arg::with_name("audio-sync")
+            .long("audio-sync")
+            .help("Pace emulation off audio playback instead of the system clock, trading occasional dropped or doubled frames for perfectly smooth audio")
+        )
+        .arg(
+            clap::Arg::with_name("track")
+                .long("track")
+                .takes_value(true)
+                .value_name("N")
+                .help("Track to start on when playing a GBS file, counting from 1 [default: the file's first track]"),
+        )
         .arg(
             clap::Arg::with_name("rom")
-                .help("ROM file to load")
+                .help("ROM file to load, or GBS file to play")
                 .required(true),
         ).get_matches()
 }
@@ -73,3 +183,62 @@ impl Saver {
         }
     }
 }
+
+/// A path next to the ROM, named after the ROM and the current time so
+/// repeated captures don't overwrite each other
+pub fn timestamped_path(rom_path: &str, extension: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    format!("{}-{}.{}", rom_path, timestamp, extension)
+}
+
+pub fn save_screenshot(framebuffer: &Framebuffer, rom_path: &str) {
+    let path = timestamped_path(rom_path, "png");
+    match File::create(&path).and_then(|mut f| framebuffer.write_png(&mut f)) {
+        Ok(()) => println!("Saved screenshot {}", path),
+        Err(e) => println!("Failed to save screenshot {}: {}", path, e),
+    }
+}
+
+// Captures run at this rate when there's no audio device to match
+const CAPTURE_SAMPLE_RATE: u64 = 48000;
+
+/// Puts the sinks asked for with --capture-audio and --capture-channels
+/// next to the audio device's sink, if there is one
+pub fn with_capture_sinks(
+    args: &clap::ArgMatches<'static>,
+    output: Option<Box<dyn AudioSink + Send>>,
+) -> Box<dyn AudioSink + Send> {
+    let rate = output
+        .as_ref()
+        .map_or(CAPTURE_SAMPLE_RATE, |s| s.sample_rate());
+    let mut sinks: Vec<Box<dyn AudioSink + Send>> = output.into_iter().collect();
+
+    if let Some(path) = args.value_of("capture-audio") {
+        match WavFileSink::create(path, rate) {
+            Ok(sink) => {
+                println!("Capturing audio to {}", path);
+                sinks.push(Box::new(sink));
+            }
+            Err(e) => println!("Failed to capture audio to {}: {}", path, e),
+        }
+    }
+    if let Some(prefix) = args.value_of("capture-channels") {
+        let paths = [1, 2, 3, 4].map(|n| Some(format!("{}-chan{}.wav", prefix, n)));
+        match ChannelCaptureSink::create(paths, rate) {
+            Ok(sink) => {
+                println!("Capturing channels to {}-chan*.wav", prefix);
+                sinks.push(Box::new(sink));
+            }
+            Err(e) => println!("Failed to capture channels to {}: {}", prefix, e),
+        }
+    }
+
+    match sinks.len() {
+        0 => Box::new(NullSink),
+        1 => sinks.pop().unwrap(),
+        _ => Box::new(TeeSink::new(sinks)),
+    }
+}
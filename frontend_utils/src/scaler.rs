@@ -0,0 +1,252 @@
+use std::str::FromStr;
+
+use j2gbc::Framebuffer;
+
+pub type Rgba = [u8; 4];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scaler {
+    /// Repeats each pixel in an NxN block
+    Nearest(usize),
+    /// AdvMAME2x, rounds off diagonal steps without adding new colors
+    Scale2x,
+    /// AdvMAME3x
+    Scale3x,
+    /// 2xBR, smooths edges by blending along them
+    Xbr2x,
+    /// NxN blocks with a light gap between them, like the DMG's LCD grid
+    DotMatrix(usize),
+}
+
+impl FromStr for Scaler {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Scaler::Nearest(1)),
+            "nearest2x" => Ok(Scaler::Nearest(2)),
+            "nearest3x" => Ok(Scaler::Nearest(3)),
+            "nearest4x" => Ok(Scaler::Nearest(4)),
+            "scale2x" => Ok(Scaler::Scale2x),
+            "scale3x" => Ok(Scaler::Scale3x),
+            "xbr2x" => Ok(Scaler::Xbr2x),
+            "dot-matrix" => Ok(Scaler::DotMatrix(4)),
+            _ => Err(format!("Unknown scaler {}", s)),
+        }
+    }
+}
+
+// How far the gaps of the dot matrix are lightened, out of 256
+const DOT_MATRIX_GAP_LIGHTEN: u16 = 96;
+
+impl Scaler {
+    pub fn factor(self) -> usize {
+        match self {
+            Scaler::Nearest(n) | Scaler::DotMatrix(n) => n,
+            Scaler::Scale2x | Scaler::Xbr2x => 2,
+            Scaler::Scale3x => 3,
+        }
+    }
+
+    pub fn output_size(self, (width, height): (usize, usize)) -> (usize, usize) {
+        (width * self.factor(), height * self.factor())
+    }
+
+    /// Scales a frame into rows of RGBA pixels. `output` is resized to fit.
+    pub fn scale(self, input: &Framebuffer, output: &mut Vec<Rgba>) {
+        let (width, height) = input.size();
+        let factor = self.factor();
+        let out_width = width * factor;
+        output.resize(out_width * height * factor, [0, 0, 0, 0xFF]);
+
+        let get = |x: isize, y: isize| {
+            let x = x.max(0).min(width as isize - 1) as usize;
+            let y = y.max(0).min(height as isize - 1) as usize;
+            let [r, g, b] = input.get(x, y);
+            [r, g, b, 0xFF]
+        };
+
+        let mut block = vec![[0; 4]; factor * factor];
+        for y in 0..height {
+            for x in 0..width {
+                let (xi, yi) = (x as isize, y as isize);
+                let p = |dx: isize, dy: isize| get(xi + dx, yi + dy);
+                match self {
+                    Scaler::Nearest(_) => block.iter_mut().for_each(|b| *b = p(0, 0)),
+                    Scaler::Scale2x => scale2x(&p, &mut block),
+                    Scaler::Scale3x => scale3x(&p, &mut block),
+                    Scaler::Xbr2x => xbr2x(&p, &mut block),
+                    Scaler::DotMatrix(n) => dot_matrix(p(0, 0), n, &mut block),
+                }
+
+                for (i, pixel) in block.iter().enumerate() {
+                    let out_x = x * factor + i % factor;
+                    let out_y = y * factor + i / factor;
+                    output[out_y * out_width + out_x] = *pixel;
+                }
+            }
+        }
+    }
+}
+
+fn scale2x(p: &dyn Fn(isize, isize) -> Rgba, out: &mut [Rgba]) {
+    let (a, b, c, d, e) = (p(0, -1), p(1, 0), p(-1, 0), p(0, 1), p(0, 0));
+    out[0] = if c == a && c != d && a != b { a } else { e };
+    out[1] = if a == b && a != c && b != d { b } else { e };
+    out[2] = if d == c && d != b && c != a { c } else { e };
+    out[3] = if b == d && b != a && d != c { d } else { e };
+}
+
+fn scale3x(p: &dyn Fn(isize, isize) -> Rgba, out: &mut [Rgba]) {
+    let (a, b, c) = (p(-1, -1), p(0, -1), p(1, -1));
+    let (d, e, f) = (p(-1, 0), p(0, 0), p(1, 0));
+    let (g, h, i) = (p(-1, 1), p(0, 1), p(1, 1));
+
+    out.iter_mut().for_each(|o| *o = e);
+    if b == h || d == f {
+        return;
+    }
+
+    let pick = |cond: bool, v: Rgba| if cond { v } else { e };
+    out[0] = pick(d == b, d);
+    out[1] = pick((d == b && e != c) || (b == f && e != a), b);
+    out[2] = pick(b == f, f);
+    out[3] = pick((d == b && e != g) || (d == h && e != a), d);
+    out[5] = pick((b == f && e != i) || (h == f && e != c), f);
+    out[6] = pick(d == h, d);
+    out[7] = pick((d == h && e != i) || (h == f && e != g), h);
+    out[8] = pick(h == f, f);
+}
+
+fn xbr2x(p: &dyn Fn(isize, isize) -> Rgba, out: &mut [Rgba]) {
+    // Each corner uses the same kernel as the bottom right one, with the
+    // neighbourhood rotated around the center
+    for &(cx, cy) in &[(1, 1), (-1, 1), (-1, -1), (1, -1)] {
+        let rotated = |x: isize, y: isize| {
+            let (x, y) = match (cx, cy) {
+                (1, 1) => (x, y),
+                (-1, 1) => (-y, x),
+                (-1, -1) => (-x, -y),
+                _ => (y, -x),
+            };
+            p(x, y)
+        };
+        let (e, i) = (rotated(0, 0), rotated(1, 1));
+        let (b, c, d, f) = (
+            rotated(0, -1),
+            rotated(1, -1),
+            rotated(-1, 0),
+            rotated(1, 0),
+        );
+        let (g, h) = (rotated(-1, 1), rotated(0, 1));
+        let (f4, i4, h5, i5) = (rotated(2, 0), rotated(2, 1), rotated(0, 2), rotated(1, 2));
+
+        let across = color_distance(e, c)
+            + color_distance(e, g)
+            + color_distance(i, h5)
+            + color_distance(i, f4)
+            + 4. * color_distance(h, f);
+        let along = color_distance(h, d)
+            + color_distance(h, i5)
+            + color_distance(f, i4)
+            + color_distance(f, b)
+            + 4. * color_distance(e, i);
+
+        let corner = (cx + 1) / 2 + (cy + 1);
+        out[corner as usize] = if across < along {
+            let edge = if color_distance(e, f) <= color_distance(e, h) {
+                f
+            } else {
+                h
+            };
+            mix(e, edge)
+        } else {
+            e
+        };
+    }
+}
+
+fn dot_matrix(pixel: Rgba, n: usize, out: &mut [Rgba]) {
+    let lighten = |c: u8| c + ((u16::from(0xFF - c) * DOT_MATRIX_GAP_LIGHTEN) >> 8) as u8;
+    let gap = [
+        lighten(pixel[0]),
+        lighten(pixel[1]),
+        lighten(pixel[2]),
+        pixel[3],
+    ];
+    for (i, o) in out.iter_mut().enumerate() {
+        let on_gap = i % n == n - 1 || i / n == n - 1;
+        *o = if on_gap { gap } else { pixel };
+    }
+}
+
+// Weighted YUV distance, as used by xBR
+fn color_distance(a: Rgba, b: Rgba) -> f32 {
+    let dr = f32::from(a[0]) - f32::from(b[0]);
+    let dg = f32::from(a[1]) - f32::from(b[1]);
+    let db = f32::from(a[2]) - f32::from(b[2]);
+    let y = 0.299 * dr + 0.587 * dg + 0.114 * db;
+    let u = -0.169 * dr - 0.331 * dg + 0.5 * db;
+    let v = 0.5 * dr - 0.419 * dg - 0.081 * db;
+    48. * y.abs() + 7. * u.abs() + 6. * v.abs()
+}
+
+fn mix(a: Rgba, b: Rgba) -> Rgba {
+    let m = |i: usize| ((u16::from(a[i]) + u16::from(b[i])) / 2) as u8;
+    [m(0), m(1), m(2), m(3)]
+}
+
+#[cfg(test)]
+fn test_frame(pixels: &[(usize, usize)]) -> Framebuffer {
+    let mut fb = Framebuffer::new(j2gbc::SCREEN_SIZE);
+    for y in 0..j2gbc::SCREEN_SIZE.1 {
+        for x in 0..j2gbc::SCREEN_SIZE.0 {
+            fb.set(x, y, [255, 255, 255]);
+        }
+    }
+    for (x, y) in pixels {
+        fb.set(*x, *y, [0, 0, 0]);
+    }
+    fb
+}
+
+#[test]
+fn test_nearest() {
+    let fb = test_frame(&[(1, 0)]);
+    let mut out = Vec::new();
+    Scaler::Nearest(3).scale(&fb, &mut out);
+
+    let width = j2gbc::SCREEN_SIZE.0 * 3;
+    assert_eq!(out.len(), width * j2gbc::SCREEN_SIZE.1 * 3);
+    assert_eq!(out[2], [255, 255, 255, 255]);
+    assert_eq!(out[3], [0, 0, 0, 255]);
+    assert_eq!(out[2 * width + 5], [0, 0, 0, 255]);
+    assert_eq!(out[3 * width + 5], [255, 255, 255, 255]);
+}
+
+#[test]
+fn test_scale2x_rounds_diagonals() {
+    // A diagonal step: the inner corners get filled in
+    let fb = test_frame(&[(1, 0), (0, 1)]);
+    let mut out = Vec::new();
+    Scaler::Scale2x.scale(&fb, &mut out);
+
+    let width = j2gbc::SCREEN_SIZE.0 * 2;
+    assert_eq!(out[width + 1], [0, 0, 0, 255]);
+    assert_eq!(out[2 * width + 2], [0, 0, 0, 255]);
+    assert_eq!(out[0], [255, 255, 255, 255]);
+    assert_eq!(out[3 * width + 3], [255, 255, 255, 255]);
+}
+
+#[test]
+fn test_dot_matrix_gap() {
+    let fb = test_frame(&[(0, 0)]);
+    let mut out = Vec::new();
+    Scaler::DotMatrix(4).scale(&fb, &mut out);
+
+    let width = j2gbc::SCREEN_SIZE.0 * 4;
+    assert_eq!(out[0], [0, 0, 0, 255]);
+    assert_eq!(out[3], [95, 95, 95, 255]);
+    assert_eq!(out[3 * width], [95, 95, 95, 255]);
+    assert_eq!(out[4], [255, 255, 255, 255]);
+}
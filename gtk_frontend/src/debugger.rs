@@ -21,6 +21,8 @@ struct Context {
     register_hl: gtk::Label,
     register_sp: gtk::Label,
     register_pc: gtk::Label,
+
+    channel_toggles: [gtk::CheckButton; 4],
 }
 
 pub fn load_debugger(system: &SystemRef) -> gtk::Window {
@@ -48,6 +50,16 @@ pub fn load_debugger(system: &SystemRef) -> gtk::Window {
             context.halted();
         }));
 
+    for (channel, toggle) in context.channel_toggles.iter().enumerate() {
+        toggle.set_active(context.system.borrow().is_channel_enabled(channel));
+        toggle.connect_toggled(enclose!((context) move |toggle| {
+            context
+                .system
+                .borrow_mut()
+                .set_channel_enabled(channel, toggle.get_active());
+        }));
+    }
+
     window.show_all();
     window
 }
@@ -68,6 +80,13 @@ impl Context {
             register_hl: builder.get_object("register_HL").unwrap(),
             register_sp: builder.get_object("register_SP").unwrap(),
             register_pc: builder.get_object("register_PC").unwrap(),
+
+            channel_toggles: [
+                builder.get_object("channel1_toggle").unwrap(),
+                builder.get_object("channel2_toggle").unwrap(),
+                builder.get_object("channel3_toggle").unwrap(),
+                builder.get_object("channel4_toggle").unwrap(),
+            ],
         }
     }
 
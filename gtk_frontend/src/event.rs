@@ -1,5 +1,12 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use enclose::enclose;
-use frontend_utils::DeltaTimer;
+use frontend_utils::{
+    clip::ClipToggle,
+    scaler::{Rgba, Scaler},
+    Pacer,
+};
 use gdk_pixbuf::Pixbuf;
 use gtk::prelude::*;
 use gtk::Image;
@@ -7,19 +14,28 @@ use j2gbc::Button;
 
 use crate::SystemRef;
 
-pub fn install_event_handlers<W>(key_widget: &W, system: &SystemRef)
-where
+pub fn install_event_handlers<W>(
+    key_widget: &W,
+    system: &SystemRef,
+    clip: &Rc<RefCell<ClipToggle>>,
+    rom_path: String,
+) where
     W: WidgetExt,
 {
-    key_widget.connect_key_press_event(enclose!((system) move |_, event| {
-        if let Some(button) = keycode_to_button(event.get_keyval()) {
-            system.borrow_mut().activate_button(button);
+    key_widget.connect_key_press_event(enclose!((system, clip) move |_, event| {
+        let key = event.get_keyval();
+        if key == gdk::keys::constants::F12 {
+            frontend_utils::save_screenshot(system.borrow().get_raw_framebuffer(), &rom_path);
+        } else if key == gdk::keys::constants::F11 {
+            clip.borrow_mut().toggle(&rom_path);
+        } else if let Some(button) = keycode_to_button(key) {
+            system.borrow_mut().activate_button(0, button);
         }
         Inhibit(false)
     }));
     key_widget.connect_key_release_event(enclose!((system) move |_, event| {
         if let Some(button) = keycode_to_button(event.get_keyval()) {
-            system.borrow_mut().deactivate_button(button);
+            system.borrow_mut().deactivate_button(0, button);
         }
         Inhibit(false)
     }));
@@ -39,15 +55,53 @@ fn keycode_to_button(keycode: gdk::keys::Key) -> Option<Button> {
     }
 }
 
-pub fn run_frame(image: &Image, pixbuf: &Pixbuf, system: &SystemRef, dt: &mut DeltaTimer) {
+/// How the emulated screen is turned into the pixbuf that gets displayed
+pub struct ScreenOutput {
+    show_sgb_border: bool,
+    scaler: Scaler,
+    scaled: Vec<Rgba>,
+    clip: Rc<RefCell<ClipToggle>>,
+}
+
+impl ScreenOutput {
+    pub fn new(
+        show_sgb_border: bool,
+        scaler: Scaler,
+        clip: Rc<RefCell<ClipToggle>>,
+    ) -> ScreenOutput {
+        ScreenOutput {
+            show_sgb_border,
+            scaler,
+            scaled: Vec::new(),
+            clip,
+        }
+    }
+}
+
+pub fn run_frame(
+    image: &Image,
+    pixbuf: &Pixbuf,
+    system: &SystemRef,
+    pacer: &mut Pacer,
+    output: &mut ScreenOutput,
+) {
     let mut sys = system.borrow_mut();
-    sys.run_for_duration(&dt.elapsed());
+    sys.run_for_duration(&pacer.next_duration());
+    output
+        .clip
+        .borrow_mut()
+        .push_frame(sys.get_raw_framebuffer());
 
-    let fb = sys.get_framebuffer();
+    let fb = if output.show_sgb_border {
+        sys.get_sgb_framebuffer().unwrap()
+    } else {
+        sys.get_framebuffer()
+    };
+    output.scaler.scale(fb, &mut output.scaled);
     unsafe {
         let count = pixbuf.get_pixels().len();
         std::ptr::copy_nonoverlapping(
-            fb.raw().as_ptr() as *const u8,
+            output.scaled.as_ptr() as *const u8,
             pixbuf.get_pixels().as_mut_ptr(),
             count,
         );
@@ -1,23 +1,27 @@
 use std::fs::File;
 use std::io::Read;
-use std::sync::Arc;
 
-use cpal_audio::{CaptureConfig, CpalSink};
-use frontend_utils::Saver;
-use j2gbc::{AudioSink, NullSink, System};
+use cpal_audio::CpalSink;
+use frontend_utils::{Pacer, Saver};
+use j2gbc::{AudioSink, DmgPalettePreset, System};
 
-pub fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver, Arc<CaptureConfig>) {
+pub fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver, Pacer) {
     let cart_path = args.value_of("rom").unwrap();
 
     let cart_file = File::open(cart_path).unwrap();
 
-    let (sink, capture_config): (Box<dyn AudioSink + Send>, _) = if !args.is_present("no-audio") {
+    let mut pacer = Pacer::default();
+    let output: Option<Box<dyn AudioSink + Send>> = if !args.is_present("no-audio") {
         let sink = CpalSink::new().unwrap();
-        let config = sink.get_capture_config();
-        (Box::new(sink), config)
+        if args.is_present("audio-sync") {
+            let audio = sink.pacer();
+            pacer = Pacer::External(Box::new(move || audio.duration_to_run()));
+        }
+        Some(Box::new(sink))
     } else {
-        (Box::new(NullSink), Arc::new(CaptureConfig::default()))
+        None
     };
+    let sink = frontend_utils::with_capture_sinks(args, output);
 
     let cgb_mode = if let Some(m) = args.value_of("mode") {
         m == "cgb"
@@ -26,7 +30,31 @@ pub fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver, Arc<Capt
     };
 
     let mut system = System::new(cart_file, sink, cgb_mode).unwrap();
+    if args.value_of("mode") == Some("sgb") && !system.enable_sgb() {
+        println!("Cart doesn't support the SGB, running as a DMG");
+    }
     system.set_mmu_pedantic(!args.is_present("no-pedantic-mmu"));
+    if let Some(c) = args.value_of("color-correction") {
+        system.set_color_correction(c.parse().unwrap());
+    }
+    if let Some(p) = args.value_of("dmg-palette") {
+        system.set_dmg_palette(p.parse::<DmgPalettePreset>().unwrap().palettes());
+    }
+    if let Some(p) = args.value_of("compat-palette") {
+        system.set_compat_palette(p.parse().unwrap());
+    }
+    if let Some(b) = args.value_of("frame-blend") {
+        system.set_frame_blend(Some(b.parse().unwrap()));
+    }
+    if let Some(path) = args.value_of("record-av") {
+        let (video, audio) = (format!("{}.y4m", path), format!("{}.wav", path));
+        system.start_av_recording(&video, &audio).unwrap();
+        println!("Recording to {} and {}", video, audio);
+    }
+    if let Some(path) = args.value_of("log-vgm") {
+        system.start_vgm_logging(path).unwrap();
+        println!("Logging music to {}", path);
+    }
 
     let save_path = format!("{}.sav", cart_path);
     if let Ok(mut f) = File::open(&save_path) {
@@ -38,5 +66,5 @@ pub fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver, Arc<Capt
     }
     let saver = Saver::new(save_path.as_str());
 
-    (system, saver, capture_config)
+    (system, saver, pacer)
 }
@@ -1,10 +1,11 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use frontend_utils::{clip::ClipToggle, scaler::Scaler};
 use gio::prelude::*;
 use gtk::prelude::*;
 use gtk::{Application, ApplicationWindow};
-use j2gbc::{System, SCREEN_SIZE};
+use j2gbc::{System, SCREEN_SIZE, SGB_SCREEN_SIZE};
 
 mod debugger;
 mod event;
@@ -20,7 +21,17 @@ pub fn main() {
 
     application.connect_activate(|app| {
         let args = frontend_utils::parse_args();
-        let (system, mut saver, _) = loader::load_system(&args);
+        let (system, mut saver, mut pacer) = loader::load_system(&args);
+        let show_sgb_border =
+            args.is_present("sgb-border") && system.get_sgb_framebuffer().is_some();
+        let scaler = args
+            .value_of("scaler")
+            .map_or(Scaler::Nearest(1), |s| s.parse().unwrap());
+        let size = scaler.output_size(if show_sgb_border {
+            SGB_SCREEN_SIZE
+        } else {
+            SCREEN_SIZE
+        });
         let system = Rc::new(RefCell::new(system));
 
         let window = ApplicationWindow::new(app);
@@ -29,24 +40,39 @@ pub fn main() {
 
         let pixbuf = gdk_pixbuf::Pixbuf::new(
             gdk_pixbuf::Colorspace::Rgb,
-            false,
+            true,
             8,
-            SCREEN_SIZE.0 as i32,
-            SCREEN_SIZE.1 as i32,
+            size.0 as i32,
+            size.1 as i32,
         )
         .unwrap();
 
         let image = gtk::Image::from_pixbuf(Some(&pixbuf));
         window.add(&image);
 
-        let mut dt = frontend_utils::DeltaTimer::default();
+        let clip = Rc::new(RefCell::new(ClipToggle::default()));
+        let mut output = event::ScreenOutput::new(show_sgb_border, scaler, clip.clone());
+
+        // The system might not get dropped when the application quits, so
+        // finish recording here to make sure everything gets written out
+        let recording_system = system.clone();
+        window.connect_destroy(move |_| {
+            let mut system = recording_system.borrow_mut();
+            system.stop_av_recording();
+            system.stop_vgm_logging();
+        });
 
-        event::install_event_handlers(&window, &system);
+        event::install_event_handlers(
+            &window,
+            &system,
+            &clip,
+            args.value_of("rom").unwrap().to_string(),
+        );
         debugger::load_debugger(&system);
 
         glib::timeout_add_local(16, move || {
             saver.maybe_save(&system.borrow());
-            event::run_frame(&image, &pixbuf, &system, &mut dt);
+            event::run_frame(&image, &pixbuf, &system, &mut pacer, &mut output);
             glib::source::Continue(true)
         });
 
@@ -2,7 +2,6 @@
 extern crate serde_derive;
 
 use std::{
-    collections::HashMap,
     fs::File,
     io::{Read, Write},
     path::Path,
@@ -19,6 +18,21 @@ struct ConformanceTestData {
     pass_addr: u16,
 }
 
+// Mooneye's tests report back in registers rather than on screen, so they
+// only need a ROM and a time limit
+#[derive(Deserialize)]
+struct MooneyeTestData {
+    name: String,
+    path: String,
+    max_runtime: u64,
+}
+
+#[derive(Deserialize)]
+struct ConformanceTests {
+    test: Vec<ConformanceTestData>,
+    mooneye_test: Vec<MooneyeTestData>,
+}
+
 fn main() {
     build_conformance_roms();
 
@@ -29,10 +43,9 @@ fn main() {
     let mut input_d = Vec::new();
     let mut input_f = File::open("conformance_tests.toml").unwrap();
     input_f.read_to_end(&mut input_d).unwrap();
-    let tests: HashMap<String, Vec<ConformanceTestData>> =
-        toml::from_slice(input_d.as_slice()).unwrap();
+    let tests: ConformanceTests = toml::from_slice(input_d.as_slice()).unwrap();
 
-    for test in &tests["test"] {
+    for test in &tests.test {
         write!(
             output,
             r#"
@@ -47,6 +60,21 @@ fn main() {
         )
         .unwrap();
     }
+
+    for test in &tests.mooneye_test {
+        write!(
+            output,
+            r#"
+                mooneye_test! {{
+                    name: {},
+                    path: "{}",
+                    max_runtime: {},
+                }}
+            "#,
+            test.name, test.path, test.max_runtime
+        )
+        .unwrap();
+    }
 }
 
 fn build_conformance_roms() {
@@ -1,14 +1,27 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
 use log::error;
 
-use super::mem::{Address, MemDevice, Ram, RNG_SND_WAV_RAM};
+use super::mem::{Address, MemDevice, RNG_SND_REGS, RNG_SND_WAV_RAM};
 use crate::error::ExecutionError;
 
+mod blip;
+mod envelope;
+mod high_pass;
+mod length;
 mod mixer;
 mod noise;
+mod sinks;
 mod square;
 mod synth;
+mod vgm;
 mod wave;
 
+pub use sinks::{ChannelCaptureSink, TeeSink, WavFileSink};
+pub use synth::ChannelSnapshot;
+
 const REG_NR10: Address = Address(0xFF10);
 const REG_NR11: Address = Address(0xFF11);
 const REG_NR12: Address = Address(0xFF12);
@@ -31,8 +44,18 @@ const REG_NR50: Address = Address(0xFF24);
 const REG_NR51: Address = Address(0xFF25);
 const REG_NR52: Address = Address(0xFF26);
 
+// Bits that always read back as 1, for every address from NR10 up to the wave
+// RAM. Write-only bits and unused registers read as set.
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
+
 pub struct Audio {
-    wav: Ram,
     nr10: u8,
     nr11: u8,
     nr12: u8,
@@ -53,7 +76,11 @@ pub struct Audio {
     nr44: u8,
     nr50: u8,
     nr51: u8,
-    nr52: u8,
+
+    powered: bool,
+    cgb_mode: bool,
+
+    vgm: Option<vgm::VgmLogger<BufWriter<File>>>,
 
     pub synth: synth::Synth,
 }
@@ -77,9 +104,8 @@ impl AudioSink for NullSink {
 }
 
 impl Audio {
-    pub fn new(sink: Box<dyn AudioSink + Send>) -> Audio {
+    pub fn new(sink: Box<dyn AudioSink + Send>, cgb_mode: bool) -> Audio {
         Audio {
-            wav: Ram::new(RNG_SND_WAV_RAM.len()),
             nr10: 0,
             nr11: 0,
             nr12: 0,
@@ -100,9 +126,14 @@ impl Audio {
             nr44: 0,
             nr50: 0,
             nr51: 0,
-            nr52: 0,
 
-            synth: synth::Synth::new(sink),
+            // The boot ROM leaves the APU powered on
+            powered: true,
+            cgb_mode,
+
+            vgm: None,
+
+            synth: synth::Synth::new(sink, cgb_mode),
         }
     }
 }
@@ -110,246 +141,414 @@ impl Audio {
 impl MemDevice for Audio {
     fn read(&self, a: Address) -> Result<u8, ExecutionError> {
         if a.in_(RNG_SND_WAV_RAM) {
-            self.wav.read(a - RNG_SND_WAV_RAM.0)
-        } else {
-            match a {
-                REG_NR10 => Ok(self.nr10),
-                REG_NR11 => Ok(self.nr11),
-                REG_NR12 => Ok(self.nr12),
-                REG_NR14 => Ok(self.nr14),
-                REG_NR21 => Ok(self.nr21),
-                REG_NR22 => Ok(self.nr22),
-                REG_NR23 => Ok(self.nr23),
-                REG_NR24 => Ok(self.nr24),
-                REG_NR30 => Ok(self.nr30),
-                REG_NR31 => Ok(self.nr31),
-                REG_NR32 => Ok(self.nr32),
-                REG_NR33 => Ok(self.nr33),
-                REG_NR34 => Ok(self.nr34),
-                REG_NR41 => Ok(self.nr41),
-                REG_NR42 => Ok(self.nr42),
-                REG_NR43 => Ok(self.nr43),
-                REG_NR44 => Ok(self.nr44),
-                REG_NR50 => Ok(self.nr50),
-                REG_NR51 => Ok(self.nr51),
-                REG_NR52 => {
-                    let mut v = 0b1000_0000 & self.nr52;
-                    if self.synth.chan1.is_active() {
-                        v |= 0b0000_0001;
-                    }
-                    if self.synth.chan2.is_active() {
-                        v |= 0b0000_0010;
-                    }
-                    if self.synth.chan3.is_active() {
-                        v |= 0b0000_0100;
-                    }
-                    if self.synth.chan4.is_active() {
-                        v |= 0b0000_1000;
-                    }
-                    Ok(v)
+            let offset = (a - RNG_SND_WAV_RAM.0).0 as usize;
+            return Ok(self.synth.chan3.read_ram(offset, self.cgb_mode));
+        }
+
+        let v = match a {
+            REG_NR10 => self.nr10,
+            REG_NR11 => self.nr11,
+            REG_NR12 => self.nr12,
+            REG_NR13 => self.nr13,
+            REG_NR14 => self.nr14,
+            REG_NR21 => self.nr21,
+            REG_NR22 => self.nr22,
+            REG_NR23 => self.nr23,
+            REG_NR24 => self.nr24,
+            REG_NR30 => self.nr30,
+            REG_NR31 => self.nr31,
+            REG_NR32 => self.nr32,
+            REG_NR33 => self.nr33,
+            REG_NR34 => self.nr34,
+            REG_NR41 => self.nr41,
+            REG_NR42 => self.nr42,
+            REG_NR43 => self.nr43,
+            REG_NR44 => self.nr44,
+            REG_NR50 => self.nr50,
+            REG_NR51 => self.nr51,
+            REG_NR52 if self.powered => {
+                let mut v = 0b1000_0000;
+                if self.synth.chan1.is_enabled() {
+                    v |= 0b0000_0001;
+                }
+                if self.synth.chan2.is_enabled() {
+                    v |= 0b0000_0010;
                 }
-                _ => {
-                    error!("Unimplemented sound register {:?}", a);
-                    Err(ExecutionError::BusError)
+                if self.synth.chan3.is_enabled() {
+                    v |= 0b0000_0100;
                 }
+                if self.synth.chan4.is_enabled() {
+                    v |= 0b0000_1000;
+                }
+                v
             }
-        }
+            _ => 0,
+        };
+        Ok(v | READ_MASKS[(a - RNG_SND_REGS.0).0 as usize])
     }
 
     fn write(&mut self, a: Address, v: u8) -> Result<(), ExecutionError> {
+        self.log_write(a, v);
+
         if a.in_(RNG_SND_WAV_RAM) {
-            let offset = a - RNG_SND_WAV_RAM.0;
-            self.wav.write(offset, v).unwrap();
-            self.synth
-                .chan3
-                .write_sample(bits_to_sample(v & 0b1111), offset.0 as usize * 2 + 1);
-            self.synth
-                .chan3
-                .write_sample(bits_to_sample(v >> 4), offset.0 as usize * 2);
+            let offset = (a - RNG_SND_WAV_RAM.0).0 as usize;
+            self.synth.chan3.write_ram(offset, v, self.cgb_mode);
             Ok(())
-        } else {
-            match a {
-                REG_NR10 => {
-                    self.nr10 = v;
-                    self.synth.chan1.set_freqeuncy_sweepers(
-                        v >> 4 & 0b111,
-                        v & 0b111,
-                        v & 0b1000 == 0,
-                    );
-                    Ok(())
-                }
-                REG_NR11 => {
-                    self.nr11 = v;
-                    self.synth.chan1.set_duty_cycle(v >> 6);
-                    self.synth.chan1.update_length(v & 0b0011_1111);
-                    Ok(())
-                }
-                REG_NR12 => {
-                    self.nr12 = v;
-                    self.synth.chan1.set_vol_env_period(v & 0b111);
-                    self.synth.chan1.increment_vol_env(v & 0b1000 != 0);
-                    self.synth.chan1.set_volume(v >> 4);
-                    Ok(())
-                }
-                REG_NR13 => {
-                    self.nr13 = v;
-                    self.synth
-                        .chan1
-                        .set_frequency_from_bits(self.nr14, self.nr13);
-                    Ok(())
-                }
-                REG_NR14 => {
-                    self.nr14 = v;
-                    self.synth
-                        .chan1
-                        .set_frequency_from_bits(self.nr14, self.nr13);
-                    self.synth.chan1.use_length_counter(v & 0b0100_0000 != 0);
-                    if v & 0b1000_0000 != 0 {
-                        self.synth.chan1.reset();
-                    }
-                    Ok(())
-                }
-                REG_NR21 => {
-                    self.nr21 = v;
-                    self.synth.chan2.set_duty_cycle(v >> 6);
-                    self.synth.chan2.update_length(v & 0b0011_1111);
-                    Ok(())
-                }
-                REG_NR22 => {
-                    self.nr22 = v;
-                    self.synth.chan2.set_vol_env_period(v & 0b111);
-                    self.synth.chan2.increment_vol_env(v & 0b1000 != 0);
-                    self.synth.chan2.set_volume(v >> 4);
-                    Ok(())
-                }
-                REG_NR23 => {
-                    self.nr23 = v;
-                    self.synth
-                        .chan2
-                        .set_frequency_from_bits(self.nr24, self.nr23);
-                    Ok(())
-                }
-                REG_NR24 => {
-                    self.nr24 = v;
-                    self.synth
-                        .chan2
-                        .set_frequency_from_bits(self.nr24, self.nr23);
-                    self.synth.chan2.use_length_counter(v & 0b0100_0000 != 0);
-                    if v & 0b1000_0000 != 0 {
-                        self.synth.chan2.reset();
-                    }
-                    Ok(())
-                }
-                REG_NR30 => {
-                    self.nr30 = v;
-                    self.synth.chan3.enabled = v & 0b1000_0000 != 0;
-                    Ok(())
-                }
-                REG_NR31 => {
-                    self.nr31 = v;
-                    self.synth.chan3.set_len(v);
-                    Ok(())
-                }
-                REG_NR32 => {
-                    self.nr32 = v;
-                    self.synth.chan3.vol_multiplier = match (v >> 5) & 0b11 {
-                        0 => 0.,
-                        1 => 1.,
-                        2 => 0.5,
-                        3 => 0.25,
-                        _ => unreachable!(),
-                    };
-                    Ok(())
-                }
-                REG_NR33 => {
-                    self.nr33 = v;
-                    self.synth
-                        .chan3
-                        .set_frequency_from_bits(self.nr34, self.nr33);
-                    Ok(())
-                }
-                REG_NR34 => {
-                    self.nr34 = v;
-                    self.synth
-                        .chan3
-                        .set_frequency_from_bits(self.nr34, self.nr33);
-                    self.synth.chan3.use_len = v & 0b0100_0000 != 0;
-                    if v & 0b1000_0000 != 0 {
-                        self.synth.chan3.reset();
-                    }
-                    Ok(())
-                }
-                REG_NR41 => {
-                    self.nr41 = v;
-                    self.synth.chan4.set_len(v & 0b0011_1111);
-                    Ok(())
-                }
-                REG_NR42 => {
-                    self.nr42 = v;
-                    self.synth.chan4.set_vol_env_period(v & 0b111);
-                    self.synth.chan4.increment_vol_env(v & 0b1000 != 0);
-                    self.synth.chan4.set_volume(v >> 4);
-                    Ok(())
-                }
-                REG_NR43 => {
-                    self.nr43 = v;
-                    self.synth.chan4.set_frequency_from_bits(v);
-                    Ok(())
-                }
-                REG_NR44 => {
-                    self.nr44 = v;
-                    self.synth.chan4.use_len = v & 0b0100_0000 != 0;
-                    if v & 0b1000_0000 != 0 {
-                        self.synth.chan4.reset();
-                    }
-                    Ok(())
-                }
-                REG_NR50 => {
-                    self.nr50 = v;
-                    self.synth.mixer.set_master_volumes(
-                        f32::from((v >> 4) & 0b111) / 7.,
-                        f32::from(v & 0b111) / 7.,
-                    );
-                    Ok(())
-                }
-                REG_NR51 => {
-                    self.nr51 = v;
-                    self.synth.mixer.set_enabled_channels(
-                        [
-                            v & 0b0000_0001 != 0,
-                            v & 0b0000_0010 != 0,
-                            v & 0b0000_0100 != 0,
-                            v & 0b0000_1000 != 0,
-                        ],
-                        [
-                            v & 0b0001_0000 != 0,
-                            v & 0b0010_0000 != 0,
-                            v & 0b0100_0000 != 0,
-                            v & 0b1000_0000 != 0,
-                        ],
-                    );
-                    Ok(())
-                }
-                REG_NR52 => {
-                    self.nr52 = v;
-                    Ok(())
-                }
-                _ => {
-                    error!("Unimplemented sound register {:?}", a);
-                    Err(ExecutionError::BusError)
+        } else if a == REG_NR52 {
+            self.write_nr52(v);
+            Ok(())
+        } else if !self.powered {
+            // Everything but NR52 ignores writes while powered off, except
+            // for the length counters on the DMG
+            if !self.cgb_mode {
+                match a {
+                    REG_NR11 => self.synth.chan1.write_length(v),
+                    REG_NR21 => self.synth.chan2.write_length(v),
+                    REG_NR31 => self.synth.chan3.write_length(v),
+                    REG_NR41 => self.synth.chan4.write_length(v),
+                    _ => {}
                 }
             }
+            Ok(())
+        } else {
+            self.write_register(a, v)
         }
     }
 }
 
-fn bits_to_sample(b: u8) -> f32 {
-    (f32::from(b) - 8.) / 8.
+impl Audio {
+    /// Logs every write to the sound registers from now on to a VGM file.
+    /// The log starts off by writing back the current state, since players
+    /// start from a freshly reset APU.
+    pub fn start_vgm_logging<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let cycle = self.synth.cycle();
+        let mut vgm = vgm::VgmLogger::create(path, cycle)?;
+        vgm.log_write(cycle, vgm_register(REG_NR52), (self.powered as u8) << 7)?;
+        for (i, v) in self.synth.chan3.ram().iter().enumerate() {
+            vgm.log_write(cycle, vgm_register(RNG_SND_WAV_RAM.0) + i as u8, *v)?;
+        }
+        if self.powered {
+            // Without the trigger bits, which would restart the channels
+            let registers = [
+                (REG_NR10, self.nr10),
+                (REG_NR11, self.nr11),
+                (REG_NR12, self.nr12),
+                (REG_NR13, self.nr13),
+                (REG_NR14, self.nr14 & 0b0111_1111),
+                (REG_NR21, self.nr21),
+                (REG_NR22, self.nr22),
+                (REG_NR23, self.nr23),
+                (REG_NR24, self.nr24 & 0b0111_1111),
+                (REG_NR30, self.nr30),
+                (REG_NR31, self.nr31),
+                (REG_NR32, self.nr32),
+                (REG_NR33, self.nr33),
+                (REG_NR34, self.nr34 & 0b0111_1111),
+                (REG_NR41, self.nr41),
+                (REG_NR42, self.nr42),
+                (REG_NR43, self.nr43),
+                (REG_NR44, self.nr44 & 0b0111_1111),
+                (REG_NR50, self.nr50),
+                (REG_NR51, self.nr51),
+            ];
+            for (a, v) in registers.iter() {
+                vgm.log_write(cycle, vgm_register(*a), *v)?;
+            }
+        }
+        self.vgm = Some(vgm);
+        Ok(())
+    }
+
+    pub fn stop_vgm_logging(&mut self) {
+        if let Some(mut vgm) = self.vgm.take() {
+            if let Err(e) = vgm.finish(self.synth.cycle()) {
+                error!("Failed to finish VGM log: {}", e);
+            }
+        }
+    }
+
+    // Registers are logged as they're written, before anything decides to
+    // ignore the write, since the player's APU will do the same
+    fn log_write(&mut self, a: Address, v: u8) {
+        let cycle = self.synth.cycle();
+        if let Some(Err(e)) = self
+            .vgm
+            .as_mut()
+            .map(|vgm| vgm.log_write(cycle, vgm_register(a), v))
+        {
+            error!("Stopped logging VGM: {}", e);
+            self.vgm = None;
+        }
+    }
+
+    fn write_nr52(&mut self, v: u8) {
+        let powered = v & 0b1000_0000 != 0;
+        if self.powered && !powered {
+            self.power_off();
+        } else if !self.powered && powered {
+            self.synth.power_on();
+        }
+        self.powered = powered;
+    }
+
+    // Clears every register from NR10 to NR51, leaving the wave RAM alone
+    fn power_off(&mut self) {
+        self.nr10 = 0;
+        self.nr11 = 0;
+        self.nr12 = 0;
+        self.nr13 = 0;
+        self.nr14 = 0;
+        self.nr21 = 0;
+        self.nr22 = 0;
+        self.nr23 = 0;
+        self.nr24 = 0;
+        self.nr30 = 0;
+        self.nr31 = 0;
+        self.nr32 = 0;
+        self.nr33 = 0;
+        self.nr34 = 0;
+        self.nr41 = 0;
+        self.nr42 = 0;
+        self.nr43 = 0;
+        self.nr44 = 0;
+        self.nr50 = 0;
+        self.nr51 = 0;
+        self.synth.power_off(!self.cgb_mode);
+    }
+
+    fn write_register(&mut self, a: Address, v: u8) -> Result<(), ExecutionError> {
+        let length_step_next = self.synth.length_step_next();
+        match a {
+            REG_NR10 => {
+                self.nr10 = v;
+                self.synth.chan1.write_sweep(v);
+                Ok(())
+            }
+            REG_NR11 => {
+                self.nr11 = v;
+                self.synth.chan1.write_length_duty(v);
+                Ok(())
+            }
+            REG_NR12 => {
+                self.nr12 = v;
+                self.synth.chan1.write_envelope(v);
+                Ok(())
+            }
+            REG_NR13 => {
+                self.nr13 = v;
+                self.synth.chan1.write_frequency_low(v);
+                Ok(())
+            }
+            REG_NR14 => {
+                self.nr14 = v;
+                self.synth.chan1.write_control(v, length_step_next);
+                Ok(())
+            }
+            REG_NR21 => {
+                self.nr21 = v;
+                self.synth.chan2.write_length_duty(v);
+                Ok(())
+            }
+            REG_NR22 => {
+                self.nr22 = v;
+                self.synth.chan2.write_envelope(v);
+                Ok(())
+            }
+            REG_NR23 => {
+                self.nr23 = v;
+                self.synth.chan2.write_frequency_low(v);
+                Ok(())
+            }
+            REG_NR24 => {
+                self.nr24 = v;
+                self.synth.chan2.write_control(v, length_step_next);
+                Ok(())
+            }
+            REG_NR30 => {
+                self.nr30 = v;
+                self.synth.chan3.write_dac(v);
+                Ok(())
+            }
+            REG_NR31 => {
+                self.nr31 = v;
+                self.synth.chan3.write_length(v);
+                Ok(())
+            }
+            REG_NR32 => {
+                self.nr32 = v;
+                self.synth.chan3.write_volume(v);
+                Ok(())
+            }
+            REG_NR33 => {
+                self.nr33 = v;
+                self.synth.chan3.write_frequency_low(v);
+                Ok(())
+            }
+            REG_NR34 => {
+                self.nr34 = v;
+                self.synth.chan3.write_control(v, length_step_next);
+                Ok(())
+            }
+            REG_NR41 => {
+                self.nr41 = v;
+                self.synth.chan4.write_length(v);
+                Ok(())
+            }
+            REG_NR42 => {
+                self.nr42 = v;
+                self.synth.chan4.write_envelope(v);
+                Ok(())
+            }
+            REG_NR43 => {
+                self.nr43 = v;
+                self.synth.chan4.write_polynomial(v);
+                Ok(())
+            }
+            REG_NR44 => {
+                self.nr44 = v;
+                self.synth.chan4.write_control(v, length_step_next);
+                Ok(())
+            }
+            REG_NR50 => {
+                self.nr50 = v;
+                self.synth
+                    .mixer
+                    .set_master_volumes((v >> 4) & 0b111, v & 0b111);
+                self.synth
+                    .mixer
+                    .set_vin_enabled(v & 0b1000_0000 != 0, v & 0b0000_1000 != 0);
+                Ok(())
+            }
+            REG_NR51 => {
+                self.nr51 = v;
+                self.synth.mixer.set_enabled_channels(
+                    [
+                        v & 0b0000_0001 != 0,
+                        v & 0b0000_0010 != 0,
+                        v & 0b0000_0100 != 0,
+                        v & 0b0000_1000 != 0,
+                    ],
+                    [
+                        v & 0b0001_0000 != 0,
+                        v & 0b0010_0000 != 0,
+                        v & 0b0100_0000 != 0,
+                        v & 0b1000_0000 != 0,
+                    ],
+                );
+                Ok(())
+            }
+            // The unused registers in between
+            _ => Ok(()),
+        }
+    }
+}
+
+fn vgm_register(a: Address) -> u8 {
+    (a - RNG_SND_REGS.0).0 as u8
+}
+
+// What each channel's DAC makes of its 4-bit output. Note that a silent
+// channel with its DAC on still outputs a level, which only the high-pass
+// filter takes back out.
+fn dac_output(digital: u8) -> f32 {
+    1. - f32::from(digital) / 7.5
+}
+
+#[test]
+fn test_dac_output() {
+    assert_eq!(dac_output(0), 1.);
+    assert_eq!(dac_output(15), -1.);
+    assert!(dac_output(7) > 0. && dac_output(8) < 0.);
+}
+
+#[cfg(test)]
+fn test_audio(cgb_mode: bool) -> Audio {
+    Audio::new(Box::new(NullSink), cgb_mode)
+}
+
+#[test]
+fn test_read_masks() {
+    let mut audio = test_audio(false);
+    audio.write(REG_NR10, 0).unwrap();
+    assert_eq!(audio.read(REG_NR10).unwrap(), 0x80);
+    audio.write(REG_NR11, 0b1000_0101).unwrap();
+    assert_eq!(audio.read(REG_NR11).unwrap(), 0b1011_1111);
+    audio.write(REG_NR13, 0x12).unwrap();
+    assert_eq!(audio.read(REG_NR13).unwrap(), 0xFF);
+    audio.write(REG_NR50, 0x35).unwrap();
+    assert_eq!(audio.read(REG_NR50).unwrap(), 0x35);
+    assert_eq!(audio.read(Address(0xFF15)).unwrap(), 0xFF);
+    assert_eq!(audio.read(Address(0xFF2F)).unwrap(), 0xFF);
+}
+
+#[test]
+fn test_power_off() {
+    let mut audio = test_audio(false);
+    audio.write(REG_NR50, 0x77).unwrap();
+    audio.write(RNG_SND_WAV_RAM.0, 0x12).unwrap();
+
+    audio.write(REG_NR52, 0).unwrap();
+    assert_eq!(audio.read(REG_NR52).unwrap(), 0x70);
+    assert_eq!(audio.read(REG_NR50).unwrap(), 0);
+    assert_eq!(audio.read(RNG_SND_WAV_RAM.0).unwrap(), 0x12);
+
+    audio.write(REG_NR50, 0x77).unwrap();
+    assert_eq!(audio.read(REG_NR50).unwrap(), 0);
+    audio.write(RNG_SND_WAV_RAM.0, 0x34).unwrap();
+    assert_eq!(audio.read(RNG_SND_WAV_RAM.0).unwrap(), 0x34);
+
+    audio.write(REG_NR52, 0x80).unwrap();
+    audio.write(REG_NR50, 0x77).unwrap();
+    assert_eq!(audio.read(REG_NR50).unwrap(), 0x77);
 }
 
 #[test]
-fn test_bits_to_sample() {
-    assert_eq!(bits_to_sample(0), -1.);
-    assert_eq!(bits_to_sample(8), 0.);
-    assert_eq!(bits_to_sample(16), 1.);
+fn test_length_writes_while_off() {
+    for &cgb_mode in &[false, true] {
+        let mut audio = test_audio(cgb_mode);
+        audio.write(REG_NR52, 0).unwrap();
+        // A length of 1
+        audio.write(REG_NR11, 0b0011_1111).unwrap();
+        audio.write(REG_NR52, 0x80).unwrap();
+        audio.write(REG_NR12, 0xF0).unwrap();
+        audio.write(REG_NR14, 0b1100_0000).unwrap();
+        // Powering on restarted the frame sequencer, so this clocks lengths
+        audio.synth.pump_cycle(0);
+
+        let chan1_on = audio.read(REG_NR52).unwrap() & 0b0000_0001 != 0;
+        assert_eq!(chan1_on, cgb_mode);
+        // The duty bits weren't written either way
+        assert_eq!(audio.read(REG_NR11).unwrap(), 0x3F);
+    }
+}
+
+#[test]
+fn test_channel_snapshots() {
+    let mut audio = test_audio(false);
+    audio.write(REG_NR11, 0b1000_0000 | 60).unwrap();
+    audio.write(REG_NR12, 0xA0).unwrap();
+    audio.write(REG_NR13, 0x00).unwrap();
+    audio.write(REG_NR14, 0b1100_0111).unwrap();
+    audio.write(REG_NR32, 0b0100_0000).unwrap();
+    audio.write(REG_NR43, 0b1110_0000).unwrap();
+
+    let snapshots = audio.synth.snapshot();
+    assert!(snapshots[0].enabled);
+    assert_eq!(snapshots[0].frequency, 4_194_304. / 8. / 4. / 256.);
+    assert_eq!(snapshots[0].volume, 10);
+    assert_eq!(snapshots[0].duty, Some(2));
+    assert_eq!(snapshots[0].length_remaining, Some(4));
+    assert_eq!(snapshots[1].length_remaining, None);
+    assert_eq!(snapshots[2].volume, 7);
+    assert_eq!(snapshots[2].duty, None);
+    assert_eq!(snapshots[3].frequency, 0.);
+
+    // One sample a second, from the null sink
+    for second in 1..=3 {
+        audio.synth.pump_cycle(second * 4_194_304);
+    }
+    let snapshots = audio.synth.snapshot();
+    assert!(snapshots.iter().all(|s| s.samples.len() == 3));
+    // With its DAC off
+    assert!(snapshots[1].samples.iter().all(|s| *s == 0.));
 }
@@ -0,0 +1,129 @@
+use std::f32::consts::PI;
+
+// Length of the band-limited step, in output samples. Output is delayed by
+// half of this.
+const TAPS: usize = 16;
+// How finely steps are positioned between two output samples
+const PHASES: usize = 32;
+// Fraction of the Nyquist frequency that gets through, leaving the windowed
+// kernel some room to roll off before aliasing starts
+const CUTOFF: f32 = 0.9;
+const RING_SIZE: usize = 32;
+
+/// Turns a channel's level, changing at exact cycles, into output samples
+/// without aliasing.
+///
+/// Each change is spread over the following samples as a band-limited
+/// impulse, and the output is the running sum of those impulses. Every phase
+/// of the kernel sums to 1, so a step always settles at exactly its height.
+pub struct BlipBuffer {
+    period: u64,
+    kernel: Vec<[f32; TAPS]>,
+    ring: [f32; RING_SIZE],
+    head: usize,
+    // Cycle of the last sample read, changes are positioned relative to it
+    base_cycle: u64,
+    level: f32,
+    output: f32,
+}
+
+impl BlipBuffer {
+    /// `period` is the number of cycles between two output samples, and
+    /// `level` the channel's level at `cycle`
+    pub fn new(period: u64, cycle: u64, level: f32) -> BlipBuffer {
+        BlipBuffer {
+            period: period.max(1),
+            kernel: build_kernel(),
+            ring: [0.; RING_SIZE],
+            head: 0,
+            base_cycle: cycle,
+            level,
+            output: level,
+        }
+    }
+
+    /// Moves the channel to `level` at `cycle`. Cycles must not go back past
+    /// the last sample read.
+    pub fn set_level(&mut self, cycle: u64, level: f32) {
+        let delta = level - self.level;
+        if delta == 0. {
+            return;
+        }
+        self.level = level;
+
+        let offset = cycle.saturating_sub(self.base_cycle);
+        // Changes only ever come at most a sample or two ahead of the reads
+        let whole = ((offset / self.period) as usize).min(RING_SIZE - TAPS - 1);
+        let phase =
+            ((offset % self.period) as usize * PHASES / self.period as usize).min(PHASES - 1);
+        for (i, k) in self.kernel[phase].iter().enumerate() {
+            let slot = (self.head + whole + 1 + i) % RING_SIZE;
+            self.ring[slot] += delta * k;
+        }
+    }
+
+    /// Takes the next output sample, which is due at `cycle`
+    pub fn read_sample(&mut self, cycle: u64) -> f32 {
+        self.output += self.ring[self.head];
+        self.ring[self.head] = 0.;
+        self.head = (self.head + 1) % RING_SIZE;
+        self.base_cycle = cycle;
+        self.output
+    }
+}
+
+fn build_kernel() -> Vec<[f32; TAPS]> {
+    let half = TAPS as f32 / 2.;
+    (0..PHASES)
+        .map(|phase| {
+            let fraction = phase as f32 / PHASES as f32;
+            let mut taps = [0.; TAPS];
+            for (i, tap) in taps.iter_mut().enumerate() {
+                // Distance from the center of the impulse, in samples
+                let x = (i + 1) as f32 - fraction - half;
+                let sinc = if x == 0. {
+                    1.
+                } else {
+                    (PI * CUTOFF * x).sin() / (PI * CUTOFF * x)
+                };
+                // Blackman window over the length of the kernel
+                let w = (x + half) / TAPS as f32;
+                let window = 0.42 - 0.5 * (2. * PI * w).cos() + 0.08 * (4. * PI * w).cos();
+                *tap = sinc * window.max(0.);
+            }
+            let sum: f32 = taps.iter().sum();
+            taps.iter_mut().for_each(|t| *t /= sum);
+            taps
+        })
+        .collect()
+}
+
+#[test]
+fn test_step_settles() {
+    let mut blip = BlipBuffer::new(100, 0, 0.);
+    blip.set_level(50, 1.);
+
+    let samples: Vec<f32> = (1..=TAPS as u64 + 2)
+        .map(|i| blip.read_sample(i * 100))
+        .collect();
+    assert!(samples[0].abs() < 0.01);
+    // The step is smoothed rather than instant
+    assert!(samples.iter().any(|s| *s > 0.1 && *s < 0.9));
+    assert!((samples[TAPS + 1] - 1.).abs() < 1e-5);
+}
+
+#[test]
+fn test_nyquist_is_filtered() {
+    // A square wave at the output's Nyquist frequency, which point sampling
+    // would turn into a full scale tone or DC depending on its phase
+    let mut blip = BlipBuffer::new(100, 0, 0.);
+    let mut peak: f32 = 0.;
+    for i in 0..200u64 {
+        blip.set_level(i * 100 + 50, if i % 2 == 0 { 1. } else { -1. });
+        let s = blip.read_sample((i + 1) * 100);
+        if i > TAPS as u64 {
+            peak = peak.max(s.abs());
+        }
+    }
+    assert!(peak < 0.2, "peak {}", peak);
+}
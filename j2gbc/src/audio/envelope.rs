@@ -0,0 +1,120 @@
+/// The volume envelope of the square and noise channels, clocked on the
+/// frame sequencer's last step
+#[derive(Clone, Copy, Default)]
+pub struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+    running: bool,
+}
+
+impl Envelope {
+    /// Writes NRx2. Writing while the channel plays doesn't restart the
+    /// envelope, but nudges the current volume in a way some games rely on
+    /// ("zombie mode").
+    pub fn write(&mut self, v: u8, channel_enabled: bool) {
+        let increase = v & 0b1000 != 0;
+        if channel_enabled {
+            // The volume is only 4 bits wide, so all of this wraps around
+            if self.period == 0 && self.running {
+                self.volume = (self.volume + 1) & 0b1111;
+            } else if !self.increase {
+                self.volume = (self.volume + 2) & 0b1111;
+            }
+            if increase != self.increase {
+                self.volume = (16 - self.volume) & 0b1111;
+            }
+        }
+
+        self.initial_volume = v >> 4;
+        self.increase = increase;
+        self.period = v & 0b111;
+    }
+
+    /// The DAC is on as long as any of the upper 5 bits of NRx2 are set
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = timer_period(self.period);
+        self.running = true;
+    }
+
+    pub fn clock(&mut self) {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = timer_period(self.period);
+        if self.period == 0 || !self.running {
+            return;
+        }
+
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        } else {
+            self.running = false;
+        }
+    }
+}
+
+/// Envelope and sweep timers reload a period of 0 as 8, though they then
+/// never change anything
+pub fn timer_period(period: u8) -> u8 {
+    if period == 0 {
+        8
+    } else {
+        period
+    }
+}
+
+#[test]
+fn test_envelope_decreases() {
+    let mut env = Envelope::default();
+    env.write(0b0010_0010, false);
+    env.trigger();
+    assert_eq!(env.volume(), 2);
+    env.clock();
+    assert_eq!(env.volume(), 2);
+    env.clock();
+    assert_eq!(env.volume(), 1);
+    for _ in 0..4 {
+        env.clock();
+    }
+    assert_eq!(env.volume(), 0);
+}
+
+#[test]
+fn test_zombie_mode() {
+    let mut env = Envelope::default();
+    env.write(0b0101_0000, false);
+    env.trigger();
+
+    // Period 0 with the envelope still running
+    env.write(0b0101_0000, true);
+    assert_eq!(env.volume(), 6);
+    // Switching direction flips the volume
+    env.write(0b0101_1011, true);
+    assert_eq!(env.volume(), 16 - 7);
+    env.write(0b0000_0111, true);
+    assert_eq!(env.volume(), 16 - 9);
+    assert!(!env.dac_enabled());
+
+    // Decrease mode adds 2, wrapping around
+    let mut env = Envelope::default();
+    env.write(0b1111_0001, false);
+    env.trigger();
+    env.write(0b1111_0001, true);
+    assert_eq!(env.volume(), 1);
+}
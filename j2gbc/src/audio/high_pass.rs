@@ -0,0 +1,90 @@
+use crate::cpu::CLOCK_RATE;
+
+// How much of its charge the output capacitor keeps every cycle. The CGB's
+// lets low frequencies through less than the DMG's.
+const DMG_CHARGE_FACTOR: f64 = 0.999_958;
+const CGB_CHARGE_FACTOR: f64 = 0.998_943;
+
+/// The capacitor in front of the output, which takes the DC offset out of
+/// the DACs' output
+#[derive(Clone, Copy)]
+pub struct HighPass {
+    charge_factor_per_cycle: f64,
+    charge_factor: f32,
+    capacitor: (f32, f32),
+}
+
+impl HighPass {
+    pub fn new(sample_rate: u64, cgb_mode: bool) -> HighPass {
+        let per_cycle = if cgb_mode {
+            CGB_CHARGE_FACTOR
+        } else {
+            DMG_CHARGE_FACTOR
+        };
+        HighPass {
+            charge_factor_per_cycle: per_cycle,
+            charge_factor: charge_factor(per_cycle, sample_rate),
+            capacitor: (0., 0.),
+        }
+    }
+
+    /// The same filter with its current charge, running at another rate
+    pub fn with_sample_rate(&self, sample_rate: u64) -> HighPass {
+        HighPass {
+            charge_factor: charge_factor(self.charge_factor_per_cycle, sample_rate),
+            ..*self
+        }
+    }
+
+    /// The capacitor only charges while any DAC is on, and the output is
+    /// silent otherwise
+    pub fn filter(&mut self, sample: (f32, f32), dacs_enabled: bool) -> (f32, f32) {
+        if !dacs_enabled {
+            return (0., 0.);
+        }
+        let out = (sample.0 - self.capacitor.0, sample.1 - self.capacitor.1);
+        self.capacitor = (
+            sample.0 - out.0 * self.charge_factor,
+            sample.1 - out.1 * self.charge_factor,
+        );
+        out
+    }
+}
+
+fn charge_factor(per_cycle: f64, sample_rate: u64) -> f32 {
+    per_cycle.powf(CLOCK_RATE as f64 / sample_rate as f64) as f32
+}
+
+#[test]
+fn test_dc_is_removed() {
+    for &cgb_mode in &[false, true] {
+        let mut high_pass = HighPass::new(48000, cgb_mode);
+        let first = high_pass.filter((1., -1.), true);
+        assert_eq!(first, (1., -1.));
+        let mut last = first;
+        for _ in 0..48000 {
+            last = high_pass.filter((1., -1.), true);
+        }
+        assert!(last.0.abs() < 0.001 && last.1.abs() < 0.001);
+    }
+    // The CGB's filter is quicker to settle
+    let mut dmg = HighPass::new(48000, false);
+    let mut cgb = HighPass::new(48000, true);
+    for _ in 0..100 {
+        dmg.filter((1., 1.), true);
+        cgb.filter((1., 1.), true);
+    }
+    assert!(cgb.filter((1., 1.), true).0 < dmg.filter((1., 1.), true).0);
+}
+
+#[test]
+fn test_silent_without_dacs() {
+    let mut high_pass = HighPass::new(48000, false);
+    assert_eq!(high_pass.filter((1., 1.), false), (0., 0.));
+    // The capacitor didn't charge meanwhile
+    assert_eq!(high_pass.filter((1., 1.), true), (1., 1.));
+
+    let resampled = high_pass.with_sample_rate(65536);
+    assert_eq!(resampled.capacitor, high_pass.capacitor);
+    assert!(resampled.charge_factor > high_pass.charge_factor);
+}
@@ -0,0 +1,90 @@
+/// A channel's length counter, clocked on the frame sequencer's even steps.
+///
+/// `length_step_next` tells whether the next frame sequencer step clocks the
+/// counters. Enabling the counter or triggering the channel in the other half
+/// of a length period behaves as if the counter was clocked one extra time.
+#[derive(Clone, Copy)]
+pub struct Length {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Length {
+        Length {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, v: u8) {
+        self.counter = self.max - u16::from(v);
+    }
+
+    /// Returns true if the counter just ran out, which disables the channel
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool, length_step_next: bool) -> bool {
+        let extra_clock = !self.enabled && enabled && !length_step_next;
+        self.enabled = enabled;
+        extra_clock && self.clock()
+    }
+
+    pub fn trigger(&mut self, length_step_next: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && !length_step_next {
+                self.counter -= 1;
+            }
+        }
+    }
+
+    pub fn remaining(&self) -> Option<u16> {
+        if self.enabled {
+            Some(self.counter)
+        } else {
+            None
+        }
+    }
+
+    pub fn power_off(&mut self, keep_counter: bool) {
+        self.enabled = false;
+        if !keep_counter {
+            self.counter = 0;
+        }
+    }
+}
+
+#[test]
+fn test_length_runs_out() {
+    let mut length = Length::new(64);
+    length.load(62);
+    assert!(!length.set_enabled(true, true));
+    assert!(!length.clock());
+    assert!(length.clock());
+    assert!(!length.clock());
+}
+
+#[test]
+fn test_length_extra_clock() {
+    let mut length = Length::new(64);
+    length.load(63);
+    assert!(length.set_enabled(true, false));
+
+    // Triggering with an empty counter reloads one short of the maximum
+    length.trigger(false);
+    assert_eq!(length.counter, 63);
+
+    // Staying enabled doesn't clock it again
+    assert!(!length.set_enabled(true, false));
+    assert_eq!(length.counter, 63);
+}
@@ -1,10 +1,27 @@
-#[derive(Default)]
+// Vin brings in audio from the cartridge, but no cartridge emulated here
+// drives it, so routing it in only mixes in silence
+const VIN_LEVEL: f32 = 0.;
+
 pub struct Mixer {
     left_enable: [bool; 4],
     right_enable: [bool; 4],
+    left_vin: bool,
+    right_vin: bool,
+
+    // From 0 to 7, as written to NR50
+    left_master_vol: u8,
+    right_master_vol: u8,
+
+    // Set by the user rather than the game, so they outlive NR51 writes and
+    // powering off the APU
+    channel_enabled: [bool; 4],
+    channel_gain: [f32; 4],
+}
 
-    left_master_vol: f32,
-    right_master_vol: f32,
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Mixer {
@@ -12,27 +29,45 @@ impl Mixer {
         Mixer {
             left_enable: [false; 4],
             right_enable: [false; 4],
+            left_vin: false,
+            right_vin: false,
+
+            left_master_vol: 0,
+            right_master_vol: 0,
 
-            left_master_vol: 0.,
-            right_master_vol: 0.,
+            channel_enabled: [true; 4],
+            channel_gain: [1.; 4],
         }
     }
 
+    /// Mixes the DAC outputs, each from -1 to 1, down to a stereo sample
+    /// from -1 to 1 before the high-pass filter
     pub fn mix(&self, samples: [f32; 4]) -> (f32, f32) {
+        let mut samples = samples;
+        for (i, sample) in samples.iter_mut().enumerate() {
+            *sample = if self.channel_enabled[i] {
+                *sample * self.channel_gain[i]
+            } else {
+                0.
+            };
+        }
+
         let left_val: f32 = samples
             .iter()
             .zip(self.left_enable.iter())
             .map(|(sample, enabled)| if *enabled { *sample } else { 0. })
-            .sum();
+            .sum::<f32>()
+            + if self.left_vin { VIN_LEVEL } else { 0. };
         let right_val: f32 = samples
             .iter()
             .zip(self.right_enable.iter())
             .map(|(sample, enabled)| if *enabled { *sample } else { 0. })
-            .sum();
+            .sum::<f32>()
+            + if self.right_vin { VIN_LEVEL } else { 0. };
 
         (
-            left_val / 4. * self.left_master_vol,
-            right_val / 4. * self.right_master_vol,
+            left_val / 4. * master_volume(self.left_master_vol),
+            right_val / 4. * master_volume(self.right_master_vol),
         )
     }
 
@@ -41,8 +76,77 @@ impl Mixer {
         self.right_enable = right_enable;
     }
 
-    pub fn set_master_volumes(&mut self, left: f32, right: f32) {
+    pub fn set_master_volumes(&mut self, left: u8, right: u8) {
         self.left_master_vol = left;
         self.right_master_vol = right;
     }
+
+    pub fn set_vin_enabled(&mut self, left: bool, right: bool) {
+        self.left_vin = left;
+        self.right_vin = right;
+    }
+
+    /// Mutes a channel on both sides, whatever the game routes through NR51
+    // Channels past the fourth don't exist, so setting them does nothing
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        if let Some(e) = self.channel_enabled.get_mut(channel) {
+            *e = enabled;
+        }
+    }
+
+    pub fn is_channel_enabled(&self, channel: usize) -> bool {
+        self.channel_enabled.get(channel) == Some(&true)
+    }
+
+    /// Mutes every channel but `channel`, which gets unmuted
+    pub fn solo_channel(&mut self, channel: usize) {
+        for (i, e) in self.channel_enabled.iter_mut().enumerate() {
+            *e = i == channel;
+        }
+    }
+
+    pub fn set_channel_gain(&mut self, channel: usize, gain: f32) {
+        if let Some(g) = self.channel_gain.get_mut(channel) {
+            *g = gain;
+        }
+    }
+}
+
+// The lowest setting is quiet, but not muted
+fn master_volume(v: u8) -> f32 {
+    f32::from(v + 1) / 8.
+}
+
+#[test]
+fn test_master_volume() {
+    let mut mixer = Mixer::new();
+    mixer.set_enabled_channels([true; 4], [true, false, false, false]);
+    mixer.set_master_volumes(7, 0);
+    assert_eq!(mixer.mix([1.; 4]), (1., 1. / 4. / 8.));
+    mixer.set_master_volumes(3, 7);
+    assert_eq!(mixer.mix([-1., 1., -1., 1.]), (0., -1. / 4.));
+}
+
+#[test]
+fn test_channel_controls() {
+    let mut mixer = Mixer::new();
+    mixer.set_enabled_channels([true; 4], [true; 4]);
+    mixer.set_master_volumes(7, 7);
+    mixer.set_channel_enabled(1, false);
+    mixer.set_channel_gain(2, 0.5);
+    assert_eq!(mixer.mix([1.; 4]), (2.5 / 4., 2.5 / 4.));
+
+    // The game's routing doesn't undo either of them
+    mixer.set_enabled_channels([false, true, true, false], [true; 4]);
+    assert_eq!(mixer.mix([1.; 4]), (0.5 / 4., 2.5 / 4.));
+    assert!(!mixer.is_channel_enabled(1));
+
+    mixer.solo_channel(2);
+    assert_eq!(mixer.mix([1.; 4]), (0.5 / 4., 0.5 / 4.));
+
+    // There is no fifth channel
+    mixer.set_channel_enabled(4, true);
+    mixer.set_channel_gain(4, 2.);
+    assert!(!mixer.is_channel_enabled(4));
+    assert_eq!(mixer.mix([1.; 4]), (0.5 / 4., 0.5 / 4.));
 }
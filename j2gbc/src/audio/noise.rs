@@ -1,21 +1,22 @@
-use j2ds::Clock;
+use super::dac_output;
+use super::envelope::Envelope;
+use super::length::Length;
+use super::synth::{Channel, ChannelSnapshot};
+use crate::cpu::CLOCK_RATE;
 
 pub struct NoiseChannel {
+    enabled: bool,
     lfsr: u16,
-    lfsr_half: bool,
-
-    period: u64,
-
-    len: u8,
-    pub use_len: bool,
+    // Width mode, where the LFSR is only 7 bits long
+    lfsr_narrow: bool,
+    clock_shift: u8,
+    divisor_code: u8,
 
     next_lfsr_shift_cycle: u64,
-    last_cpu_cycle: u64,
+    cycle: u64,
 
-    vol: u8,
-    vol_orig: u8,
-    vol_env_increment: bool,
-    vol_counter: Clock,
+    length: Length,
+    envelope: Envelope,
 }
 
 const DIVISORS: [u64; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
@@ -29,102 +30,167 @@ impl Default for NoiseChannel {
 impl NoiseChannel {
     pub fn new() -> NoiseChannel {
         NoiseChannel {
-            lfsr: 0b1111_1111,
-            lfsr_half: false,
-
-            period: 0,
-
-            len: 0,
-            use_len: false,
+            enabled: false,
+            lfsr: 0x7FFF,
+            lfsr_narrow: false,
+            clock_shift: 0,
+            divisor_code: 0,
 
             next_lfsr_shift_cycle: 0,
-            last_cpu_cycle: 0,
+            cycle: 0,
 
-            vol: 0,
-            vol_orig: 0,
-            vol_env_increment: false,
-            vol_counter: Clock::new(0),
+            length: Length::new(64),
+            envelope: Envelope::default(),
         }
     }
 
-    pub fn set_frequency_from_bits(&mut self, bits: u8) {
-        let s = (bits >> 4) & 0b1111;
-        let r = DIVISORS[(bits & 0b111) as usize];
-        self.period = r << s;
-
-        self.lfsr_half = bits & 0b0000_1000 != 0;
+    pub fn power_off(&mut self, keep_length: bool) {
+        let (mut length, cycle) = (self.length, self.cycle);
+        *self = NoiseChannel::new();
+        self.cycle = cycle;
+        length.power_off(keep_length);
+        self.length = length;
     }
 
-    pub fn set_len(&mut self, len: u8) {
-        self.len = 64 - len;
+    /// Writes NR41
+    pub fn write_length(&mut self, v: u8) {
+        self.length.load(v & 0b0011_1111);
     }
 
-    pub fn decrement_length(&mut self) {
-        if self.len > 0 {
-            self.len -= 1;
+    /// Writes NR42
+    pub fn write_envelope(&mut self, v: u8) {
+        self.envelope.write(v, self.enabled);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
         }
     }
 
-    pub fn set_volume(&mut self, vol: u8) {
-        self.vol = vol;
-        self.vol_orig = vol;
+    /// Writes NR43, which takes effect from the next shift on
+    pub fn write_polynomial(&mut self, v: u8) {
+        self.clock_shift = v >> 4;
+        self.lfsr_narrow = v & 0b0000_1000 != 0;
+        self.divisor_code = v & 0b111;
     }
 
-    pub fn set_vol_env_period(&mut self, p: u8) {
-        self.vol_counter = Clock::new(u64::from(p));
+    /// Writes NR44
+    pub fn write_control(&mut self, v: u8, length_step_next: bool) {
+        if self
+            .length
+            .set_enabled(v & 0b0100_0000 != 0, length_step_next)
+        {
+            self.enabled = false;
+        }
+        if v & 0b1000_0000 != 0 {
+            self.enabled = self.envelope.dac_enabled();
+            self.length.trigger(length_step_next);
+            self.envelope.trigger();
+            self.lfsr = 0x7FFF;
+            self.next_lfsr_shift_cycle = self.cycle + self.period();
+        }
     }
 
-    pub fn increment_vol_env(&mut self, inc: bool) {
-        self.vol_env_increment = inc;
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
     }
 
-    pub fn volume_env_update(&mut self) {
-        if self.vol_counter.period() == 0 {
-            return;
-        }
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
 
-        if self.vol_counter.tick() {
-            if self.vol_env_increment && self.vol < 15 {
-                self.vol += 1;
-            } else if self.vol != 0 {
-                self.vol -= 1;
-            }
-        }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
     }
 
-    pub fn sample(&mut self, cpu_cycle: u64) -> f32 {
-        if self.period == 0 || !self.is_active() {
-            return 0.;
-        }
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
 
-        while self.next_lfsr_shift_cycle <= cpu_cycle {
-            let shift = if self.lfsr_half { 6 } else { 14 };
+    fn period(&self) -> u64 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
 
-            let downshifted = self.lfsr >> 1;
-            let bit_1 = self.lfsr & 0b1;
-            let bit_2 = downshifted & 0b1;
-            let new_bit = (bit_1 ^ bit_2) << shift;
-            let cleared = downshifted & !(1 << shift);
+    // Shifts of 14 and 15 stop the LFSR altogether
+    fn is_shifting(&self) -> bool {
+        self.enabled && self.clock_shift < 14
+    }
 
-            self.lfsr = cleared | new_bit;
+    fn shift_lfsr(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0b1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.lfsr_narrow {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+        }
+    }
+}
 
-            self.next_lfsr_shift_cycle += self.period;
+impl Channel for NoiseChannel {
+    fn sample(&mut self, cpu_cycle: u64) -> f32 {
+        self.cycle = cpu_cycle;
+        if !self.dac_enabled() {
+            return 0.;
+        } else if !self.enabled {
+            return dac_output(0);
+        }
+        if self.is_shifting() {
+            while self.next_lfsr_shift_cycle <= cpu_cycle {
+                self.shift_lfsr();
+                self.next_lfsr_shift_cycle += self.period();
+            }
+        } else {
+            self.next_lfsr_shift_cycle = cpu_cycle + self.period();
         }
-        self.last_cpu_cycle = cpu_cycle;
 
-        (f32::from(self.vol) / 15.) * if self.lfsr & 0b1 != 0 { -1. } else { 1. }
+        if self.lfsr & 0b1 != 0 {
+            dac_output(0)
+        } else {
+            dac_output(self.envelope.volume())
+        }
     }
 
-    pub fn is_active(&self) -> bool {
-        !self.use_len || self.len > 0
+    fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.dac_enabled(),
+            frequency: if self.clock_shift < 14 {
+                CLOCK_RATE as f32 / self.period() as f32
+            } else {
+                0.
+            },
+            volume: self.envelope.volume(),
+            duty: None,
+            length_remaining: self.length.remaining(),
+            samples: Vec::new(),
+        }
     }
 
-    pub fn reset(&mut self) {
-        if self.len == 0 {
-            self.len = 64;
+    fn next_change(&self, cpu_cycle: u64) -> u64 {
+        if !self.is_shifting() {
+            return u64::MAX;
         }
-        self.next_lfsr_shift_cycle = self.period + self.last_cpu_cycle;
-        self.vol = self.vol_orig;
-        self.lfsr = 0b1111_1111;
+        self.next_lfsr_shift_cycle.max(cpu_cycle + 1)
     }
 }
+
+#[test]
+fn test_lfsr_widths() {
+    let repeats_after = |narrow: bool| {
+        let mut chan = NoiseChannel::new();
+        chan.lfsr_narrow = narrow;
+        let start = chan.lfsr;
+        let mut n = 0;
+        loop {
+            chan.shift_lfsr();
+            n += 1;
+            // The upper bits still shift in narrow mode, so only compare the
+            // ones that get fed back
+            let mask = if narrow { 0x7F } else { 0x7FFF };
+            if chan.lfsr & mask == start & mask {
+                return n;
+            }
+        }
+    };
+    assert_eq!(repeats_after(false), 32767);
+    assert_eq!(repeats_after(true), 127);
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use log::error;
+
+use super::AudioSink;
+use crate::recorder::AudioRecorder;
+
+type WavRecorder = AudioRecorder<BufWriter<File>>;
+
+/// Writes the mixed output to a WAV file, without needing an audio device
+pub struct WavFileSink {
+    recorder: Option<WavRecorder>,
+    sample_rate: u64,
+}
+
+impl WavFileSink {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u64) -> io::Result<WavFileSink> {
+        Ok(WavFileSink {
+            recorder: Some(AudioRecorder::create(path, sample_rate as u32, 2)?),
+            sample_rate,
+        })
+    }
+}
+
+impl AudioSink for WavFileSink {
+    fn emit_sample(&mut self, sample: (f32, f32)) {
+        push_or_stop(&mut self.recorder, &[sample.0, sample.1]);
+    }
+
+    fn sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+}
+
+/// Writes each channel's output from before the mixer to a mono WAV file of
+/// its own
+pub struct ChannelCaptureSink {
+    recorders: [Option<WavRecorder>; 4],
+    sample_rate: u64,
+}
+
+impl ChannelCaptureSink {
+    /// Channels without a path aren't captured
+    pub fn create<P: AsRef<Path>>(
+        paths: [Option<P>; 4],
+        sample_rate: u64,
+    ) -> io::Result<ChannelCaptureSink> {
+        let mut recorders = [None, None, None, None];
+        for (recorder, path) in recorders.iter_mut().zip(paths.iter()) {
+            if let Some(path) = path {
+                *recorder = Some(AudioRecorder::create(path, sample_rate as u32, 1)?);
+            }
+        }
+        Ok(ChannelCaptureSink {
+            recorders,
+            sample_rate,
+        })
+    }
+}
+
+impl AudioSink for ChannelCaptureSink {
+    fn emit_sample(&mut self, _: (f32, f32)) {}
+
+    fn emit_raw_chans(&mut self, chans: [f32; 4]) {
+        for (recorder, sample) in self.recorders.iter_mut().zip(chans.iter()) {
+            push_or_stop(recorder, &[*sample]);
+        }
+    }
+
+    fn sample_rate(&self) -> u64 {
+        self.sample_rate
+    }
+}
+
+/// Hands everything to several sinks at once. They all get the same
+/// samples, at the rate the first one asks for.
+pub struct TeeSink {
+    sinks: Vec<Box<dyn AudioSink + Send>>,
+}
+
+impl TeeSink {
+    pub fn new(sinks: Vec<Box<dyn AudioSink + Send>>) -> TeeSink {
+        TeeSink { sinks }
+    }
+}
+
+impl AudioSink for TeeSink {
+    fn emit_sample(&mut self, sample: (f32, f32)) {
+        for sink in &mut self.sinks {
+            sink.emit_sample(sample);
+        }
+    }
+
+    fn emit_raw_chans(&mut self, chans: [f32; 4]) {
+        for sink in &mut self.sinks {
+            sink.emit_raw_chans(chans);
+        }
+    }
+
+    fn sample_rate(&self) -> u64 {
+        self.sinks.first().map_or(1, |s| s.sample_rate())
+    }
+}
+
+// A sink can't report errors, so one that fails just stops writing
+fn push_or_stop(recorder: &mut Option<WavRecorder>, frame: &[f32]) {
+    if let Some(Err(e)) = recorder.as_mut().map(|r| r.push_frame(frame)) {
+        error!("Stopped writing audio: {}", e);
+        *recorder = None;
+    }
+}
+
+#[test]
+fn test_tee_sink() {
+    use std::sync::{Arc, Mutex};
+
+    struct TestSink(u64, Arc<Mutex<Vec<f32>>>);
+    impl AudioSink for TestSink {
+        fn emit_sample(&mut self, sample: (f32, f32)) {
+            self.1.lock().unwrap().push(sample.0);
+        }
+        fn emit_raw_chans(&mut self, chans: [f32; 4]) {
+            self.1.lock().unwrap().push(chans[3]);
+        }
+        fn sample_rate(&self) -> u64 {
+            self.0
+        }
+    }
+
+    let (a, b) = (
+        Arc::new(Mutex::new(Vec::new())),
+        Arc::new(Mutex::new(Vec::new())),
+    );
+    let mut tee = TeeSink::new(vec![
+        Box::new(TestSink(48000, a.clone())),
+        Box::new(TestSink(44100, b.clone())),
+    ]);
+    assert_eq!(tee.sample_rate(), 48000);
+    tee.emit_sample((0.5, 0.));
+    tee.emit_raw_chans([0., 0., 0., 0.25]);
+    assert_eq!(*a.lock().unwrap(), vec![0.5, 0.25]);
+    assert_eq!(*b.lock().unwrap(), vec![0.5, 0.25]);
+}
+
+#[test]
+fn test_file_sinks() {
+    let dir = std::env::temp_dir();
+    let mixed = dir.join(format!("j2gbc-test-{}-mixed.wav", std::process::id()));
+    let chan = dir.join(format!("j2gbc-test-{}-chan2.wav", std::process::id()));
+    {
+        let mut tee = TeeSink::new(vec![
+            Box::new(WavFileSink::create(&mixed, 32768).unwrap()),
+            Box::new(ChannelCaptureSink::create([None, Some(&chan), None, None], 32768).unwrap()),
+        ]);
+        for _ in 0..10 {
+            tee.emit_sample((0.5, -0.5));
+            tee.emit_raw_chans([0., 1., 0., 0.]);
+        }
+    }
+
+    let mixed_wav = std::fs::read(&mixed).unwrap();
+    let chan_wav = std::fs::read(&chan).unwrap();
+    std::fs::remove_file(&mixed).unwrap();
+    std::fs::remove_file(&chan).unwrap();
+    assert_eq!(mixed_wav.len(), 44 + 10 * 8);
+    assert_eq!(chan_wav.len(), 44 + 10 * 4);
+    assert_eq!(chan_wav[22..24], 1u16.to_le_bytes());
+    assert_eq!(chan_wav[44..48], 1f32.to_le_bytes());
+}
@@ -1,180 +1,321 @@
-use j2ds::{Clock, Timer};
+use super::dac_output;
+use super::envelope::{timer_period, Envelope};
+use super::length::Length;
+use super::synth::{Channel, ChannelSnapshot};
+use crate::cpu::CLOCK_RATE;
+
+// Cycles between a trigger and the first duty step, on top of a whole period
+const TRIGGER_DELAY: u64 = 8;
 
 pub struct SquareChannel {
-    period: u64,
+    enabled: bool,
+    frequency: u16,
     duty_cycle: u8,
-    use_len: bool,
-    len: u8,
-    last_cpu_cycle: u64,
     duty_cycle_step: usize,
-    duty_cycle_step_timer: Timer,
-    duty_cycle_step_timer_offset: u64,
-
-    vol: u8,
-    vol_orig: u8,
-    vol_env_increment: bool,
-    vol_counter: Clock,
-
-    frequency: u64,
-    frequency_shift: u8,
-    frequency_increment: bool,
-    frequency_sweep_counter: Clock,
+    next_step_cycle: u64,
+    cycle: u64,
+
+    length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
 }
 
-const DUTY_VALUES: [[f32; 8]; 4] = [
-    [-1., -1., -1., -1., -1., -1., -1., 1.],
-    [1., -1., -1., -1., -1., -1., -1., 1.],
-    [1., -1., -1., -1., -1., 1., 1., 1.],
-    [-1., 1., 1., 1., 1., 1., 1., -1.],
+const DUTY_VALUES: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
 ];
 
-impl Default for SquareChannel {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl SquareChannel {
-    pub fn new() -> SquareChannel {
+    /// Only the first square channel has a frequency sweep
+    pub fn new(has_sweep: bool) -> SquareChannel {
         SquareChannel {
-            period: 0,
+            enabled: false,
+            frequency: 0,
             duty_cycle: 0,
             duty_cycle_step: 0,
-            duty_cycle_step_timer: Timer::new(1, 0, 0),
-            duty_cycle_step_timer_offset: 0,
-            use_len: false,
-            len: 0,
-            last_cpu_cycle: 0,
-
-            vol: 0,
-            vol_orig: 0,
-            vol_env_increment: false,
-            vol_counter: Clock::new(0),
+            next_step_cycle: 0,
+            cycle: 0,
 
-            frequency: 0,
-            frequency_shift: 0,
-            frequency_increment: false,
-            frequency_sweep_counter: Clock::new(0),
+            length: Length::new(64),
+            envelope: Envelope::default(),
+            sweep: if has_sweep {
+                Some(Sweep::default())
+            } else {
+                None
+            },
         }
     }
 
-    pub fn reset(&mut self) {
-        self.frequency_sweep_counter.reset();
-        self.vol_counter.reset();
-        if self.len == 0 {
-            self.len = 64;
+    pub fn power_off(&mut self, keep_length: bool) {
+        let (mut length, cycle) = (self.length, self.cycle);
+        *self = SquareChannel::new(self.sweep.is_some());
+        self.cycle = cycle;
+        length.power_off(keep_length);
+        self.length = length;
+    }
+
+    /// Writes NR10
+    pub fn write_sweep(&mut self, v: u8) {
+        if let Some(sweep) = self.sweep.as_mut() {
+            if sweep.write(v) {
+                self.enabled = false;
+            }
         }
-        self.vol = self.vol_orig;
     }
 
-    pub fn set_volume(&mut self, vol: u8) {
-        self.vol = vol;
-        self.vol_orig = vol;
+    /// Writes NRx1
+    pub fn write_length_duty(&mut self, v: u8) {
+        self.duty_cycle = v >> 6;
+        self.write_length(v);
     }
 
-    pub fn set_vol_env_period(&mut self, p: u8) {
-        self.vol_counter = Clock::new(u64::from(p));
+    /// Writes only the length part of NRx1, as the DMG does while powered off
+    pub fn write_length(&mut self, v: u8) {
+        self.length.load(v & 0b0011_1111);
     }
 
-    pub fn increment_vol_env(&mut self, inc: bool) {
-        self.vol_env_increment = inc;
+    /// Writes NRx2
+    pub fn write_envelope(&mut self, v: u8) {
+        self.envelope.write(v, self.enabled);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
     }
 
-    pub fn set_freqeuncy_sweepers(
-        &mut self,
-        freqeuncy_period: u8,
-        freqeuncy_shift: u8,
-        freqeuncy_increment: bool,
-    ) {
-        self.frequency_sweep_counter = Clock::new(u64::from(freqeuncy_period));
-        self.frequency_shift = freqeuncy_shift;
-        self.frequency_increment = freqeuncy_increment;
+    /// Writes NRx3
+    pub fn write_frequency_low(&mut self, v: u8) {
+        self.frequency = (self.frequency & 0x700) | u16::from(v);
     }
 
-    pub fn freq_sweep_update(&mut self) {
-        if self.frequency_sweep_counter.period() == 0 {
-            return;
+    /// Writes NRx4
+    pub fn write_control(&mut self, v: u8, length_step_next: bool) {
+        self.frequency = (self.frequency & 0xFF) | (u16::from(v) & 0b111) << 8;
+        if self
+            .length
+            .set_enabled(v & 0b0100_0000 != 0, length_step_next)
+        {
+            self.enabled = false;
+        }
+        if v & 0b1000_0000 != 0 {
+            self.trigger(length_step_next);
         }
+    }
 
-        if self.frequency_sweep_counter.tick() {
-            let operand = self.frequency >> self.frequency_shift;
-            let mut new_f = self.frequency;
-            if self.frequency_increment {
-                new_f += operand;
-                if new_f > 2049 {
-                    new_f = 2049;
-                }
-            } else if self.frequency_shift != 0 && new_f >= operand {
-                new_f -= operand;
+    fn trigger(&mut self, length_step_next: bool) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(length_step_next);
+        self.envelope.trigger();
+        // The duty step carries on from where it was, only its timer restarts
+        self.next_step_cycle = self.cycle + self.period() + TRIGGER_DELAY;
+        if let Some(sweep) = self.sweep.as_mut() {
+            if sweep.trigger(self.frequency) {
+                self.enabled = false;
             }
-            self.frequency = new_f;
-            self.update_from_frequency();
         }
     }
 
-    pub fn set_frequency_from_bits(&mut self, hi: u8, lo: u8) {
-        self.frequency = (u64::from(hi) & 0b111) << 8 | u64::from(lo);
-        self.update_from_frequency();
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
     }
 
-    fn update_from_frequency(&mut self) {
-        if self.frequency <= 2048 {
-            self.period = 4 * (2048 - self.frequency);
-            self.duty_cycle_step_timer = Timer::new(self.period, 0, 0);
-            self.duty_cycle_step_timer_offset = self.last_cpu_cycle;
+    pub fn clock_sweep(&mut self) {
+        if let Some(sweep) = self.sweep.as_mut() {
+            if sweep.clock(&mut self.frequency) {
+                self.enabled = false;
+            }
         }
     }
 
-    pub fn set_duty_cycle(&mut self, duty_cycle: u8) {
-        self.duty_cycle = duty_cycle;
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
     }
 
-    pub fn decrement_length(&mut self) {
-        if self.len > 0 {
-            self.len -= 1;
-        }
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
     }
 
-    pub fn update_length(&mut self, len: u8) {
-        self.len = 64 - len;
+    fn period(&self) -> u64 {
+        4 * (2048 - u64::from(self.frequency))
     }
+}
 
-    pub fn use_length_counter(&mut self, use_len: bool) {
-        self.use_len = use_len;
+impl Channel for SquareChannel {
+    fn sample(&mut self, cpu_cycle: u64) -> f32 {
+        self.cycle = cpu_cycle;
+        if !self.dac_enabled() {
+            return 0.;
+        } else if !self.enabled {
+            return dac_output(0);
+        }
+        // A new frequency only takes effect once the current step is over
+        while self.next_step_cycle <= cpu_cycle {
+            self.duty_cycle_step = (self.duty_cycle_step + 1) % 8;
+            self.next_step_cycle += self.period();
+        }
+
+        dac_output(
+            DUTY_VALUES[self.duty_cycle as usize][self.duty_cycle_step] * self.envelope.volume(),
+        )
     }
 
-    pub fn volume_env_update(&mut self) {
-        if self.vol_counter.period() == 0 {
-            return;
+    fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.dac_enabled(),
+            // A whole waveform takes all 8 duty steps
+            frequency: CLOCK_RATE as f32 / (8 * self.period()) as f32,
+            volume: self.envelope.volume(),
+            duty: Some(self.duty_cycle),
+            length_remaining: self.length.remaining(),
+            samples: Vec::new(),
         }
+    }
 
-        if self.vol_counter.tick() {
-            if self.vol_env_increment && self.vol < 15 {
-                self.vol += 1;
-            } else if self.vol != 0 {
-                self.vol -= 1;
-            }
+    fn next_change(&self, cpu_cycle: u64) -> u64 {
+        if !self.enabled {
+            return u64::MAX;
         }
+        self.next_step_cycle.max(cpu_cycle + 1)
     }
+}
 
-    pub fn sample(&mut self, cpu_cycle: u64) -> f32 {
-        if self.period == 0 || self.frequency > 2048 || !self.is_active() {
-            return 0.;
+/// The first square channel's frequency sweep, clocked on steps 2 and 6 of
+/// the frame sequencer
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    // Switching out of negate mode after a calculation has used it since the
+    // last trigger disables the channel
+    negate_used: bool,
+}
+
+impl Sweep {
+    /// Returns true if the write disables the channel
+    fn write(&mut self, v: u8) -> bool {
+        self.period = (v >> 4) & 0b111;
+        let negate = v & 0b1000 != 0;
+        self.shift = v & 0b111;
+        let disable = self.negate && !negate && self.negate_used;
+        self.negate = negate;
+        disable
+    }
+
+    /// Returns true if the immediate overflow check disables the channel
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.timer = timer_period(self.period);
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negate_used = false;
+        self.shift != 0 && self.calculate() > 2047
+    }
+
+    /// Updates the channel's frequency, returning true if it overflowed and
+    /// the channel gets disabled
+    fn clock(&mut self, frequency: &mut u16) -> bool {
+        if self.timer > 1 {
+            self.timer -= 1;
+            return false;
         }
-        self.last_cpu_cycle = cpu_cycle;
-        while self
-            .duty_cycle_step_timer
-            .update(cpu_cycle - self.duty_cycle_step_timer_offset)
-            .is_some()
-        {
-            self.duty_cycle_step = (self.duty_cycle_step + 1) % 8;
+        self.timer = timer_period(self.period);
+        if !self.enabled || self.period == 0 {
+            return false;
         }
 
-        DUTY_VALUES[self.duty_cycle as usize][self.duty_cycle_step as usize]
-            * (f32::from(self.vol) / 15.0)
+        let new_frequency = self.calculate();
+        if new_frequency > 2047 {
+            return true;
+        }
+        if self.shift == 0 {
+            return false;
+        }
+        self.shadow_frequency = new_frequency;
+        *frequency = new_frequency;
+        // The new frequency is checked again straight away, but the result
+        // is only used for the overflow check
+        self.calculate() > 2047
     }
 
-    pub fn is_active(&self) -> bool {
-        !self.use_len || self.len > 0
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
     }
 }
+
+#[cfg(test)]
+fn playing_channel(sweep: u8, frequency: u16) -> SquareChannel {
+    let mut chan = SquareChannel::new(true);
+    chan.write_sweep(sweep);
+    chan.write_envelope(0xF0);
+    chan.write_frequency_low(frequency as u8);
+    chan.write_control(0b1000_0000 | (frequency >> 8) as u8, true);
+    chan
+}
+
+#[test]
+fn test_sweep_overflow() {
+    // The check on trigger already overflows
+    let chan = playing_channel(0b0001_0001, 0x7F0);
+    assert!(!chan.is_enabled());
+
+    // 0x500 + 0x280 is written back, but the second check on 0x780 overflows
+    let mut chan = playing_channel(0b0001_0001, 0x500);
+    assert!(chan.is_enabled());
+    chan.clock_sweep();
+    assert!(!chan.is_enabled());
+    assert_eq!(chan.frequency, 0x780);
+
+    let mut chan = playing_channel(0b0001_0010, 0x400);
+    chan.clock_sweep();
+    assert!(chan.is_enabled());
+    assert_eq!(chan.frequency, 0x500);
+}
+
+#[test]
+fn test_sweep_negate_quirk() {
+    let mut chan = playing_channel(0b0001_1001, 0x400);
+    chan.clock_sweep();
+    assert_eq!(chan.frequency, 0x200);
+    assert!(chan.is_enabled());
+    chan.write_sweep(0b0001_0001);
+    assert!(!chan.is_enabled());
+
+    // Without a calculation in negate mode it's fine
+    let mut chan = playing_channel(0b0001_1000, 0x400);
+    chan.write_sweep(0b0001_0000);
+    assert!(chan.is_enabled());
+}
+
+#[test]
+fn test_trigger_delay_and_dac() {
+    let mut chan = playing_channel(0, 0x7FF);
+    let first = chan.sample(0);
+    assert_eq!(chan.next_change(0), 4 + TRIGGER_DELAY);
+    assert_eq!(chan.sample(4 + TRIGGER_DELAY - 1), first);
+
+    chan.write_envelope(0b0000_1000);
+    assert!(chan.is_enabled());
+    chan.write_envelope(0);
+    assert!(!chan.is_enabled());
+    // Without a DAC triggering doesn't enable it
+    chan.write_control(0b1000_0000, true);
+    assert!(!chan.is_enabled());
+}
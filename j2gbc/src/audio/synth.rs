@@ -1,17 +1,81 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
 use j2ds::{next_timer_event, Timer, TimerEvent};
+use log::error;
 
 use super::{
-    mixer::Mixer, noise::NoiseChannel, square::SquareChannel, wave::WaveChannel, AudioSink,
+    blip::BlipBuffer, high_pass::HighPass, mixer::Mixer, noise::NoiseChannel,
+    square::SquareChannel, wave::WaveChannel, AudioSink, NullSink,
 };
 use crate::cpu::CLOCK_RATE;
+use crate::recorder::{AudioRecorder, RECORD_SAMPLE_RATE};
+
+/// A channel's output level, which only changes at the cycles it reports
+pub trait Channel {
+    fn sample(&mut self, cpu_cycle: u64) -> f32;
+
+    /// The first cycle after `cpu_cycle` where the level might change, or
+    /// `u64::MAX` if it won't change until the channel is written to
+    fn next_change(&self, cpu_cycle: u64) -> u64;
+
+    /// Everything but the samples, which the synth keeps track of
+    fn snapshot(&self) -> ChannelSnapshot;
+}
+
+// About a frame's worth of samples at common sink rates
+const SCOPE_LENGTH: usize = 1024;
+
+/// What a channel is playing, for visualizing the music
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelSnapshot {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    /// How many times a second the waveform repeats. For the noise channel,
+    /// how many times a second the LFSR shifts, which is 0 while it's
+    /// stopped.
+    pub frequency: f32,
+    /// From 0 to 15. The wave channel has the loudest level its volume
+    /// setting lets through.
+    pub volume: u8,
+    /// The square channels' duty cycle, from 0 (12.5%) to 3 (75%)
+    pub duty: Option<u8>,
+    /// Length counter clocks left before the channel stops, if its length
+    /// is enabled
+    pub length_remaining: Option<u16>,
+    /// The channel's latest output before mixing, oldest first, at the
+    /// sink's sample rate
+    pub samples: Vec<f32>,
+}
 
 pub struct Synth {
     sink: Box<dyn AudioSink + Send>,
 
+    // Channels are followed through every change of their level, and turned
+    // into band-limited samples for the sink and the recorder separately
+    blips: [BlipBuffer; 4],
+    record_blips: Option<[BlipBuffer; 4]>,
+    levels: [f32; 4],
+    channels_cycle: u64,
+    scopes: [VecDeque<f32>; 4],
+
+    // Each output filters at its own rate
+    high_pass: HighPass,
+    record_high_pass: HighPass,
+
     sample_clock: Timer,
-    len_clock: Timer,
-    env_clock: Timer,
-    freq_clock: Timer,
+
+    // Clocks the length counters, the sweep and the envelopes at 512 Hz,
+    // each on their own steps out of 8
+    frame_sequencer: Timer,
+    frame_step: u8,
+
+    // Runs separately from the sink's clock, so recordings have the same
+    // rate whatever the sink asks for
+    record_clock: Timer,
+    recorder: Option<AudioRecorder<BufWriter<File>>>,
 
     pub mixer: Mixer,
 
@@ -22,60 +86,225 @@ pub struct Synth {
 }
 
 impl Synth {
-    pub fn new(sink: Box<dyn AudioSink + Send>) -> Synth {
+    pub fn new(sink: Box<dyn AudioSink + Send>, cgb_mode: bool) -> Synth {
         Synth {
+            blips: channel_blips(CLOCK_RATE / sink.sample_rate(), 0, [0.; 4]),
+            record_blips: None,
+            levels: [0.; 4],
+            channels_cycle: 0,
+            scopes: Default::default(),
+
+            high_pass: HighPass::new(sink.sample_rate(), cgb_mode),
+            record_high_pass: HighPass::new(RECORD_SAMPLE_RATE, cgb_mode),
+
             sample_clock: Timer::new(CLOCK_RATE / sink.sample_rate(), 0, 0),
-            len_clock: Timer::new(CLOCK_RATE / 256, 0, 0),
-            env_clock: Timer::new(CLOCK_RATE / 64, 0, 0),
-            freq_clock: Timer::new(CLOCK_RATE / 128, 0, 0),
+            frame_sequencer: Timer::new(CLOCK_RATE / 512, 0, 0),
+            frame_step: 0,
+
+            record_clock: Timer::new(CLOCK_RATE / RECORD_SAMPLE_RATE, 0, 0),
+            recorder: None,
 
             sink,
 
             mixer: Mixer::new(),
 
-            chan1: SquareChannel::new(),
-            chan2: SquareChannel::new(),
+            chan1: SquareChannel::new(true),
+            chan2: SquareChannel::new(false),
             chan3: WaveChannel::new(),
             chan4: NoiseChannel::new(),
         }
     }
 
+    /// Records the mixed output from now on to a WAV file
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P, cycle: u64) -> io::Result<()> {
+        self.recorder = Some(AudioRecorder::create(path, RECORD_SAMPLE_RATE as u32, 2)?);
+        self.record_clock = Timer::new(CLOCK_RATE / RECORD_SAMPLE_RATE, cycle, 0);
+        self.record_blips = Some(channel_blips(
+            CLOCK_RATE / RECORD_SAMPLE_RATE,
+            cycle,
+            self.levels,
+        ));
+        // Starting from the output's charge keeps the recording from
+        // starting with a pop
+        self.record_high_pass = self.high_pass.with_sample_rate(RECORD_SAMPLE_RATE);
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> [ChannelSnapshot; 4] {
+        let mut snapshots = [
+            self.chan1.snapshot(),
+            self.chan2.snapshot(),
+            self.chan3.snapshot(),
+            self.chan4.snapshot(),
+        ];
+        for (snapshot, scope) in snapshots.iter_mut().zip(self.scopes.iter()) {
+            snapshot.samples = scope.iter().cloned().collect();
+        }
+        snapshots
+    }
+
+    /// Hands the sink over to another synth, leaving this one silent
+    pub fn take_sink(&mut self) -> Box<dyn AudioSink + Send> {
+        std::mem::replace(&mut self.sink, Box::new(NullSink))
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+        self.record_blips = None;
+    }
+
     pub fn get_next_event_cycle(&self) -> u64 {
-        next_timer_event(&[
-            self.sample_clock,
-            self.len_clock,
-            self.env_clock,
-            self.freq_clock,
-        ])
+        let next = next_timer_event(&[self.sample_clock, self.frame_sequencer]);
+        if self.recorder.is_some() {
+            next.min(next_timer_event(&[self.record_clock]))
+        } else {
+            next
+        }
+    }
+
+    /// Resets the channels and mixer as the APU is powered off. Lengths are
+    /// kept on the DMG, which can still write them while off.
+    pub fn power_off(&mut self, keep_lengths: bool) {
+        self.chan1.power_off(keep_lengths);
+        self.chan2.power_off(keep_lengths);
+        self.chan3.power_off(keep_lengths);
+        self.chan4.power_off(keep_lengths);
+        self.mixer.set_master_volumes(0, 0);
+        self.mixer.set_vin_enabled(false, false);
+        self.mixer.set_enabled_channels([false; 4], [false; 4]);
+    }
+
+    /// The cycle the channels have been run up to. Register writes are
+    /// taken to happen at this cycle.
+    pub fn cycle(&self) -> u64 {
+        self.channels_cycle
+    }
+
+    /// Powering on restarts the frame sequencer, so its next step is step 0
+    pub fn power_on(&mut self) {
+        self.frame_step = 0;
+    }
+
+    /// Whether the next frame sequencer step clocks the length counters,
+    /// which the channels need to know when their NRx4 is written
+    pub fn length_step_next(&self) -> bool {
+        self.frame_step & 1 == 0
+    }
+
+    fn dacs_enabled(&self) -> bool {
+        self.chan1.dac_enabled()
+            || self.chan2.dac_enabled()
+            || self.chan3.dac_enabled()
+            || self.chan4.dac_enabled()
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            self.chan1.clock_length();
+            self.chan2.clock_length();
+            self.chan3.clock_length();
+            self.chan4.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.chan1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.chan1.clock_envelope();
+            self.chan2.clock_envelope();
+            self.chan4.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn advance_channels(&mut self, until: u64) {
+        let Synth {
+            chan1,
+            chan2,
+            chan3,
+            chan4,
+            blips,
+            record_blips,
+            levels,
+            channels_cycle,
+            ..
+        } = self;
+        let mut channels: [&mut dyn Channel; 4] = [chan1, chan2, chan3, chan4];
+
+        for (i, chan) in channels.iter_mut().enumerate() {
+            let mut cycle = *channels_cycle;
+            loop {
+                cycle = chan.next_change(cycle).min(until);
+                let level = chan.sample(cycle);
+                levels[i] = level;
+                blips[i].set_level(cycle, level);
+                if let Some(record_blips) = record_blips.as_mut() {
+                    record_blips[i].set_level(cycle, level);
+                }
+                if cycle >= until {
+                    break;
+                }
+            }
+        }
+        *channels_cycle = until;
     }
 
     pub fn pump_cycle(&mut self, cpu_cycle: u64) {
+        self.advance_channels(cpu_cycle);
+
         if self.sample_clock.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
-            let samples = [
-                self.chan1.sample(cpu_cycle),
-                self.chan2.sample(cpu_cycle),
-                self.chan3.sample(cpu_cycle),
-                self.chan4.sample(cpu_cycle),
-            ];
-            self.sink.emit_sample(self.mixer.mix(samples));
+            let samples = read_samples(&mut self.blips, cpu_cycle);
+            let sample = self
+                .high_pass
+                .filter(self.mixer.mix(samples), self.dacs_enabled());
+            self.sink.emit_sample(sample);
             self.sink.emit_raw_chans(samples);
-        }
 
-        if self.len_clock.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
-            self.chan1.decrement_length();
-            self.chan2.decrement_length();
-            self.chan3.decrement_length();
-            self.chan4.decrement_length();
+            for (scope, sample) in self.scopes.iter_mut().zip(samples.iter()) {
+                if scope.len() == SCOPE_LENGTH {
+                    scope.pop_front();
+                }
+                scope.push_back(*sample);
+            }
         }
 
-        if self.env_clock.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
-            self.chan1.volume_env_update();
-            self.chan2.volume_env_update();
-            self.chan4.volume_env_update();
+        let dacs_enabled = self.dacs_enabled();
+        if let Some(record_blips) = self.record_blips.as_mut() {
+            if self.record_clock.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
+                let sample = self.record_high_pass.filter(
+                    self.mixer.mix(read_samples(record_blips, cpu_cycle)),
+                    dacs_enabled,
+                );
+                if let Some(Err(e)) = self
+                    .recorder
+                    .as_mut()
+                    .map(|r| r.push_frame(&[sample.0, sample.1]))
+                {
+                    error!("Stopped recording audio: {}", e);
+                    self.stop_recording();
+                }
+            }
         }
 
-        if self.freq_clock.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
-            self.chan1.freq_sweep_update();
+        if self.frame_sequencer.update(cpu_cycle) == Some(TimerEvent::RisingEdge) {
+            self.step_frame_sequencer();
         }
     }
 }
+
+fn channel_blips(period: u64, cycle: u64, levels: [f32; 4]) -> [BlipBuffer; 4] {
+    [
+        BlipBuffer::new(period, cycle, levels[0]),
+        BlipBuffer::new(period, cycle, levels[1]),
+        BlipBuffer::new(period, cycle, levels[2]),
+        BlipBuffer::new(period, cycle, levels[3]),
+    ]
+}
+
+fn read_samples(blips: &mut [BlipBuffer; 4], cycle: u64) -> [f32; 4] {
+    [
+        blips[0].read_sample(cycle),
+        blips[1].read_sample(cycle),
+        blips[2].read_sample(cycle),
+        blips[3].read_sample(cycle),
+    ]
+}
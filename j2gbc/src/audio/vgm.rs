@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::cpu::CLOCK_RATE;
+
+// VGM files count time in samples at this rate, whatever the chip runs at
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VGM_VERSION: u32 = 0x171;
+const HEADER_SIZE: u32 = 0x100;
+
+const HEADER_EOF_OFFSET: usize = 0x04;
+const HEADER_VERSION: usize = 0x08;
+const HEADER_TOTAL_SAMPLES: usize = 0x18;
+const HEADER_DATA_OFFSET: usize = 0x34;
+const HEADER_DMG_CLOCK: usize = 0x80;
+
+const CMD_DMG_WRITE: u8 = 0xB3;
+const CMD_WAIT: u8 = 0x61;
+// Waits of 1 to 16 samples fit in the low bits of the command
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+/// Logs writes to the sound registers as a VGM 1.71 file, which players
+/// replay through their own DMG sound chip.
+///
+/// Registers are numbered from NR10 as 0, so the wave RAM starts at 0x20.
+/// The header is patched with the current length every second of music, the
+/// same as for WAV recordings.
+pub struct VgmLogger<W: Write + Seek> {
+    out: W,
+    start_cycle: u64,
+    samples: u64,
+    data_size: u32,
+    ended: bool,
+}
+
+impl VgmLogger<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        start_cycle: u64,
+    ) -> io::Result<VgmLogger<BufWriter<File>>> {
+        VgmLogger::new(BufWriter::new(File::create(path)?), start_cycle)
+    }
+}
+
+impl<W: Write + Seek> VgmLogger<W> {
+    pub fn new(mut out: W, start_cycle: u64) -> io::Result<VgmLogger<W>> {
+        let mut header = [0; HEADER_SIZE as usize];
+        header[0..4].copy_from_slice(b"Vgm ");
+        put_u32(&mut header, HEADER_EOF_OFFSET, HEADER_SIZE - 4);
+        put_u32(&mut header, HEADER_VERSION, VGM_VERSION);
+        // Relative to the field itself
+        put_u32(
+            &mut header,
+            HEADER_DATA_OFFSET,
+            HEADER_SIZE - HEADER_DATA_OFFSET as u32,
+        );
+        put_u32(&mut header, HEADER_DMG_CLOCK, CLOCK_RATE as u32);
+        out.write_all(&header)?;
+        Ok(VgmLogger {
+            out,
+            start_cycle,
+            samples: 0,
+            data_size: 0,
+            ended: false,
+        })
+    }
+
+    pub fn log_write(&mut self, cycle: u64, register: u8, v: u8) -> io::Result<()> {
+        self.wait_until(cycle)?;
+        self.write_data(&[CMD_DMG_WRITE, register, v])
+    }
+
+    /// Ends the log at `cycle`, so it plays for as long as it was recorded
+    pub fn finish(&mut self, cycle: u64) -> io::Result<()> {
+        if !self.ended {
+            self.wait_until(cycle)?;
+        }
+        self.end()
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        if !self.ended {
+            self.write_data(&[CMD_END])?;
+            self.ended = true;
+        }
+        self.update_header()?;
+        self.out.flush()
+    }
+
+    fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        let target = cycle.saturating_sub(self.start_cycle) * VGM_SAMPLE_RATE / CLOCK_RATE;
+        let second = self.samples / VGM_SAMPLE_RATE;
+        while self.samples < target {
+            let wait = (target - self.samples).min(u64::from(u16::MAX));
+            if wait <= 16 {
+                self.write_data(&[CMD_WAIT_SHORT | (wait - 1) as u8])?;
+            } else {
+                let wait = (wait as u16).to_le_bytes();
+                self.write_data(&[CMD_WAIT, wait[0], wait[1]])?;
+            }
+            self.samples += wait;
+        }
+        if self.samples / VGM_SAMPLE_RATE != second {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.data_size += data.len() as u32;
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(HEADER_EOF_OFFSET as u64))?;
+        self.out
+            .write_all(&(HEADER_SIZE + self.data_size - 4).to_le_bytes())?;
+        self.out
+            .seek(SeekFrom::Start(HEADER_TOTAL_SAMPLES as u64))?;
+        self.out.write_all(&(self.samples as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for VgmLogger<W> {
+    fn drop(&mut self) {
+        let _ = self.end();
+    }
+}
+
+fn put_u32(header: &mut [u8], offset: usize, v: u32) {
+    header[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+#[test]
+fn test_vgm_log() {
+    let mut out = io::Cursor::new(Vec::new());
+    {
+        let mut logger = VgmLogger::new(&mut out, 1000).unwrap();
+        logger.log_write(1000, 0x16, 0x80).unwrap();
+        logger.log_write(1000 + CLOCK_RATE, 0x12, 0xF0).unwrap();
+        logger.log_write(1000 + CLOCK_RATE, 0x20, 0x12).unwrap();
+        logger.finish(1000 + CLOCK_RATE + 1000).unwrap();
+    }
+    let vgm = out.into_inner();
+
+    assert_eq!(vgm[0..4], *b"Vgm ");
+    assert_eq!(vgm[0x04..0x08], (vgm.len() as u32 - 4).to_le_bytes());
+    assert_eq!(vgm[0x08..0x0C], 0x171u32.to_le_bytes());
+    assert_eq!(vgm[0x18..0x1C], 44110u32.to_le_bytes());
+    assert_eq!(vgm[0x80..0x84], 4_194_304u32.to_le_bytes());
+    assert_eq!(
+        vgm[0x100..],
+        [
+            0xB3, 0x16, 0x80, // NR52 straight away
+            0x61, 0x44, 0xAC, // A second later
+            0xB3, 0x12, 0xF0, // NR12
+            0xB3, 0x20, 0x12, // Wave RAM
+            0x79, // The rest of the log
+            0x66,
+        ]
+    );
+}
@@ -1,72 +1,240 @@
-#[derive(Default)]
-pub struct WaveChannel {
-    samples: [f32; 32],
-    period: u64,
-    pub use_len: bool,
-    len: u8,
+use super::dac_output;
+use super::length::Length;
+use super::synth::{Channel, ChannelSnapshot};
+use crate::cpu::CLOCK_RATE;
+
+// Cycles between a trigger and the first sample being fetched, on top of a
+// whole period
+const TRIGGER_DELAY: u64 = 6;
+
+// How long after fetching a sample the DMG lets the CPU at the wave RAM
+const DMG_RAM_ACCESS_WINDOW: u64 = 2;
 
-    pub enabled: bool,
+pub struct WaveChannel {
+    ram: [u8; 16],
 
-    pub vol_multiplier: f32,
+    enabled: bool,
+    dac_enabled: bool,
+    frequency: u16,
+    volume_code: u8,
+    position: usize,
+    // The byte of wave RAM the current sample comes from
+    sample_buffer: u8,
+    next_step_cycle: u64,
+    last_fetch_cycle: u64,
+    cycle: u64,
 
-    position_offset_cycle: u64,
-    last_cpu_cycle: u64,
+    length: Length,
 }
 
 impl WaveChannel {
     pub fn new() -> WaveChannel {
         WaveChannel {
-            samples: [-1.; 32],
-            period: 0,
-            use_len: false,
-            len: 0,
+            ram: [0; 16],
 
             enabled: false,
-            vol_multiplier: 0.,
+            dac_enabled: false,
+            frequency: 0,
+            volume_code: 0,
+            position: 0,
+            sample_buffer: 0,
+            next_step_cycle: 0,
+            last_fetch_cycle: 0,
+            cycle: 0,
+
+            length: Length::new(256),
+        }
+    }
+
+    // The wave RAM isn't affected by power
+    pub fn power_off(&mut self, keep_length: bool) {
+        let (ram, mut length, cycle) = (self.ram, self.length, self.cycle);
+        *self = WaveChannel::new();
+        self.ram = ram;
+        self.cycle = cycle;
+        length.power_off(keep_length);
+        self.length = length;
+    }
+
+    /// While the channel plays, the CPU only gets at the byte the channel is
+    /// reading. The DMG only allows that right as the byte is fetched, and
+    /// reads 0xFF otherwise.
+    pub fn read_ram(&self, offset: usize, cgb_mode: bool) -> u8 {
+        match self.ram_access(offset, cgb_mode) {
+            Some(i) => self.ram[i],
+            None => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, offset: usize, v: u8, cgb_mode: bool) {
+        if let Some(i) = self.ram_access(offset, cgb_mode) {
+            self.ram[i] = v;
+        }
+    }
+
+    /// The whole wave RAM, without the limits on what the CPU can reach
+    /// while the channel plays
+    pub fn ram(&self) -> [u8; 16] {
+        self.ram
+    }
+
+    fn ram_access(&self, offset: usize, cgb_mode: bool) -> Option<usize> {
+        if !self.enabled {
+            Some(offset)
+        } else if cgb_mode || self.cycle < self.last_fetch_cycle + DMG_RAM_ACCESS_WINDOW {
+            Some(self.position / 2)
+        } else {
+            None
+        }
+    }
+
+    /// Writes NR30
+    pub fn write_dac(&mut self, v: u8) {
+        self.dac_enabled = v & 0b1000_0000 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
 
-            position_offset_cycle: 0,
-            last_cpu_cycle: 0,
+    /// Writes NR31
+    pub fn write_length(&mut self, v: u8) {
+        self.length.load(v);
+    }
+
+    /// Writes NR32
+    pub fn write_volume(&mut self, v: u8) {
+        self.volume_code = (v >> 5) & 0b11;
+    }
+
+    /// Writes NR33
+    pub fn write_frequency_low(&mut self, v: u8) {
+        self.frequency = (self.frequency & 0x700) | u16::from(v);
+    }
+
+    /// Writes NR34
+    pub fn write_control(&mut self, v: u8, length_step_next: bool) {
+        self.frequency = (self.frequency & 0xFF) | (u16::from(v) & 0b111) << 8;
+        if self
+            .length
+            .set_enabled(v & 0b0100_0000 != 0, length_step_next)
+        {
+            self.enabled = false;
         }
+        if v & 0b1000_0000 != 0 {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(length_step_next);
+            // The sample buffer isn't refilled, so the last sample fetched
+            // plays until the first step
+            self.position = 0;
+            self.next_step_cycle = self.cycle + self.period() + TRIGGER_DELAY;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
     }
 
-    pub fn set_frequency_from_bits(&mut self, hi: u8, lo: u8) {
-        let f = (u64::from(hi) & 0b111) << 8 | u64::from(lo);
-        self.period = (2048 - f) * 2 * 32;
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
     }
 
-    pub fn set_len(&mut self, len: u8) {
-        self.len = 255 - len;
+    // Each of the 32 samples plays for an equal part of the wave
+    fn period(&self) -> u64 {
+        2 * (2048 - u64::from(self.frequency))
     }
 
-    pub fn decrement_length(&mut self) {
-        if self.len > 0 {
-            self.len -= 1;
+    // Volume is applied by shifting the samples down, all the way to
+    // silence for code 0
+    fn volume_shift(&self) -> u8 {
+        match self.volume_code {
+            0 => 4,
+            code => code - 1,
         }
     }
+}
 
-    pub fn write_sample(&mut self, sample: f32, position: usize) {
-        self.samples[position] = sample;
+impl Default for WaveChannel {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    pub fn sample(&mut self, cpu_cycle: u64) -> f32 {
-        if self.period == 0 || !self.is_active() {
+impl Channel for WaveChannel {
+    fn sample(&mut self, cpu_cycle: u64) -> f32 {
+        self.cycle = cpu_cycle;
+        if !self.dac_enabled {
             return 0.;
+        } else if !self.enabled {
+            return dac_output(0);
+        }
+        while self.next_step_cycle <= cpu_cycle {
+            self.position = (self.position + 1) % 32;
+            self.sample_buffer = self.ram[self.position / 2];
+            self.last_fetch_cycle = self.next_step_cycle;
+            self.next_step_cycle += self.period();
         }
-        self.last_cpu_cycle = cpu_cycle;
 
-        let phase = (cpu_cycle + self.position_offset_cycle) % self.period;
-        let position = phase * 32 / self.period;
-        self.samples[position as usize] * self.vol_multiplier
+        let bits = if self.position & 1 == 0 {
+            self.sample_buffer >> 4
+        } else {
+            self.sample_buffer & 0b1111
+        };
+        dac_output(bits >> self.volume_shift())
     }
 
-    pub fn is_active(&self) -> bool {
-        (!self.use_len || self.len > 0) && self.enabled
+    fn snapshot(&self) -> ChannelSnapshot {
+        ChannelSnapshot {
+            enabled: self.enabled,
+            dac_enabled: self.dac_enabled,
+            frequency: CLOCK_RATE as f32 / (32 * self.period()) as f32,
+            volume: 0b1111 >> self.volume_shift(),
+            duty: None,
+            length_remaining: self.length.remaining(),
+            samples: Vec::new(),
+        }
     }
 
-    pub fn reset(&mut self) {
-        self.position_offset_cycle = self.last_cpu_cycle;
-        if self.len == 0 {
-            self.len = 255;
+    fn next_change(&self, cpu_cycle: u64) -> u64 {
+        if !self.enabled {
+            return u64::MAX;
+        }
+        self.next_step_cycle.max(cpu_cycle + 1)
+    }
+}
+
+#[test]
+fn test_ram_access_while_playing() {
+    for &cgb_mode in &[false, true] {
+        let mut chan = WaveChannel::new();
+        for i in 0..16 {
+            chan.write_ram(i, i as u8 * 0x11, cgb_mode);
+        }
+        chan.write_dac(0b1000_0000);
+        chan.write_volume(0b0010_0000);
+        chan.write_frequency_low(0xF0);
+        chan.write_control(0b1000_0111, true);
+        let period = 32;
+
+        // Fetch samples 1 to 3, and access RAM right as the last one is read
+        let fetch = period + TRIGGER_DELAY + 2 * period;
+        chan.sample(fetch);
+        assert_eq!(chan.read_ram(0, cgb_mode), 0x11);
+        chan.write_ram(5, 0xAB, cgb_mode);
+        assert_eq!(chan.ram[1], 0xAB);
+
+        chan.sample(fetch + period / 2);
+        if cgb_mode {
+            assert_eq!(chan.read_ram(0, cgb_mode), 0xAB);
+        } else {
+            assert_eq!(chan.read_ram(0, cgb_mode), 0xFF);
+            chan.write_ram(1, 0, cgb_mode);
+            assert_eq!(chan.ram[1], 0xAB);
         }
     }
 }
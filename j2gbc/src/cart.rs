@@ -19,15 +19,21 @@ pub struct Cart {
 const OFF_CART_NAME_START: usize = 0x134;
 const OFF_CART_NAME_END: usize = 0x142;
 const OFF_CART_CGB_SUPPORTED: usize = 0x143;
+const OFF_CART_NEW_LICENSEE: usize = 0x144;
+const OFF_CART_SGB_SUPPORTED: usize = 0x146;
 const OFF_CART_TYPE: usize = 0x147;
 const OFF_CART_SIZE: usize = 0x148;
 const OFF_RAM_SIZE: usize = 0x149;
+const OFF_CART_OLD_LICENSEE: usize = 0x14B;
 
 impl Cart {
     pub fn load<R: Read>(mut r: R) -> io::Result<Cart> {
         let mut data = Vec::new();
         r.read_to_end(&mut data)?;
+        Ok(Cart::new(data))
+    }
 
+    pub fn new(data: Vec<u8>) -> Cart {
         let mbc: Box<dyn Mbc + Send> = match data[OFF_CART_TYPE] {
             0x00 => Box::new(Mbc0::new(data.clone())),
             0x01 | 0x02 | 0x03 => Box::new(Mbc1::new(data.clone())),
@@ -37,7 +43,7 @@ impl Cart {
             }
         };
 
-        Ok(Cart { data, mbc })
+        Cart { data, mbc }
     }
 
     pub fn name(&self) -> String {
@@ -93,6 +99,32 @@ impl Cart {
         let v = self.data[OFF_CART_CGB_SUPPORTED];
         v == 0x80 || v == 0xC0
     }
+
+    // The SGB ignores the flag unless the old licensee code says to use the
+    // new one
+    pub fn supports_sgb(&self) -> bool {
+        self.data[OFF_CART_SGB_SUPPORTED] == 0x03 && self.data[OFF_CART_OLD_LICENSEE] == 0x33
+    }
+
+    pub fn is_nintendo_licensed(&self) -> bool {
+        match self.data[OFF_CART_OLD_LICENSEE] {
+            0x01 => true,
+            0x33 => &self.data[OFF_CART_NEW_LICENSEE..OFF_CART_NEW_LICENSEE + 2] == b"01",
+            _ => false,
+        }
+    }
+
+    /// The sum of the title bytes, which the CGB boot ROM uses to look up a
+    /// palette for DMG-only carts
+    pub fn title_checksum(&self) -> u8 {
+        self.data[OFF_CART_NAME_START..OFF_CART_CGB_SUPPORTED + 1]
+            .iter()
+            .fold(0, |sum, b| sum.wrapping_add(*b))
+    }
+
+    pub fn title_fourth_letter(&self) -> u8 {
+        self.data[OFF_CART_NAME_START + 3]
+    }
 }
 
 impl MemDevice for Cart {
@@ -13,6 +13,7 @@ use crate::{
     audio::AudioSink,
     cart::Cart,
     inst::{Arith, Bits, Control, Instruction, Load, Logic},
+    lcd::compat,
     mem::{Address, MemDevice},
     mmu::Mmu,
 };
@@ -49,11 +50,21 @@ impl Cpu {
     pub fn new(c: Cart, audio_sink: Box<dyn AudioSink + Send>, mut cgb_mode: bool) -> Cpu {
         let initial_breakpoints = HashSet::new();
 
+        let cgb_hardware = cgb_mode;
+        let mut compat_palettes = None;
         if cgb_mode {
             cgb_mode = c.supports_cgb_mode();
+            if !cgb_mode {
+                compat_palettes = Some(compat::title_palettes(
+                    c.is_nintendo_licensed(),
+                    c.title_checksum(),
+                    c.title_fourth_letter(),
+                ));
+            }
         }
 
         debug!("CGB mode: {}", cgb_mode);
+        debug!("DMG compatibility mode: {}", compat_palettes.is_some());
 
         let mut cpu = Cpu {
             registers: [0, 0, 0, 0, 0, 0, 0, 0],
@@ -69,7 +80,11 @@ impl Cpu {
             interrupt_breakpoints: HashSet::new(),
         };
 
-        cpu[Register8::A] = if cgb_mode { 0x11 } else { 0x01 };
+        if let Some(palettes) = compat_palettes {
+            cpu.mmu.lcd.set_compat_palettes(&palettes);
+        }
+
+        cpu[Register8::A] = if cgb_hardware { 0x11 } else { 0x01 };
         cpu[Register8::F] = 0xB0;
         cpu[Register8::B] = 0x00;
         cpu[Register8::C] = 0x13;
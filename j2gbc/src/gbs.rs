@@ -0,0 +1,268 @@
+use std::io::{self, Read};
+
+use crate::cart::Cart;
+
+const GBS_HEADER_SIZE: usize = 0x70;
+const GBS_VERSION: u8 = 1;
+
+const OFF_GBS_VERSION: usize = 0x03;
+const OFF_GBS_TRACK_COUNT: usize = 0x04;
+const OFF_GBS_FIRST_TRACK: usize = 0x05;
+const OFF_GBS_LOAD_ADDRESS: usize = 0x06;
+const OFF_GBS_INIT_ADDRESS: usize = 0x08;
+const OFF_GBS_PLAY_ADDRESS: usize = 0x0A;
+const OFF_GBS_STACK_POINTER: usize = 0x0C;
+const OFF_GBS_TMA: usize = 0x0E;
+const OFF_GBS_TAC: usize = 0x0F;
+const OFF_GBS_TITLE: usize = 0x10;
+const OFF_GBS_AUTHOR: usize = 0x30;
+const OFF_GBS_COPYRIGHT: usize = 0x50;
+const GBS_STRING_LEN: usize = 0x20;
+
+// Everything below the load address belongs to the driver, and the RST
+// vectors, interrupt vectors and cart header all have to fit in there
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+const DRIVER_ADDRESS: u16 = 0x150;
+
+const VBLANK_VECTOR: usize = 0x40;
+const TIMER_VECTOR: usize = 0x50;
+
+// The low bytes of the registers the driver writes
+const IO_TIMA: u8 = 0x05;
+const IO_TMA: u8 = 0x06;
+const IO_TAC: u8 = 0x07;
+const IO_IF: u8 = 0x0F;
+const IO_NR50: u8 = 0x24;
+const IO_NR51: u8 = 0x25;
+const IO_NR52: u8 = 0x26;
+const IO_KEY1: u8 = 0x4D;
+const IO_IE: u8 = 0xFF;
+
+const TAC_TIMER_ENABLED: u8 = 0b0000_0100;
+const TAC_DOUBLE_SPEED: u8 = 0b1000_0000;
+
+/// A GBS file, which holds just the music code and data ripped from a game.
+///
+/// It gets played by building a cart around it, whose code calls the
+/// file's INIT routine for the selected track and then its PLAY routine from
+/// either the timer or the VBlank interrupt.
+pub struct Gbs {
+    track_count: u8,
+    first_track: u8,
+    load_address: u16,
+    init_address: u16,
+    play_address: u16,
+    stack_pointer: u16,
+    tma: u8,
+    tac: u8,
+    title: String,
+    author: String,
+    copyright: String,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn load<R: Read>(mut r: R) -> io::Result<Gbs> {
+        let mut file = Vec::new();
+        r.read_to_end(&mut file)?;
+
+        if file.len() < GBS_HEADER_SIZE || &file[0..3] != b"GBS" {
+            return Err(invalid_data("Not a GBS file"));
+        }
+        if file[OFF_GBS_VERSION] != GBS_VERSION {
+            return Err(invalid_data("Unsupported GBS version"));
+        }
+        let load_address = read_u16(&file, OFF_GBS_LOAD_ADDRESS);
+        if load_address < MIN_LOAD_ADDRESS {
+            return Err(invalid_data("GBS load address overlaps the driver"));
+        }
+        let track_count = file[OFF_GBS_TRACK_COUNT].max(1);
+
+        Ok(Gbs {
+            track_count,
+            // Numbered from 1 in the header
+            first_track: file[OFF_GBS_FIRST_TRACK]
+                .saturating_sub(1)
+                .min(track_count - 1),
+            load_address,
+            init_address: read_u16(&file, OFF_GBS_INIT_ADDRESS),
+            play_address: read_u16(&file, OFF_GBS_PLAY_ADDRESS),
+            stack_pointer: read_u16(&file, OFF_GBS_STACK_POINTER),
+            tma: file[OFF_GBS_TMA],
+            tac: file[OFF_GBS_TAC],
+            title: read_string(&file, OFF_GBS_TITLE),
+            author: read_string(&file, OFF_GBS_AUTHOR),
+            copyright: read_string(&file, OFF_GBS_COPYRIGHT),
+            data: file[GBS_HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    pub fn copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    pub fn track_count(&self) -> u8 {
+        self.track_count
+    }
+
+    /// The track to start on, counting from 0
+    pub fn first_track(&self) -> u8 {
+        self.first_track
+    }
+
+    /// A cart that plays `track` as soon as it starts. The music runs at
+    /// double speed if the file asks for it and CGB mode is allowed.
+    pub(crate) fn cart(&self, track: u8, allow_cgb_mode: bool) -> Cart {
+        Cart::new(self.rom(track, allow_cgb_mode && self.tac & TAC_DOUBLE_SPEED != 0))
+    }
+
+    // An MBC5 ROM with the music data at the load address, so the data's
+    // banks line up with the ROM's
+    fn rom(&self, track: u8, double_speed: bool) -> Vec<u8> {
+        let load = usize::from(self.load_address);
+        let size = (load + self.data.len()).next_power_of_two().max(0x8000);
+        let mut rom = vec![0; size];
+        rom[load..load + self.data.len()].copy_from_slice(&self.data);
+
+        // The music's RSTs go to the same offsets from its load address
+        for vector in (0..VBLANK_VECTOR).step_by(8) {
+            let target = self.load_address + vector as u16;
+            put_code(&mut rom, vector, &[0xC3, lo(target), hi(target)]);
+        }
+        for &vector in &[VBLANK_VECTOR, TIMER_VECTOR] {
+            // call play; reti
+            let play = self.play_address;
+            put_code(&mut rom, vector, &[0xCD, lo(play), hi(play), 0xD9]);
+        }
+
+        // nop; jp driver
+        put_code(
+            &mut rom,
+            0x100,
+            &[0x00, 0xC3, lo(DRIVER_ADDRESS), hi(DRIVER_ADDRESS)],
+        );
+        rom[0x143] = if double_speed { 0x80 } else { 0x00 };
+        // MBC5, with an 8 KiB RAM for files that keep their state there
+        rom[0x147] = 0x19;
+        rom[0x148] = (size / 0x8000).trailing_zeros() as u8;
+        rom[0x149] = 0x02;
+
+        let driver = self.driver(track, double_speed);
+        put_code(&mut rom, usize::from(DRIVER_ADDRESS), &driver);
+        rom
+    }
+
+    fn driver(&self, track: u8, double_speed: bool) -> Vec<u8> {
+        let (sp, init) = (self.stack_pointer, self.init_address);
+        // di; ld sp, stack pointer
+        let mut code = vec![0xF3, 0x31, lo(sp), hi(sp)];
+        // Sound on, at full volume on both sides, as the music expects
+        write_io(&mut code, IO_NR52, 0x80);
+        write_io(&mut code, IO_NR50, 0x77);
+        write_io(&mut code, IO_NR51, 0xFF);
+        if double_speed {
+            write_io(&mut code, IO_KEY1, 0x01);
+            // stop
+            code.extend_from_slice(&[0x10, 0x00]);
+        }
+
+        let interrupts = if self.tac & TAC_TIMER_ENABLED != 0 {
+            0b0000_0100
+        } else {
+            0b0000_0001
+        };
+        // The first PLAY comes a whole period after INIT
+        write_io(&mut code, IO_TIMA, self.tma);
+        write_io(&mut code, IO_TMA, self.tma);
+        write_io(&mut code, IO_TAC, self.tac & 0b111);
+        write_io(&mut code, IO_IF, 0);
+        write_io(&mut code, IO_IE, interrupts);
+
+        // ld a, track; call init; ei
+        code.extend_from_slice(&[0x3E, track, 0xCD, lo(init), hi(init), 0xFB]);
+        // halt; jr back to the halt
+        code.extend_from_slice(&[0x76, 0x18, 0xFD]);
+        code
+    }
+}
+
+// ld a, v; ldh (register), a
+fn write_io(code: &mut Vec<u8>, register: u8, v: u8) {
+    code.extend_from_slice(&[0x3E, v, 0xE0, register]);
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u16(file: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([file[offset], file[offset + 1]])
+}
+
+fn read_string(file: &[u8], offset: usize) -> String {
+    let b = &file[offset..offset + GBS_STRING_LEN];
+    let s = b
+        .iter()
+        .take_while(|n| **n != 0)
+        .cloned()
+        .collect::<Vec<u8>>();
+    String::from_utf8_lossy(&s[..]).into_owned()
+}
+
+fn put_code(rom: &mut [u8], address: usize, code: &[u8]) {
+    rom[address..address + code.len()].copy_from_slice(code);
+}
+
+fn lo(v: u16) -> u8 {
+    v as u8
+}
+
+fn hi(v: u16) -> u8 {
+    (v >> 8) as u8
+}
+
+#[cfg(test)]
+pub fn test_gbs(tac: u8, data: &[u8]) -> Vec<u8> {
+    let mut file = vec![0; GBS_HEADER_SIZE];
+    file[0..3].copy_from_slice(b"GBS");
+    file[OFF_GBS_VERSION] = GBS_VERSION;
+    file[OFF_GBS_TRACK_COUNT] = 3;
+    file[OFF_GBS_FIRST_TRACK] = 2;
+    file[OFF_GBS_LOAD_ADDRESS..OFF_GBS_LOAD_ADDRESS + 2].copy_from_slice(&[0x00, 0x04]);
+    file[OFF_GBS_INIT_ADDRESS..OFF_GBS_INIT_ADDRESS + 2].copy_from_slice(&[0x00, 0x04]);
+    file[OFF_GBS_PLAY_ADDRESS..OFF_GBS_PLAY_ADDRESS + 2].copy_from_slice(&[0x10, 0x04]);
+    file[OFF_GBS_STACK_POINTER..OFF_GBS_STACK_POINTER + 2].copy_from_slice(&[0xFE, 0xFF]);
+    file[OFF_GBS_TAC] = tac;
+    file[OFF_GBS_TITLE..OFF_GBS_TITLE + 4].copy_from_slice(b"Test");
+    file.extend_from_slice(data);
+    file
+}
+
+#[test]
+fn test_gbs_header() {
+    let gbs = Gbs::load(&test_gbs(0, &[0xC9])[..]).unwrap();
+    assert_eq!(gbs.title(), "Test");
+    assert_eq!(gbs.author(), "");
+    assert_eq!(gbs.track_count(), 3);
+    assert_eq!(gbs.first_track(), 1);
+
+    let rom = gbs.rom(2, false);
+    assert_eq!(rom.len(), 0x8000);
+    assert_eq!(rom[0x400], 0xC9);
+    // rst 0x08 goes into the music's code
+    assert_eq!(rom[0x08..0x0B], [0xC3, 0x08, 0x04]);
+    assert_eq!(rom[0x40..0x44], [0xCD, 0x10, 0x04, 0xD9]);
+
+    let mut bad = test_gbs(0, &[]);
+    bad[OFF_GBS_LOAD_ADDRESS + 1] = 0x01;
+    assert!(Gbs::load(&bad[..]).is_err());
+    assert!(Gbs::load(&b"GBX"[..]).is_err());
+}
@@ -2,7 +2,7 @@ use std::collections::HashSet;
 use std::ops::BitOr;
 
 use super::mem::*;
-use crate::error::ExecutionError;
+use crate::{error::ExecutionError, sgb};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Button {
@@ -24,9 +24,19 @@ const P13: u8 = 0b0000_1000;
 const P14: u8 = 0b0001_0000;
 const P15: u8 = 0b0010_0000;
 
+/// The SGB supports up to 4 joypads through MLT_REQ
+pub const MAX_PLAYERS: usize = 4;
+
 const INPUT_MASK: u8 = P10 | P11 | P12 | P13;
 const OUTPUT_MASK: u8 = P14 | P15;
 
+// SGB packets are sent as pulses on P14 and P15, each followed by releasing
+// both. Pulling both low resets the receiver.
+const SGB_PULSE_RESET: u8 = 0;
+const SGB_PULSE_ZERO: u8 = P15;
+const SGB_PULSE_ONE: u8 = P14;
+const SGB_PACKET_BITS: usize = sgb::PACKET_SIZE * 8;
+
 impl Button {
     fn selected_by_output(self, output: u8) -> bool {
         match self {
@@ -47,21 +57,45 @@ impl Button {
 
 #[derive(Default)]
 pub struct Input {
-    active: HashSet<Button>,
+    active: [HashSet<Button>; MAX_PLAYERS],
     p1: u8,
+
+    sgb: bool,
+    packet: [u8; sgb::PACKET_SIZE],
+    packet_bit: Option<usize>,
+    command: Vec<u8>,
+    completed_command: Option<Vec<u8>>,
+    player_count: u8,
+    player: u8,
 }
 
 impl Input {
     pub fn new() -> Input {
         Input {
-            active: HashSet::new(),
+            active: Default::default(),
             p1: OUTPUT_MASK | INPUT_MASK,
+
+            sgb: false,
+            packet: [0; sgb::PACKET_SIZE],
+            packet_bit: None,
+            command: Vec::new(),
+            completed_command: None,
+            player_count: 1,
+            player: 0,
         }
     }
 
+    pub fn enable_sgb(&mut self) {
+        self.sgb = true;
+    }
+
+    /// The last SGB command that was fully received, if it hasn't been taken yet
+    pub fn take_sgb_command(&mut self) -> Option<Vec<u8>> {
+        self.completed_command.take()
+    }
+
     fn active_input_bits(&self, output_bits: u8) -> u8 {
-        (!self
-            .active
+        (!self.active[usize::from(self.player)]
             .iter()
             .filter(|x| x.selected_by_output(output_bits))
             .map(|b| b.output())
@@ -71,18 +105,89 @@ impl Input {
 
     fn recalculate(&mut self) {
         let output_bits = self.p1 & OUTPUT_MASK;
-        let input_bits = self.active_input_bits(output_bits);
+        let input_bits = if output_bits == OUTPUT_MASK {
+            // With MLT_REQ the SGB reports the selected joypad's ID here,
+            // counting down from 0xF
+            INPUT_MASK - self.player
+        } else {
+            self.active_input_bits(output_bits)
+        };
         self.p1 = output_bits | input_bits;
     }
 
-    pub fn activate_button(&mut self, button: Button) {
-        self.active.insert(button);
-        self.recalculate();
+    fn receive_sgb_pulse(&mut self, previous: u8, output: u8) {
+        if previous != OUTPUT_MASK {
+            // Moving the rising edge of P15 to the next joypad is how games
+            // poll the other players
+            if previous & P15 == 0 && output & P15 != 0 && self.packet_bit.is_none() {
+                self.player = (self.player + 1) % self.player_count;
+            }
+            return;
+        }
+
+        match output {
+            SGB_PULSE_RESET => {
+                self.packet = [0; sgb::PACKET_SIZE];
+                self.packet_bit = Some(0);
+            }
+            SGB_PULSE_ZERO | SGB_PULSE_ONE => {
+                if let Some(bit) = self.packet_bit {
+                    if bit == SGB_PACKET_BITS {
+                        // Stop bit
+                        self.packet_bit = None;
+                        self.finish_sgb_packet();
+                    } else {
+                        if output == SGB_PULSE_ONE {
+                            self.packet[bit / 8] |= 1 << (bit % 8);
+                        }
+                        self.packet_bit = Some(bit + 1);
+                    }
+                }
+            }
+            _ => {}
+        }
     }
 
-    pub fn deactivate_button(&mut self, button: Button) {
-        self.active.remove(&button);
-        self.recalculate();
+    fn finish_sgb_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = sgb::command_packet_count(self.command[0]);
+        if self.command.len() < packets * sgb::PACKET_SIZE {
+            return;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        if sgb::command_id(&command) == sgb::CMD_MLT_REQ {
+            self.player_count = match command[1] & 0b11 {
+                1 => 2,
+                3 => 4,
+                _ => 1,
+            };
+            self.player = 0;
+        }
+        self.completed_command = Some(command);
+    }
+
+    /// Returns false, leaving the buttons alone, if there is no such player
+    pub fn activate_button(&mut self, player: usize, button: Button) -> bool {
+        match self.active.get_mut(player) {
+            Some(active) => {
+                active.insert(button);
+                self.recalculate();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn deactivate_button(&mut self, player: usize, button: Button) -> bool {
+        match self.active.get_mut(player) {
+            Some(active) => {
+                active.remove(&button);
+                self.recalculate();
+                true
+            }
+            None => false,
+        }
     }
 }
 
@@ -96,9 +201,95 @@ impl MemDevice for Input {
     fn write(&mut self, a: Address, v: u8) -> Result<(), ExecutionError> {
         assert_eq!(a, REG_P1);
 
+        let previous = self.p1 & OUTPUT_MASK;
         self.p1 = v;
+        if self.sgb {
+            self.receive_sgb_pulse(previous, v & OUTPUT_MASK);
+        }
         self.recalculate();
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn send_sgb_packet(input: &mut Input, packet: &[u8; sgb::PACKET_SIZE]) {
+    input.write(REG_P1, SGB_PULSE_RESET).unwrap();
+    input.write(REG_P1, OUTPUT_MASK).unwrap();
+    for bit in 0..=SGB_PACKET_BITS {
+        let one = bit < SGB_PACKET_BITS && packet[bit / 8] & (1 << (bit % 8)) != 0;
+        let pulse = if one { SGB_PULSE_ONE } else { SGB_PULSE_ZERO };
+        input.write(REG_P1, pulse).unwrap();
+        input.write(REG_P1, OUTPUT_MASK).unwrap();
+    }
+}
+
+#[test]
+fn test_sgb_packets_and_mlt_req() {
+    let mut input = Input::new();
+    input.enable_sgb();
+
+    let mut packet = [0; sgb::PACKET_SIZE];
+    packet[0] = (sgb::CMD_MLT_REQ << 3) | 1;
+    packet[1] = 1;
+    packet[15] = 0x80;
+    send_sgb_packet(&mut input, &packet);
+
+    assert_eq!(input.take_sgb_command(), Some(packet.to_vec()));
+    assert_eq!(input.take_sgb_command(), None);
+
+    // Joypad IDs cycle on the rising edge of P15
+    assert_eq!(input.read(REG_P1).unwrap() & INPUT_MASK, 0xF);
+    input.write(REG_P1, P14).unwrap();
+    input.write(REG_P1, OUTPUT_MASK).unwrap();
+    assert_eq!(input.read(REG_P1).unwrap() & INPUT_MASK, 0xE);
+    input.write(REG_P1, P14).unwrap();
+    input.write(REG_P1, OUTPUT_MASK).unwrap();
+    assert_eq!(input.read(REG_P1).unwrap() & INPUT_MASK, 0xF);
+}
+
+#[test]
+fn test_multi_packet_sgb_command() {
+    let mut input = Input::new();
+    input.enable_sgb();
+
+    let mut packet = [0; sgb::PACKET_SIZE];
+    packet[0] = 2;
+    send_sgb_packet(&mut input, &packet);
+    assert_eq!(input.take_sgb_command(), None);
+
+    packet[0] = 0xAA;
+    send_sgb_packet(&mut input, &packet);
+    let command = input.take_sgb_command().unwrap();
+    assert_eq!(command.len(), 2 * sgb::PACKET_SIZE);
+    assert_eq!(command[sgb::PACKET_SIZE], 0xAA);
+}
+
+#[test]
+fn test_sgb_multiplayer_buttons() {
+    let mut input = Input::new();
+    input.enable_sgb();
+
+    let mut packet = [0; sgb::PACKET_SIZE];
+    packet[0] = (sgb::CMD_MLT_REQ << 3) | 1;
+    packet[1] = 3;
+    send_sgb_packet(&mut input, &packet);
+
+    assert!(input.activate_button(0, Button::A));
+    assert!(input.activate_button(2, Button::Start));
+    assert!(!input.activate_button(MAX_PLAYERS, Button::B));
+    assert!(!input.deactivate_button(MAX_PLAYERS, Button::B));
+
+    let read_buttons = |input: &mut Input| {
+        input.write(REG_P1, P14).unwrap();
+        let buttons = input.read(REG_P1).unwrap() & INPUT_MASK;
+        input.write(REG_P1, OUTPUT_MASK).unwrap();
+        buttons
+    };
+    assert_eq!(read_buttons(&mut input), INPUT_MASK & !P10);
+    assert_eq!(read_buttons(&mut input), INPUT_MASK);
+    assert_eq!(read_buttons(&mut input), INPUT_MASK & !P13);
+    assert_eq!(input.read(REG_P1).unwrap() & INPUT_MASK, 0xC);
+    assert_eq!(read_buttons(&mut input), INPUT_MASK);
+    assert_eq!(read_buttons(&mut input), INPUT_MASK & !P10);
+}
@@ -1,19 +1,26 @@
-use std::cmp::max;
+use std::fs::File;
+use std::io::{self, BufWriter};
 use std::num::Wrapping;
+use std::path::Path;
 
-use j2ds::{next_timer_event, Timer, TimerEvent};
 use log::error;
 
 use crate::error::ExecutionError;
 use crate::{
-    cpu::{Interrupt, InterruptSet, CLOCK_RATE},
+    cpu::{Interrupt, InterruptSet},
     mem::{Address, MemDevice, Ram, RNG_CHAR_DAT, RNG_LCD_BGDD1, RNG_LCD_BGDD2, RNG_LCD_OAM},
+    recorder::VideoRecorder,
+    sgb,
     system::SystemMode,
 };
 
 mod bg;
+pub mod blend;
+pub mod color;
+pub mod compat;
 pub mod fb;
 mod obj;
+pub mod palette;
 mod scanline;
 mod tile;
 
@@ -33,16 +40,18 @@ const REG_BCPS: Address = Address(0xFF68);
 const REG_BCPD: Address = Address(0xFF69);
 const REG_OCPS: Address = Address(0xFF6A);
 const REG_OCPD: Address = Address(0xFF6B);
+const REG_OPRI: Address = Address(0xFF6C);
 
 const BG_START_1: Address = Address(0x9800);
 const BG_START_2: Address = Address(0x9C00);
 
 const TOTAL_SCANLINES: u64 = 154;
-const LINE_CYCLE_TIME: u64 = CLOCK_RATE * 108_700 / 1_000_000_000; // Src: Official GB manual
-const HBLANK_DURATION: u64 = CLOCK_RATE * 48_600 / 1_000_000_000; // Src: GBCPUMan.pdf
-const MODE_10_DURATION: u64 = CLOCK_RATE * 19_000 / 1_000_000_000; // Src: GBCPUMan.pdf
-const VBLANK_DURATION: u64 = LINE_CYCLE_TIME * 10; // Src: Official GB manual
-const SCREEN_CYCLE_TIME: u64 = TOTAL_SCANLINES * LINE_CYCLE_TIME;
+const DOTS_PER_LINE: u64 = 456;
+const OAM_SCAN_DOTS: u64 = 80;
+const TRANSFER_MIN_DOTS: u64 = 172;
+const WINDOW_TRANSFER_PENALTY: u64 = 6;
+const OBJ_TRANSFER_PENALTY: u64 = 6;
+pub const SCREEN_CYCLE_TIME: u64 = TOTAL_SCANLINES * DOTS_PER_LINE;
 const BYTES_PER_CHAR: u16 = 16;
 const BYTES_PER_ROW: u16 = 2;
 const BG_CHARS_PER_ROW: u8 = 32;
@@ -50,13 +59,13 @@ const PIXEL_PER_CHAR: u8 = 8;
 
 const LYC_MATCH_INT_FLAG: u8 = 0b0100_0000;
 const MODE_10_INT_FLAG: u8 = 0b0010_0000;
-const _MODE_01_INT_FLAG: u8 = 0b0001_0000;
+const MODE_01_INT_FLAG: u8 = 0b0001_0000;
 const MODE_00_INT_FLAG: u8 = 0b0000_1000;
 
 const MODE_00_MASK: u8 = 0b00;
 const MODE_01_MASK: u8 = 0b01;
 const MODE_10_MASK: u8 = 0b10;
-const _MODE_11_MASK: u8 = 0b11;
+const MODE_11_MASK: u8 = 0b11;
 
 const LYC_MATCH_FLAG: u8 = 0b0000_0100;
 const BG_ENABLED_FLAG: u8 = 0b0000_0001;
@@ -70,25 +79,43 @@ const WINDOW_CODE_DAT_FLAG: u8 = 0b0100_0000;
 
 const TILE_COUNT: usize = 384 * 2;
 const OBJ_COUNT: usize = 40;
+const OBJS_PER_LINE: usize = 10;
+
+const WX_OFFSET: u8 = 7;
+// A WX of 166 doesn't show anything on its own line, but the window then spans all of the next one
+const WX_SPANS_NEXT_LINE: u8 = 166;
 
 const PAL_DATA_IDX: u8 = 0b11_1111;
 
+const SGB_TRANSFER_SIZE: usize = 4096;
+const SGB_TRANSFER_CHARS_PER_ROW: usize = 20;
+const OPRI_COORDINATE_FLAG: u8 = 0b1;
+
 pub const BG_SIZE: (usize, usize) = (255, 255);
 
 type CgbPalette = [fb::Pixel; 4];
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct WindowPlacement {
+    start_x: u8,
+    skip: u8,
+}
+
 pub struct Lcd {
     lcdc: u8,
-    stat: u8,
     bgp: u8,
     obp0: u8,
     obp1: u8,
     wx: u8,
     wy: u8,
+    window_y_triggered: bool,
+    window_line: u8,
+    window_spans_line: bool,
     sx: u8,
     sy: u8,
     bcps: u8,
     ocps: u8,
+    opri: u8,
     bank_select: usize,
     cdata: Ram,
     bgdd1: Ram,
@@ -100,19 +127,25 @@ pub struct Lcd {
 
     obj_palettes: [CgbPalette; 8],
     bg_palettes: [CgbPalette; 8],
+    color_correction: color::ColorCorrection,
+    dmg_palettes: palette::DmgPalettes,
+    dmg_compat: bool,
+    sgb: Option<sgb::Sgb>,
+    blender: Option<blend::FrameBlender>,
+    recorder: Option<VideoRecorder<BufWriter<File>>>,
 
     fbs: [fb::Framebuffer; 2],
     fbi: usize,
 
-    hblank_timer: Timer,
-    vblank_timer: Timer,
-    mode10_timer: Timer,
     scanline_sweeper: scanline::ScanlineSweeper,
 
     running_until_cycle: u64,
+    last_cycle: u64,
 
     tiles: [tile::MonoTile; TILE_COUNT],
     objs: [obj::Obj; OBJ_COUNT],
+    line_objs: [usize; OBJS_PER_LINE],
+    line_obj_count: usize,
 
     system_mode: SystemMode,
 }
@@ -121,16 +154,19 @@ impl Lcd {
     pub fn new(cgb_mode: bool) -> Lcd {
         Lcd {
             lcdc: 0x83,
-            stat: 0,
             bgp: 0,
             obp0: 0,
             obp1: 0,
             wx: 0,
             wy: 0,
+            window_y_triggered: false,
+            window_line: 0,
+            window_spans_line: false,
             sx: 0,
             sy: 0,
             bcps: 0,
             ocps: 0,
+            opri: 0,
             bank_select: 0,
             cdata: Ram::new(RNG_CHAR_DAT.len() * 2),
             bgdd1: Ram::new(RNG_LCD_BGDD1.len() * 2),
@@ -147,28 +183,22 @@ impl Lcd {
 
             obj_palettes: [[fb::DMG_COLOR_WHITE; 4]; 8],
             bg_palettes: [[fb::DMG_COLOR_WHITE; 4]; 8],
+            color_correction: color::ColorCorrection::default(),
+            dmg_palettes: palette::DmgPalettes::default(),
+            dmg_compat: false,
+            sgb: None,
+            blender: None,
+            recorder: None,
 
-            hblank_timer: Timer::new(
-                LINE_CYCLE_TIME,
-                LINE_CYCLE_TIME - HBLANK_DURATION - MODE_10_DURATION,
-                HBLANK_DURATION,
-            ),
-            vblank_timer: Timer::new(
-                SCREEN_CYCLE_TIME,
-                fb::SCREEN_SIZE.1 as u64 * LINE_CYCLE_TIME,
-                VBLANK_DURATION,
-            ),
-            mode10_timer: Timer::new(
-                LINE_CYCLE_TIME,
-                LINE_CYCLE_TIME - HBLANK_DURATION,
-                HBLANK_DURATION,
-            ),
             running_until_cycle: 0,
+            last_cycle: 0,
 
             scanline_sweeper: scanline::ScanlineSweeper::new(),
 
             tiles: [tile::MonoTile::default(); TILE_COUNT],
             objs: [obj::Obj::default(); OBJ_COUNT],
+            line_objs: [0; OBJS_PER_LINE],
+            line_obj_count: 0,
 
             system_mode: if cgb_mode {
                 SystemMode::CGB
@@ -179,6 +209,14 @@ impl Lcd {
     }
 
     pub fn get_framebuffer(&self) -> &fb::Framebuffer {
+        match self.blender.as_ref() {
+            Some(blender) => blender.get_framebuffer(),
+            None => &self.fbs[self.fbi],
+        }
+    }
+
+    /// The last frame exactly as it was drawn, even with frame blending on
+    pub fn get_raw_framebuffer(&self) -> &fb::Framebuffer {
         &self.fbs[self.fbi]
     }
 
@@ -196,15 +234,129 @@ impl Lcd {
         } else {
             self.fbi = 0;
         }
+
+        if let Some(blender) = self.blender.as_mut() {
+            blender.push(&self.fbs[self.fbi]);
+        }
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.push_frame(self.last_cycle, &self.fbs[self.fbi]) {
+                error!("Stopped recording video: {}", e);
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Records every frame from now on, unblended, to a Y4M file
+    pub fn start_recording<P: AsRef<Path>>(&mut self, path: P, cycle: u64) -> io::Result<()> {
+        self.recorder = Some(VideoRecorder::create(path, &self.fbs[self.fbi], cycle)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    /// Blends each new frame into the previous ones, or shows them as they
+    /// are if `None`
+    pub fn set_frame_blend(&mut self, response: Option<blend::LcdResponse>) {
+        match (response, self.blender.as_mut()) {
+            (Some(response), Some(blender)) => blender.set_response(response),
+            (Some(response), None) => {
+                self.blender = Some(blend::FrameBlender::new(response, &self.fbs[self.fbi]))
+            }
+            (None, _) => self.blender = None,
+        }
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: color::ColorCorrection) {
+        self.color_correction = color_correction;
+        load_color_from_data(&self.bcp, &mut self.bg_palettes, color_correction);
+        load_color_from_data(&self.ocp, &mut self.obj_palettes, color_correction);
+    }
+
+    pub fn set_dmg_palettes(&mut self, dmg_palettes: palette::DmgPalettes) {
+        self.dmg_palettes = dmg_palettes;
+    }
+
+    /// Switches to the CGB's DMG compatibility mode, where a DMG-only cart's
+    /// shades are looked up in CGB palette RAM instead of the DMG palettes.
+    ///
+    /// The CGB's PPU gets DMG-style attributes in this mode: every BG
+    /// attribute is 0, objects pick OBJ palette 0 or 1 with the DMG palette
+    /// bit, BGP and OBPx still map colors to shades, LCDC bit 0 keeps its DMG
+    /// meaning and the boot ROM sets OPRI to coordinate priority. That's just
+    /// what the DMG pipeline does, so only the colors and the CGB's own
+    /// hardware quirks need to change.
+    pub fn set_compat_palettes(&mut self, palettes: &compat::CompatPalettes) {
+        self.dmg_compat = true;
+        compat::write_palette_ram(palettes, &mut self.bcp, &mut self.ocp);
+        load_color_from_data(&self.bcp, &mut self.bg_palettes, self.color_correction);
+        load_color_from_data(&self.ocp, &mut self.obj_palettes, self.color_correction);
+    }
+
+    pub fn is_dmg_compat(&self) -> bool {
+        self.dmg_compat
+    }
+
+    /// Colorizes the DMG shades with the Super Game Boy's palettes. Only DMG
+    /// mode can be colorized, returns whether it was enabled.
+    pub fn enable_sgb(&mut self) -> bool {
+        if self.is_cgb_hardware() {
+            return false;
+        }
+        self.sgb = Some(sgb::Sgb::new());
+        true
+    }
+
+    fn is_cgb_hardware(&self) -> bool {
+        matches!(self.system_mode, SystemMode::CGB) || self.dmg_compat
+    }
+
+    pub fn get_sgb_framebuffer(&self) -> Option<&fb::Framebuffer> {
+        self.sgb.as_ref().map(|sgb| sgb.get_framebuffer())
+    }
+
+    pub fn handle_sgb_command(&mut self, command: &[u8]) {
+        if let Some(sgb) = self.sgb.as_mut() {
+            sgb.handle_command(command);
+        }
+    }
+
+    fn dmg_bg_color(&self, x: usize, y: usize, shade: usize) -> fb::Pixel {
+        match self.sgb.as_ref() {
+            Some(sgb) => sgb.color(x, y, shade),
+            None => self.dmg_bg_colors()[shade],
+        }
+    }
+
+    // The SGB colorizes the final shades, so objects share the BG's palettes
+    fn dmg_obj_color(&self, high_palette: bool, x: usize, y: usize, shade: usize) -> fb::Pixel {
+        match self.sgb.as_ref() {
+            Some(sgb) => sgb.color(x, y, shade),
+            None => self.dmg_obj_colors(high_palette)[shade],
+        }
+    }
+
+    fn dmg_bg_colors(&self) -> &[fb::Pixel; 4] {
+        if self.dmg_compat {
+            &self.bg_palettes[0]
+        } else {
+            &self.dmg_palettes.bg
+        }
+    }
+
+    fn dmg_obj_colors(&self, high_palette: bool) -> &[fb::Pixel; 4] {
+        match (self.dmg_compat, high_palette) {
+            (true, false) => &self.obj_palettes[0],
+            (true, true) => &self.obj_palettes[1],
+            (false, false) => &self.dmg_palettes.obj0,
+            (false, true) => &self.dmg_palettes.obj1,
+        }
     }
 
     pub fn get_next_event_cycle(&self) -> u64 {
-        next_timer_event(&[
-            self.hblank_timer,
-            self.vblank_timer,
-            self.mode10_timer,
-            self.scanline_sweeper.timer(),
-        ])
+        self.scanline_sweeper.next_event_cycle()
     }
 
     pub fn set_running_until(&mut self, cycle: u64) {
@@ -213,93 +365,140 @@ impl Lcd {
 
     pub fn pump_cycle(&mut self, cycle: u64) -> InterruptSet {
         let mut inters = InterruptSet::default();
-
-        let scanline_inter = self.scanline_sweeper.pump_cycle(cycle);
-        self.stat = (self.stat & !LYC_MATCH_FLAG) | self.scanline_sweeper.stat_flags();
-        if let Some(intr) = scanline_inter {
-            inters.add_interrupt(intr);
-        }
-
-        match self.hblank_timer.update(cycle) {
-            Some(TimerEvent::RisingEdge) => {
-                if self.scanline_sweeper.on_visible_scanline() {
+        self.last_cycle = cycle;
+
+        while let Some(event) = self.scanline_sweeper.step(cycle) {
+            match event {
+                scanline::SweepEvent::Transfer => {
+                    self.latch_window_y();
+                    self.scan_oam();
+                    let dots = self.transfer_dots();
+                    self.scanline_sweeper.set_transfer_length(dots);
+                }
+                scanline::SweepEvent::HBlank => {
                     self.do_hblank_start(cycle);
-                    if self.is_hblank_int_enabled() {
-                        inters.add_interrupt(Interrupt::LCDC);
-                    }
                 }
-            }
-            Some(TimerEvent::FallingEdge) => {
-                self.do_hblank_end();
-            }
-            None => {}
-        }
-
-        match self.mode10_timer.update(cycle) {
-            Some(TimerEvent::RisingEdge) => {
-                self.stat = (self.stat & 0b1111_1100) | MODE_10_MASK;
-
-                if self.is_mode_10_int_enabled() {
-                    inters.add_interrupt(Interrupt::LCDC);
+                scanline::SweepEvent::VBlank => {
+                    self.do_vblank_start();
+                    inters.add_interrupt(Interrupt::VBlank);
                 }
+                scanline::SweepEvent::LineStart | scanline::SweepEvent::LycCompare => {}
             }
-            Some(TimerEvent::FallingEdge) => {
-                self.stat = (self.stat & 0b1111_1100) | MODE_00_MASK;
-            }
-            None => {}
         }
 
-        match self.vblank_timer.update(cycle) {
-            Some(TimerEvent::RisingEdge) => {
-                self.do_vblank_start();
-                inters.add_interrupt(Interrupt::VBlank);
-            }
-            Some(TimerEvent::FallingEdge) => {
-                self.do_vblank_end();
-            }
-            None => {}
+        if self.scanline_sweeper.take_stat_interrupt() {
+            inters.add_interrupt(Interrupt::LCDC);
         }
 
         inters
     }
 
+    // Mode 3 is stretched by the fine scroll and by fetching the window and objects
+    fn transfer_dots(&self) -> u64 {
+        let mut dots = TRANSFER_MIN_DOTS + u64::from(self.sx % PIXEL_PER_CHAR);
+        if self.window_placement().is_some() {
+            dots += WINDOW_TRANSFER_PENALTY;
+        }
+        if self.is_oam_enabled() {
+            dots += OBJ_TRANSFER_PENALTY * self.line_obj_count as u64;
+        }
+        dots
+    }
+
     fn do_hblank_start(&mut self, cycle: u64) {
+        let window = self.window_placement();
         if self.should_render_this_frame(cycle) {
-            self.render_screen_row();
+            self.render_screen_row(window);
         }
-        self.stat = (self.stat & 0b1111_1100) | MODE_00_MASK;
+        self.advance_window_line(window);
     }
 
     fn should_render_this_frame(&self, cycle: u64) -> bool {
-        cycle >= self.running_until_cycle
+        self.recorder.is_some()
+            || cycle >= self.running_until_cycle
             || self.running_until_cycle - cycle <= 2 * SCREEN_CYCLE_TIME
     }
 
-    fn do_hblank_end(&mut self) {}
-
     pub fn do_vblank_start(&mut self) {
-        self.swap();
-        self.stat = (self.stat & 0b1111_1100) | MODE_01_MASK;
+        if let Some(transfer) = self.sgb.as_mut().and_then(|sgb| sgb.take_transfer()) {
+            let data = self.read_sgb_transfer();
+            if let Some(sgb) = self.sgb.as_mut() {
+                sgb.complete_transfer(transfer, &data);
+            }
+        }
+
+        match self.sgb.as_ref().map_or(sgb::Mask::None, |sgb| sgb.mask()) {
+            sgb::Mask::None => self.swap(),
+            sgb::Mask::Freeze => {}
+            sgb::Mask::Black => self.fill_screen([0, 0, 0]),
+            sgb::Mask::Color0 => self.fill_screen(self.dmg_bg_color(0, 0, 0)),
+        }
+        if let Some(sgb) = self.sgb.as_mut() {
+            let screen = match self.blender.as_ref() {
+                Some(blender) => blender.get_framebuffer(),
+                None => &self.fbs[self.fbi],
+            };
+            sgb.compose(screen);
+        }
+        self.reset_window();
     }
 
-    pub fn do_vblank_end(&mut self) {
-        self.stat = (self.stat & 0b1111_1100) | MODE_00_MASK;
+    fn blank_screen(&mut self) {
+        self.fill_screen(self.dmg_bg_color(0, 0, 0));
     }
 
-    fn render_screen_row(&mut self) {
-        let y = self.scanline_sweeper.ly() as usize;
-        if !self.is_lcd_enabled() {
-            for x in 0..(fb::SCREEN_SIZE.0 as usize) {
-                self.get_back_framebuffer().set(x, y, fb::DMG_COLOR_WHITE);
+    fn fill_screen(&mut self, color: fb::Pixel) {
+        for y in 0..fb::SCREEN_SIZE.1 {
+            for x in 0..fb::SCREEN_SIZE.0 {
+                self.get_back_framebuffer().set(x, y, color);
+            }
+        }
+        self.swap();
+    }
+
+    // The SGB reads transferred data from the screen: the first 256 chars of
+    // the BG map, 20 per row, with the data of each char taken in order
+    fn read_sgb_transfer(&self) -> Vec<u8> {
+        let code_dat_start = self.get_bg_code_dat_start();
+        let signed = self.get_bg_char_addr_start();
+        let mut data = Vec::with_capacity(SGB_TRANSFER_SIZE);
+        for i in 0..(SGB_TRANSFER_SIZE / BYTES_PER_CHAR as usize) {
+            let row = i / SGB_TRANSFER_CHARS_PER_ROW;
+            let col = i % SGB_TRANSFER_CHARS_PER_ROW;
+            let code_offset = Address((row * usize::from(BG_CHARS_PER_ROW) + col) as u16);
+            let char_ = if code_dat_start == RNG_LCD_BGDD1.0 {
+                self.bgdd1.read(code_offset).unwrap()
+            } else {
+                self.bgdd2.read(code_offset).unwrap()
+            };
+
+            let char_start = if signed {
+                (0x1000 + isize::from(char_ as i8) * BYTES_PER_CHAR as isize) as u16
+            } else {
+                u16::from(char_) * BYTES_PER_CHAR
+            };
+            for b in 0..BYTES_PER_CHAR {
+                data.push(self.cdata.read(Address(char_start + b)).unwrap());
             }
-            return;
         }
+        data
+    }
+
+    fn render_screen_row(&mut self, window: Option<WindowPlacement>) {
+        let y = self.scanline_sweeper.ly() as usize;
 
         let mut bg_screen_row =
-            [fb::TentativePixel::new(fb::DMG_COLOR_WHITE, false, true); fb::SCREEN_SIZE.0];
+            [fb::TentativePixel::new(self.dmg_bg_colors()[0], false, true); fb::SCREEN_SIZE.0];
+        if self.sgb.is_some() {
+            for (x, pixel) in bg_screen_row.iter_mut().enumerate() {
+                *pixel = fb::TentativePixel::new(self.dmg_bg_color(x, y, 0), false, true);
+            }
+        }
         let mut oam_screen_row = [None; fb::SCREEN_SIZE.0];
         self.render_background_row(&mut bg_screen_row);
-        self.render_window_row(&mut bg_screen_row);
+        if let Some(window) = window {
+            self.render_window_row(window, &mut bg_screen_row);
+        }
         self.render_oam_row(&mut oam_screen_row);
 
         for x in 0..(fb::SCREEN_SIZE.0 as usize) {
@@ -323,23 +522,71 @@ impl Lcd {
         );
     }
 
-    fn render_window_row(&self, screen_row: &mut [fb::TentativePixel]) {
-        if !self.is_window_enabled() {
-            return;
+    fn reset_window(&mut self) {
+        self.window_y_triggered = false;
+        self.window_line = 0;
+        self.window_spans_line = false;
+    }
+
+    // Once LY has matched WY the window stays triggered for the rest of the frame,
+    // even if WY is changed afterwards
+    fn latch_window_y(&mut self) {
+        if self.scanline_sweeper.ly() == self.wy {
+            self.window_y_triggered = true;
         }
+    }
 
-        let adjusted_wx = max(self.wx, 7) - 7;
-        let ly = self.scanline_sweeper.ly();
-        if self.wy > ly || adjusted_wx >= fb::SCREEN_SIZE.0 as u8 {
-            return;
+    fn window_placement(&self) -> Option<WindowPlacement> {
+        if !self.is_window_enabled() || !self.window_y_triggered {
+            return None;
         }
 
-        let translated_y = ly - self.wy;
+        if self.window_spans_line {
+            return Some(WindowPlacement {
+                start_x: 0,
+                skip: 0,
+            });
+        }
+
+        match self.wx {
+            // The DMG's window stutters with the fine scroll when WX is 0
+            0 => Some(WindowPlacement {
+                start_x: 0,
+                skip: if self.is_cgb_hardware() {
+                    WX_OFFSET
+                } else {
+                    WX_OFFSET + self.sx % PIXEL_PER_CHAR
+                },
+            }),
+            WX_SPANS_NEXT_LINE => None,
+            wx if wx < WX_OFFSET => Some(WindowPlacement {
+                start_x: 0,
+                skip: WX_OFFSET - wx,
+            }),
+            wx if ((wx - WX_OFFSET) as usize) < fb::SCREEN_SIZE.0 => Some(WindowPlacement {
+                start_x: wx - WX_OFFSET,
+                skip: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    // The window keeps its own line counter, which only moves on lines where
+    // the window was actually drawn
+    fn advance_window_line(&mut self, window: Option<WindowPlacement>) {
+        if window.is_some() {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+        self.window_spans_line =
+            self.is_window_enabled() && self.window_y_triggered && self.wx == WX_SPANS_NEXT_LINE;
+    }
+
+    fn render_window_row(&self, window: WindowPlacement, screen_row: &mut [fb::TentativePixel]) {
         self.render_tile_row(
-            translated_y,
+            self.window_line,
+            window.skip,
             0,
-            0,
-            if self.wx > 7 { self.wx - 7 } else { 0 },
+            window.start_x,
             self.get_window_code_dat_start(),
             screen_row,
         );
@@ -406,7 +653,10 @@ impl Lcd {
                 SystemMode::DMG => {
                     let color_index = char_row[(translated_x % Wrapping(8)).0 as usize];
                     let corrected_index = palette_convert(color_index, self.bgp) as usize;
-                    (fb::DMG_COLORS[corrected_index], color_index)
+                    (
+                        self.dmg_bg_color(screen_x, screen_y as usize, corrected_index),
+                        color_index,
+                    )
                 }
             };
 
@@ -481,14 +731,6 @@ impl Lcd {
         self.lcdc & LCD_ENABLED_FLAG != 0
     }
 
-    fn is_hblank_int_enabled(&self) -> bool {
-        self.stat & MODE_00_INT_FLAG != 0
-    }
-
-    fn is_mode_10_int_enabled(&self) -> bool {
-        self.stat & MODE_10_INT_FLAG != 0
-    }
-
     fn get_bg_char_addr_start(&self) -> bool {
         self.lcdc & BGD_CHAR_DAT_FLAG == 0
     }
@@ -521,65 +763,106 @@ impl Lcd {
         )
     }
 
+    fn obj_height(&self) -> u8 {
+        if self.lcdc & OAM_TALL_FLAG != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn uses_coordinate_priority(&self) -> bool {
+        match self.system_mode {
+            SystemMode::DMG => true,
+            SystemMode::CGB => self.opri & OPRI_COORDINATE_FLAG != 0,
+        }
+    }
+
+    // Mimics the OAM scan in mode 2: the first 10 objects in OAM order that
+    // overlap this line are selected, regardless of their X position. They're
+    // then stored from highest to lowest drawing priority.
+    fn scan_oam(&mut self) {
+        let ly = isize::from(self.scanline_sweeper.ly());
+        let height = isize::from(self.obj_height());
+
+        self.line_obj_count = 0;
+        for (i, obj) in self.objs.iter().enumerate() {
+            let top = isize::from(obj.y) - 16;
+            if ly >= top && ly < top + height {
+                self.line_objs[self.line_obj_count] = i;
+                self.line_obj_count += 1;
+                if self.line_obj_count == OBJS_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        if self.uses_coordinate_priority() {
+            // Stable, so objects at the same X fall back to OAM order
+            let objs = &self.objs;
+            self.line_objs[..self.line_obj_count].sort_by_key(|i| objs[*i].x);
+        }
+    }
+
     fn render_oam_row(&self, screen_row: &mut [Option<fb::TentativePixel>]) {
         if !self.is_oam_enabled() {
             return;
         }
 
-        for i in 0..OBJ_COUNT {
-            let obj = self.objs[OBJ_COUNT - i - 1];
+        let hi_y = self.obj_height();
+        let ly = isize::from(self.scanline_sweeper.ly());
+
+        // Lowest priority first, so higher priority objects draw over them
+        for i in self.line_objs[..self.line_obj_count].iter().rev() {
+            let obj = self.objs[*i];
 
-            let (char_, hi_y) = if self.lcdc & OAM_TALL_FLAG != 0 {
-                (obj.char_ & 0b1111_1110, 16)
+            let char_ = if hi_y == 16 {
+                obj.char_ & 0b1111_1110
             } else {
-                (obj.char_, 8)
+                obj.char_
             };
 
-            for y in 0..hi_y {
-                let full_y = y as isize + obj.y as isize - 16;
-                if full_y > fb::SCREEN_SIZE.1 as isize
-                    || full_y < 0
-                    || full_y != self.scanline_sweeper.ly() as isize
-                {
+            let y = (ly - (isize::from(obj.y) - 16)) as u8;
+            let index_y = if obj.yflip() { hi_y - 1 - y } else { y };
+            let row = self.read_char_row_at(char_, index_y, false, obj.bank());
+            for x in 0..8 {
+                let full_x = x as isize + obj.x as isize - 8;
+
+                if full_x >= fb::SCREEN_SIZE.0 as isize || full_x < 0 {
                     continue;
                 }
 
-                let index_y = if obj.yflip() { hi_y - 1 - y } else { y };
-                let row = self.read_char_row_at(char_, index_y, false, obj.bank());
-                for x in 0..8 {
-                    let full_x = x as isize + obj.x as isize - 8;
-
-                    if full_x >= fb::SCREEN_SIZE.0 as isize || full_x < 0 {
-                        continue;
+                let index_x = if obj.xflip() { 7 - x } else { x };
+                let color_index = row[index_x as usize];
+                if color_index == 0 {
+                    // 0 is always transparent
+                    continue;
+                }
+                let color = match self.system_mode {
+                    SystemMode::CGB => {
+                        self.obj_palettes[obj.cgb_palette() as usize][color_index as usize]
                     }
-
-                    let index_x = if obj.xflip() { 7 - x } else { x };
-                    let color_index = row[index_x as usize];
-                    if color_index == 0 {
-                        // 0 is always transparent
-                        continue;
+                    SystemMode::DMG => {
+                        let pal = if obj.high_palette() {
+                            self.obp1
+                        } else {
+                            self.obp0
+                        };
+                        let corrected_index = palette_convert(color_index, pal) as usize;
+                        self.dmg_obj_color(
+                            obj.high_palette(),
+                            full_x as usize,
+                            ly as usize,
+                            corrected_index,
+                        )
                     }
-                    let color = match self.system_mode {
-                        SystemMode::CGB => {
-                            self.obj_palettes[obj.cgb_palette() as usize][color_index as usize]
-                        }
-                        SystemMode::DMG => {
-                            let pal = if obj.high_palette() {
-                                self.obp1
-                            } else {
-                                self.obp0
-                            };
-                            let corrected_index = palette_convert(color_index, pal) as usize;
-                            fb::DMG_COLORS[corrected_index]
-                        }
-                    };
+                };
 
-                    screen_row[full_x as usize] = Some(fb::TentativePixel::new(
-                        color,
-                        !obj.priority(),
-                        color_index == 0,
-                    ));
-                }
+                screen_row[full_x as usize] = Some(fb::TentativePixel::new(
+                    color,
+                    !obj.priority(),
+                    color_index == 0,
+                ));
             }
         }
     }
@@ -601,22 +884,22 @@ impl Lcd {
     }
 }
 
-fn load_color_from_data(data: &[u8], pal_out: &mut [CgbPalette]) {
+fn load_color_from_data(
+    data: &[u8],
+    pal_out: &mut [CgbPalette],
+    color_correction: color::ColorCorrection,
+) {
     let mut i = 0;
     for pal in 0..8 {
         for color_index in 0..4 {
             let low = data[i];
             let hi = data[i + 1];
 
-            let r = u16::from(low & 0b0001_1111);
-            let g = u16::from((low >> 5) | ((hi & 0b11) << 3));
-            let b = u16::from((hi >> 2) & 0b0001_1111);
-
-            let ar = (r * 255 / 0x1F) as u8;
-            let ag = (g * 255 / 0x1F) as u8;
-            let ab = (b * 255 / 0x1F) as u8;
+            let r = low & 0b0001_1111;
+            let g = (low >> 5) | ((hi & 0b11) << 3);
+            let b = (hi >> 2) & 0b0001_1111;
 
-            pal_out[pal as usize][color_index as usize] = [ar, ag, ab];
+            pal_out[pal as usize][color_index as usize] = color_correction.convert(r, g, b);
 
             i += 2;
         }
@@ -634,6 +917,116 @@ fn test_palette_convert() {
     assert_eq!(0b01, palette_convert(1, 0b0100));
 }
 
+#[test]
+fn test_window_line_counter() {
+    let mut lcd = Lcd::new(false);
+    let lcdc = LCD_ENABLED_FLAG | BG_ENABLED_FLAG;
+    lcd.write(REG_WX, WX_OFFSET).unwrap();
+    lcd.write(REG_WY, 0).unwrap();
+    lcd.write(REG_LCDC, lcdc | WINDOW_ENABLED_FLAG).unwrap();
+
+    lcd.pump_cycle(DOTS_PER_LINE * 10);
+    assert_eq!(lcd.window_line, 10);
+
+    // Disabling the window mid-frame pauses the counter rather than skipping rows
+    lcd.write(REG_LCDC, lcdc).unwrap();
+    lcd.pump_cycle(DOTS_PER_LINE * 20);
+    assert_eq!(lcd.window_line, 10);
+
+    lcd.write(REG_LCDC, lcdc | WINDOW_ENABLED_FLAG).unwrap();
+    lcd.pump_cycle(DOTS_PER_LINE * 21);
+    assert_eq!(lcd.window_line, 11);
+
+    // WY was already latched for this frame
+    lcd.write(REG_WY, 100).unwrap();
+    lcd.pump_cycle(DOTS_PER_LINE * 22);
+    assert_eq!(lcd.window_line, 12);
+
+    lcd.pump_cycle(DOTS_PER_LINE * (TOTAL_SCANLINES + 100));
+    assert_eq!(lcd.window_line, 0);
+    lcd.pump_cycle(DOTS_PER_LINE * (TOTAL_SCANLINES + 101));
+    assert_eq!(lcd.window_line, 1);
+}
+
+#[test]
+fn test_window_wx_quirks() {
+    let mut lcd = Lcd::new(false);
+    lcd.window_y_triggered = true;
+    lcd.write(REG_LCDC, LCD_ENABLED_FLAG | WINDOW_ENABLED_FLAG)
+        .unwrap();
+
+    lcd.write(REG_WX, 3).unwrap();
+    assert_eq!(
+        lcd.window_placement(),
+        Some(WindowPlacement {
+            start_x: 0,
+            skip: 4
+        })
+    );
+
+    lcd.write(REG_SCX, 2).unwrap();
+    lcd.write(REG_WX, 0).unwrap();
+    assert_eq!(
+        lcd.window_placement(),
+        Some(WindowPlacement {
+            start_x: 0,
+            skip: 9
+        })
+    );
+    // The CGB doesn't stutter, even for DMG-only carts
+    lcd.set_compat_palettes(&compat::CompatPalette::Up.palettes());
+    assert_eq!(
+        lcd.window_placement(),
+        Some(WindowPlacement {
+            start_x: 0,
+            skip: 7
+        })
+    );
+
+    lcd.write(REG_WX, WX_SPANS_NEXT_LINE).unwrap();
+    assert_eq!(lcd.window_placement(), None);
+    lcd.advance_window_line(None);
+    assert_eq!(
+        lcd.window_placement(),
+        Some(WindowPlacement {
+            start_x: 0,
+            skip: 0
+        })
+    );
+}
+
+#[cfg(test)]
+fn write_test_obj(lcd: &mut Lcd, index: u16, y: u8, x: u8) {
+    let a = RNG_LCD_OAM.0 + Address(index * 4);
+    lcd.write(a, y).unwrap();
+    lcd.write(a + Address(1), x).unwrap();
+}
+
+#[test]
+fn test_oam_scan_limit_and_priority() {
+    let mut lcd = Lcd::new(false);
+
+    // Not on line 0, so it doesn't count towards the limit
+    write_test_obj(&mut lcd, 0, 40, 8);
+    for i in 1..13 {
+        write_test_obj(&mut lcd, i, 16, 100 - i as u8);
+    }
+    write_test_obj(&mut lcd, 2, 16, 50);
+    write_test_obj(&mut lcd, 3, 16, 50);
+
+    lcd.scan_oam();
+    assert_eq!(lcd.line_obj_count, OBJS_PER_LINE);
+    assert_eq!(lcd.line_objs, [2, 3, 10, 9, 8, 7, 6, 5, 4, 1]);
+
+    lcd.system_mode = SystemMode::CGB;
+    lcd.scan_oam();
+    assert_eq!(lcd.line_objs, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+    lcd.write(REG_OPRI, OPRI_COORDINATE_FLAG).unwrap();
+    lcd.scan_oam();
+    assert_eq!(lcd.line_objs, [2, 3, 10, 9, 8, 7, 6, 5, 4, 1]);
+}
+
 impl MemDevice for Lcd {
     fn read(&self, a: Address) -> Result<u8, ExecutionError> {
         if a.in_(RNG_LCD_BGDD1) {
@@ -653,7 +1046,7 @@ impl MemDevice for Lcd {
             match a {
                 REG_LY => Ok(self.scanline_sweeper.ly()),
                 REG_LYC => Ok(self.scanline_sweeper.lyc()),
-                REG_STAT => Ok(self.stat),
+                REG_STAT => Ok(self.scanline_sweeper.stat()),
                 REG_LCDC => Ok(self.lcdc),
                 REG_OBP0 => Ok(self.obp0),
                 REG_OBP1 => Ok(self.obp1),
@@ -666,6 +1059,7 @@ impl MemDevice for Lcd {
                 REG_BCPD => Ok(self.bcp[(self.bcps & PAL_DATA_IDX) as usize]),
                 REG_OCPS => Ok(self.ocps),
                 REG_OCPD => Ok(self.ocp[(self.ocps & PAL_DATA_IDX) as usize]),
+                REG_OPRI => Ok(self.opri | !OPRI_COORDINATE_FLAG),
                 REG_BGP => {
                     error!("Error: BGP is a write-only register");
                     Err(ExecutionError::BusError)
@@ -705,11 +1099,18 @@ impl MemDevice for Lcd {
                     Ok(())
                 }
                 REG_LCDC => {
+                    let was_enabled = self.is_lcd_enabled();
                     self.lcdc = v;
+                    if was_enabled && !self.is_lcd_enabled() {
+                        self.scanline_sweeper.disable();
+                        self.blank_screen();
+                    } else if !was_enabled && self.is_lcd_enabled() {
+                        self.reset_window();
+                        self.scanline_sweeper.enable(self.last_cycle);
+                    }
                     Ok(())
                 }
                 REG_STAT => {
-                    self.stat = v & 0b1111_1100;
                     self.scanline_sweeper.update_stat(v);
                     Ok(())
                 }
@@ -759,7 +1160,7 @@ impl MemDevice for Lcd {
                         }
                         self.bcps = (self.bcps & !PAL_DATA_IDX) | (idx as u8);
                     }
-                    load_color_from_data(&self.bcp, &mut self.bg_palettes);
+                    load_color_from_data(&self.bcp, &mut self.bg_palettes, self.color_correction);
                     Ok(())
                 }
                 REG_OCPS => {
@@ -776,7 +1177,11 @@ impl MemDevice for Lcd {
                         }
                         self.ocps = (self.ocps & !PAL_DATA_IDX) | (idx as u8);
                     }
-                    load_color_from_data(&self.ocp, &mut self.obj_palettes);
+                    load_color_from_data(&self.ocp, &mut self.obj_palettes, self.color_correction);
+                    Ok(())
+                }
+                REG_OPRI => {
+                    self.opri = v & OPRI_COORDINATE_FLAG;
                     Ok(())
                 }
                 _ => {
@@ -787,3 +1192,13 @@ impl MemDevice for Lcd {
         }
     }
 }
+
+#[test]
+fn test_raw_framebuffer_skips_blending() {
+    let mut lcd = Lcd::new(false);
+    lcd.set_frame_blend(Some(blend::LcdResponse::BLEND));
+    lcd.get_back_framebuffer().set(0, 0, [0, 0, 0]);
+    lcd.swap();
+    assert_eq!(lcd.get_raw_framebuffer().get(0, 0), [0, 0, 0]);
+    assert_ne!(lcd.get_framebuffer().get(0, 0), [0, 0, 0]);
+}
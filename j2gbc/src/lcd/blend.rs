@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use super::fb::{Framebuffer, Pixel, SCREEN_SIZE};
+
+/// How fast the LCD's pixels follow the emulated frames: each frame a channel
+/// covers this fraction of the distance to its new value, separately for
+/// getting brighter and darker. 1 disables blending entirely.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LcdResponse {
+    pub rise: f32,
+    pub fall: f32,
+}
+
+impl LcdResponse {
+    /// An even mix of the last two frames, enough to merge flickering sprites
+    pub const BLEND: LcdResponse = LcdResponse {
+        rise: 0.5,
+        fall: 0.5,
+    };
+    /// The heavy smearing of the original DMG screen
+    pub const DMG: LcdResponse = LcdResponse {
+        rise: 0.3,
+        fall: 0.4,
+    };
+    /// The faster CGB screen, which still leaves a short trail
+    pub const CGB: LcdResponse = LcdResponse {
+        rise: 0.6,
+        fall: 0.7,
+    };
+}
+
+impl FromStr for LcdResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blend" => Ok(LcdResponse::BLEND),
+            "dmg" => Ok(LcdResponse::DMG),
+            "cgb" => Ok(LcdResponse::CGB),
+            _ => Err(format!("Unknown frame blend mode {}", s)),
+        }
+    }
+}
+
+pub struct FrameBlender {
+    response: LcdResponse,
+    // Kept at full precision, otherwise channels stop short of their target
+    // once the remaining steps round down to 0
+    state: Vec<[f32; 3]>,
+    output: Framebuffer,
+}
+
+impl FrameBlender {
+    pub fn new(response: LcdResponse, first: &Framebuffer) -> FrameBlender {
+        FrameBlender {
+            response,
+            state: first
+                .raw()
+                .iter()
+                .map(|p| [f32::from(p[0]), f32::from(p[1]), f32::from(p[2])])
+                .collect(),
+            output: first.clone(),
+        }
+    }
+
+    pub fn set_response(&mut self, response: LcdResponse) {
+        self.response = response;
+    }
+
+    pub fn get_framebuffer(&self) -> &Framebuffer {
+        &self.output
+    }
+
+    pub fn push(&mut self, frame: &Framebuffer) {
+        for (i, (state, target)) in self.state.iter_mut().zip(frame.raw()).enumerate() {
+            let mut pixel: Pixel = [0; 3];
+            for c in 0..3 {
+                let target = f32::from(target[c]);
+                let rate = if target > state[c] {
+                    self.response.rise
+                } else {
+                    self.response.fall
+                };
+                state[c] += (target - state[c]) * rate;
+                pixel[c] = state[c].round() as u8;
+            }
+            self.output.set(i % SCREEN_SIZE.0, i / SCREEN_SIZE.0, pixel);
+        }
+    }
+}
+
+#[test]
+fn test_blend_flicker() {
+    let mut black = Framebuffer::new(SCREEN_SIZE);
+    let mut white = Framebuffer::new(SCREEN_SIZE);
+    black.set(0, 0, [0, 0, 0]);
+    white.set(0, 0, [255, 255, 255]);
+
+    let mut blender = FrameBlender::new(LcdResponse::BLEND, &black);
+    blender.push(&white);
+    assert_eq!(blender.get_framebuffer().get(0, 0), [128, 128, 128]);
+    blender.push(&black);
+    assert_eq!(blender.get_framebuffer().get(0, 0), [64, 64, 64]);
+}
+
+#[test]
+fn test_blend_settles() {
+    let mut black = Framebuffer::new(SCREEN_SIZE);
+    let mut white = Framebuffer::new(SCREEN_SIZE);
+    black.set(3, 7, [0, 0, 0]);
+    white.set(3, 7, [255, 255, 255]);
+
+    let mut blender = FrameBlender::new(LcdResponse::DMG, &black);
+    for _ in 0..60 {
+        blender.push(&white);
+    }
+    assert_eq!(blender.get_framebuffer().get(3, 7), [255, 255, 255]);
+}
@@ -0,0 +1,110 @@
+use std::str::FromStr;
+
+use super::fb::Pixel;
+
+const MAX_CHANNEL: u16 = 0x1F;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum ColorCorrection {
+    /// Scale each 5-bit channel straight up to 8 bits
+    #[default]
+    Raw,
+    /// Approximates the color mixing and limited brightness of the CGB's LCD
+    Accurate,
+    /// The darker, more saturated response of a GBA screen running CGB games
+    Gba,
+    /// `Accurate`, but compressed towards the middle to tone down harsh palettes
+    ReducedContrast,
+}
+
+impl FromStr for ColorCorrection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ColorCorrection::Raw),
+            "accurate" => Ok(ColorCorrection::Accurate),
+            "gba" => Ok(ColorCorrection::Gba),
+            "reduced-contrast" => Ok(ColorCorrection::ReducedContrast),
+            _ => Err(format!("Unknown color correction mode {}", s)),
+        }
+    }
+}
+
+impl ColorCorrection {
+    pub fn convert(self, r: u8, g: u8, b: u8) -> Pixel {
+        let (r, g, b) = (u16::from(r), u16::from(g), u16::from(b));
+        match self {
+            ColorCorrection::Raw => [raw_channel(r), raw_channel(g), raw_channel(b)],
+            ColorCorrection::Accurate => accurate(r, g, b),
+            ColorCorrection::Gba => gba(r, g, b),
+            ColorCorrection::ReducedContrast => {
+                let [r, g, b] = accurate(r, g, b);
+                [reduce_contrast(r), reduce_contrast(g), reduce_contrast(b)]
+            }
+        }
+    }
+}
+
+fn raw_channel(c: u16) -> u8 {
+    (c * 255 / MAX_CHANNEL) as u8
+}
+
+// Src: the CGB color emulation from byuu's higan
+fn accurate(r: u16, g: u16, b: u16) -> Pixel {
+    let mix = |v: u16| (v.min(960) >> 2) as u8;
+    [
+        mix(r * 26 + g * 4 + b * 2),
+        mix(g * 24 + b * 8),
+        mix(r * 6 + g * 4 + b * 22),
+    ]
+}
+
+// Src: Pokefan531's GBA color shader
+fn gba(r: u16, g: u16, b: u16) -> Pixel {
+    const SCREEN_GAMMA: f32 = 3.2;
+    const DISPLAY_GAMMA: f32 = 2.2;
+    const LUMINANCE: f32 = 0.94;
+
+    let linear = |c: u16| (f32::from(c) / f32::from(MAX_CHANNEL)).powf(SCREEN_GAMMA) * LUMINANCE;
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let encode = |v: f32| (v.clamp(0., 1.).powf(1. / DISPLAY_GAMMA) * 255.) as u8;
+
+    [
+        encode(0.82 * r + 0.125 * g + 0.195 * b),
+        encode(0.24 * r + 0.665 * g + 0.075 * b),
+        encode(-0.06 * r + 0.21 * g + 0.73 * b),
+    ]
+}
+
+fn reduce_contrast(c: u8) -> u8 {
+    0x20 + (u16::from(c) * 3 / 4) as u8
+}
+
+#[test]
+fn test_raw_is_linear() {
+    assert_eq!(ColorCorrection::Raw.convert(0, 0, 0), [0, 0, 0]);
+    assert_eq!(ColorCorrection::Raw.convert(31, 31, 31), [255, 255, 255]);
+    assert_eq!(ColorCorrection::Raw.convert(31, 0, 0), [255, 0, 0]);
+}
+
+#[test]
+fn test_corrected_modes_stay_in_range() {
+    for mode in &[
+        ColorCorrection::Accurate,
+        ColorCorrection::Gba,
+        ColorCorrection::ReducedContrast,
+    ] {
+        let white = mode.convert(31, 31, 31);
+        let red = mode.convert(31, 0, 0);
+        // Pure colors bleed into the other channels on a real LCD
+        assert!(red[1] > 0 || red[2] > 0);
+        assert!(white.iter().all(|c| *c >= red[1]));
+    }
+
+    assert_eq!(
+        ColorCorrection::Accurate.convert(31, 31, 31),
+        [240, 240, 240]
+    );
+    assert_eq!(ColorCorrection::ReducedContrast.convert(0, 0, 0), [0x20; 3]);
+}
@@ -0,0 +1,430 @@
+use std::str::FromStr;
+
+/// BG, OBJ0 and OBJ1 colors as 24-bit RGB, the way the boot ROM's tables are
+/// usually documented
+pub type CompatPalettes = [[u32; 4]; 3];
+
+const PAL_BROWN: [u32; 4] = [0xFF_FF_FF, 0xFF_AD_63, 0x84_31_00, 0x00_00_00];
+const PAL_RED: [u32; 4] = [0xFF_FF_FF, 0xFF_85_84, 0x94_3A_3A, 0x00_00_00];
+const PAL_DARK_BROWN: [u32; 4] = [0xFF_E7_C5, 0xCC_9C_85, 0x84_6B_29, 0x5B_31_09];
+const PAL_BLUE: [u32; 4] = [0xFF_FF_FF, 0x63_A5_FF, 0x00_00_FF, 0x00_00_00];
+const PAL_DARK_BLUE: [u32; 4] = [0xFF_FF_FF, 0x8C_8C_DE, 0x52_52_8C, 0x00_00_00];
+const PAL_GRAY: [u32; 4] = [0xFF_FF_FF, 0xA5_A5_A5, 0x52_52_52, 0x00_00_00];
+const PAL_PASTEL: [u32; 4] = [0xFF_FF_A5, 0xFF_94_94, 0x94_94_FF, 0x00_00_00];
+const PAL_ORANGE: [u32; 4] = [0xFF_FF_FF, 0xFF_FF_00, 0xFF_00_00, 0x00_00_00];
+const PAL_YELLOW: [u32; 4] = [0xFF_FF_FF, 0xFF_FF_00, 0x7D_49_00, 0x00_00_00];
+const PAL_GREEN: [u32; 4] = [0xFF_FF_FF, 0x52_FF_00, 0xFF_42_00, 0x00_00_00];
+const PAL_DARK_GREEN: [u32; 4] = [0xFF_FF_FF, 0x7B_FF_31, 0x00_63_C5, 0x00_00_00];
+const PAL_SPRITE_RED: [u32; 4] = [0xFF_FF_FF, 0xFF_84_84, 0x94_3A_3A, 0x00_00_00];
+const PAL_SPRITE_GREEN: [u32; 4] = [0xFF_FF_FF, 0x7B_FF_31, 0x00_84_00, 0x00_00_00];
+const PAL_INVERTED: [u32; 4] = [0x00_00_00, 0x00_84_84, 0xFF_DE_00, 0xFF_FF_FF];
+
+/// Palettes picked by holding a direction (and optionally A or B) while the
+/// CGB boot logo is shown
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompatPalette {
+    Up,
+    UpA,
+    UpB,
+    Left,
+    LeftA,
+    LeftB,
+    Down,
+    DownA,
+    DownB,
+    Right,
+    RightA,
+    RightB,
+}
+
+impl CompatPalette {
+    pub fn palettes(self) -> CompatPalettes {
+        match self {
+            CompatPalette::Up => [PAL_BROWN; 3],
+            CompatPalette::UpA => [PAL_RED, PAL_SPRITE_GREEN, PAL_BLUE],
+            CompatPalette::UpB => [PAL_DARK_BROWN; 3],
+            CompatPalette::Left => [PAL_BLUE, PAL_SPRITE_RED, PAL_SPRITE_GREEN],
+            CompatPalette::LeftA => [PAL_DARK_BLUE, PAL_SPRITE_RED, PAL_BROWN],
+            CompatPalette::LeftB => [PAL_GRAY; 3],
+            CompatPalette::Down => [PAL_PASTEL; 3],
+            CompatPalette::DownA => [PAL_ORANGE; 3],
+            CompatPalette::DownB => [PAL_YELLOW, PAL_BLUE, PAL_SPRITE_GREEN],
+            CompatPalette::Right => [PAL_GREEN; 3],
+            CompatPalette::RightA => [PAL_DARK_GREEN, PAL_SPRITE_RED, PAL_SPRITE_RED],
+            CompatPalette::RightB => [PAL_INVERTED; 3],
+        }
+    }
+}
+
+impl FromStr for CompatPalette {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "up" => Ok(CompatPalette::Up),
+            "up-a" => Ok(CompatPalette::UpA),
+            "up-b" => Ok(CompatPalette::UpB),
+            "left" => Ok(CompatPalette::Left),
+            "left-a" => Ok(CompatPalette::LeftA),
+            "left-b" => Ok(CompatPalette::LeftB),
+            "down" => Ok(CompatPalette::Down),
+            "down-a" => Ok(CompatPalette::DownA),
+            "down-b" => Ok(CompatPalette::DownB),
+            "right" => Ok(CompatPalette::Right),
+            "right-a" => Ok(CompatPalette::RightA),
+            "right-b" => Ok(CompatPalette::RightB),
+            _ => Err(format!("Unknown compatibility palette {}", s)),
+        }
+    }
+}
+
+// The colors the boot ROM builds its palettes from, in RGB555
+const BOOT_PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+// Offset of a palette's first color in BOOT_PALETTES
+const fn pal(n: usize) -> usize {
+    n * 4
+}
+
+/// (OBJ0, OBJ1, BG) palettes for each palette combination the boot ROM
+/// knows, as offsets into its colors. A few are off by one color, so those
+/// palettes straddle two of the boot ROM's.
+const COMBINATIONS: [(usize, usize, usize); 51] = [
+    (pal(4), pal(4), pal(29)),
+    (pal(18), pal(18), pal(18)),
+    (pal(20), pal(20), pal(20)),
+    (pal(24), pal(24), pal(24)),
+    (pal(9), pal(9), pal(9)),
+    (pal(0), pal(0), pal(0)),
+    (pal(27), pal(27), pal(27)),
+    (pal(5), pal(5), pal(5)),
+    (pal(12), pal(12), pal(12)),
+    (pal(26), pal(26), pal(26)),
+    (pal(16), pal(8), pal(8)),
+    (pal(4), pal(28), pal(28)),
+    (pal(4), pal(2), pal(2)),
+    (pal(3), pal(4), pal(4)),
+    (pal(4), pal(29), pal(29)),
+    (pal(28), pal(4), pal(28)),
+    (pal(2), pal(17), pal(2)),
+    (pal(16), pal(16), pal(8)),
+    (pal(4), pal(4), pal(7)),
+    (pal(4), pal(4), pal(18)),
+    (pal(4), pal(4), pal(20)),
+    (pal(19), pal(19), pal(9)),
+    (pal(4) - 1, pal(4) - 1, pal(11)),
+    (pal(17), pal(17), pal(2)),
+    (pal(4), pal(4), pal(2)),
+    (pal(4), pal(4), pal(3)),
+    (pal(28), pal(28), pal(0)),
+    (pal(3), pal(3), pal(0)),
+    (pal(0), pal(0), pal(1)),
+    (pal(18), pal(22), pal(18)),
+    (pal(20), pal(22), pal(20)),
+    (pal(24), pal(22), pal(24)),
+    (pal(16), pal(22), pal(8)),
+    (pal(17), pal(4), pal(13)),
+    (pal(28) - 1, pal(0), pal(14)),
+    (pal(28) - 1, pal(4), pal(15)),
+    (pal(19), pal(22), pal(9)),
+    (pal(16), pal(28), pal(10)),
+    (pal(4), pal(23), pal(28)),
+    (pal(17), pal(22), pal(2)),
+    (pal(4), pal(0), pal(2)),
+    (pal(4), pal(28), pal(3)),
+    (pal(28), pal(3), pal(0)),
+    (pal(3), pal(28), pal(4)),
+    (pal(21), pal(28), pal(4)),
+    (pal(3), pal(28), pal(0)),
+    (pal(25), pal(3), pal(28)),
+    (pal(0), pal(28), pal(8)),
+    (pal(4), pal(3), pal(28)),
+    (pal(28), pal(3), pal(6)),
+    (pal(4), pal(28), pal(29)),
+];
+
+/// (title checksum, 4th letter of the title, palette combination), in the
+/// order the boot ROM searches them. Some checksums are shared by several
+/// titles, the boot ROM tells those apart by the 4th letter.
+type TitlePalette = (u8, Option<u8>, u8);
+
+const TITLE_PALETTES: [TitlePalette; 94] = [
+    (0x00, None, 0),
+    (0x88, None, 4),  // ALLEY WAY
+    (0x16, None, 5),  // YAKUMAN
+    (0x36, None, 35), // BASEBALL
+    (0xD1, None, 34), // TENNIS
+    (0xDB, None, 3),  // TETRIS
+    (0xF2, None, 31), // QIX
+    (0x3C, None, 15), // DR.MARIO
+    (0x8C, None, 10), // RADARMISSION
+    (0x92, None, 5),  // F1RACE
+    (0x3D, None, 19), // YOSSY NO TAMAGO
+    (0x5C, None, 36),
+    (0x58, None, 7),  // X
+    (0xC9, None, 37), // MARIOLAND2
+    (0x3E, None, 30), // YOSSY NO COOKIE
+    (0x70, None, 44), // ZELDA
+    (0x1D, None, 21),
+    (0x59, None, 32),
+    (0x69, None, 31), // TETRIS FLASH
+    (0x19, None, 20), // DONKEY KONG
+    (0x35, None, 5),  // MARIO'S PICROSS
+    (0xA8, None, 33),
+    (0x14, None, 13), // POKEMON RED
+    (0xAA, None, 14), // POKEMON GREEN
+    (0x75, None, 5),  // PICROSS 2
+    (0x95, None, 29), // YOSSY NO PANEPON
+    (0x99, None, 5),  // KIRAKIRA KIDS
+    (0x34, None, 18), // GAMEBOY GALLERY
+    (0x6F, None, 9),  // POCKETCAMERA
+    (0x15, None, 3),
+    (0xFF, None, 2),  // BALLOON KID
+    (0x97, None, 26), // KINGOFTHEZOO
+    (0x4B, None, 25), // DMG FOOTBALL
+    (0x90, None, 25), // WORLD CUP
+    (0x17, None, 41), // OTHELLO
+    (0x10, None, 42), // SUPER RC PRO-AM
+    (0x39, None, 26), // DYNABLASTER
+    (0xF7, None, 45), // BOY AND BLOB GB2
+    (0xF6, None, 42), // MEGAMAN
+    (0xA2, None, 45), // STAR WARS-NOA
+    (0x49, None, 36), // KIRBY DREAM LAND
+    (0x4E, None, 38), // WAVERACE
+    (0x43, None, 26),
+    (0x68, None, 42), // LOLO2
+    (0xE0, None, 30), // YOSHI'S COOKIE
+    (0x8B, None, 41), // MYSTIC QUEST
+    (0xF0, None, 34),
+    (0xCE, None, 34), // TOPRANKINGTENNIS
+    (0x0C, None, 5),  // MANSELL
+    (0x29, None, 42), // MEGAMAN3
+    (0xE8, None, 6),  // SPACE INVADERS
+    (0xB7, None, 5),  // GAME&WATCH
+    (0x86, None, 33), // DONKEYKONGLAND95
+    (0x9A, None, 25), // ASTEROIDS/MISCMD
+    (0x52, None, 42), // STREET FIGHTER 2
+    (0x01, None, 42), // DEFENDER/JOUST
+    (0x9D, None, 40), // KILLERINSTINCT95
+    (0x71, None, 2),  // TETRIS BLAST
+    (0x9C, None, 16), // PINOCCHIO
+    (0xBD, None, 25),
+    (0x5D, None, 42), // BA.TOSHINDEN
+    (0x6D, None, 42), // NETTOU KOF 95
+    (0x67, None, 5),
+    (0x3F, None, 0),  // TETRIS PLUS
+    (0x6B, None, 39), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 36),
+    (0x46, Some(b'E'), 22), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 25), // GOLF
+    (0xA5, Some(b'A'), 6),  // SOLARSTRIKER
+    (0xC6, Some(b'A'), 32), // GBWARS
+    (0xD3, Some(b'R'), 12), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 36),
+    (0x61, Some(b'E'), 11), // POKEMON BLUE
+    (0x18, Some(b'K'), 39), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 18), // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 39), // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 24), // KID ICARUS
+    (0x0D, Some(b'R'), 31), // TETRIS2
+    (0xF4, Some(b'-'), 50),
+    (0xB3, Some(b'U'), 17), // MOGURANYA
+    (0x46, Some(b'R'), 46), // METROID2
+    (0x28, Some(b'A'), 6),  // GALAGA&GALAXIAN
+    (0xA5, Some(b'R'), 27), // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0),  // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 47),
+    (0x27, Some(b'N'), 41), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 41), // VEGAS STAKES
+    (0x18, Some(b'I'), 0),
+    (0x66, Some(b'L'), 0),  // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 19), // MARIO & YOSHI
+    (0xBF, Some(b'C'), 34), // SOCCER
+    (0x0D, Some(b'E'), 23), // POKEBOM
+    (0xF4, Some(b' '), 18), // G&W GALLERY
+    (0xB3, Some(b'R'), 29), // TETRIS ATTACK
+];
+
+const DEFAULT_PALETTE: CompatPalette = CompatPalette::RightA;
+
+/// Picks the palette the CGB boot ROM would use for a DMG-only cart. Only
+/// Nintendo licensed carts are looked up by title.
+pub fn title_palettes(nintendo_licensed: bool, checksum: u8, fourth_letter: u8) -> CompatPalettes {
+    if nintendo_licensed {
+        let entry = TITLE_PALETTES
+            .iter()
+            .find(|(c, l, _)| *c == checksum && (l.is_none() || *l == Some(fourth_letter)));
+        if let Some((_, _, combination)) = entry {
+            return combination_palettes(usize::from(*combination));
+        }
+    }
+
+    DEFAULT_PALETTE.palettes()
+}
+
+fn combination_palettes(combination: usize) -> CompatPalettes {
+    let (obj0, obj1, bg) = COMBINATIONS[combination];
+    [boot_palette(bg), boot_palette(obj0), boot_palette(obj1)]
+}
+
+fn boot_palette(offset: usize) -> [u32; 4] {
+    let mut colors = [0; 4];
+    for (i, color) in colors.iter_mut().enumerate() {
+        let v = u32::from(BOOT_PALETTES[(offset + i) / 4][(offset + i) % 4]);
+        let (r, g, b) = (v & 0x1F, (v >> 5) & 0x1F, (v >> 10) & 0x1F);
+        // Spread each 5 bits over 8, so white stays 0xFF
+        *color = [r, g, b]
+            .iter()
+            .fold(0, |rgb, c| (rgb << 8) | (c << 3) | (c >> 2));
+    }
+    colors
+}
+
+/// Fills in CGB palette RAM the way the boot ROM does: BG palette 0 and OBJ
+/// palettes 0 and 1 are used for BGP, OBP0 and OBP1 respectively
+pub fn write_palette_ram(palettes: &CompatPalettes, bcp: &mut [u8], ocp: &mut [u8]) {
+    write_rgb555(&palettes[0], &mut bcp[0..8]);
+    write_rgb555(&palettes[1], &mut ocp[0..8]);
+    write_rgb555(&palettes[2], &mut ocp[8..16]);
+}
+
+fn write_rgb555(colors: &[u32; 4], out: &mut [u8]) {
+    for (color, out) in colors.iter().zip(out.chunks_mut(2)) {
+        let r = (color >> 19) & 0x1F;
+        let g = (color >> 11) & 0x1F;
+        let b = (color >> 3) & 0x1F;
+        let v = r | (g << 5) | (b << 10);
+        out[0] = v as u8;
+        out[1] = (v >> 8) as u8;
+    }
+}
+
+#[test]
+fn test_write_palette_ram() {
+    let mut bcp = [0; 0x40];
+    let mut ocp = [0; 0x40];
+    write_palette_ram(&CompatPalette::UpA.palettes(), &mut bcp, &mut ocp);
+
+    // White, then 0xFF8584 -> (31, 16, 16)
+    assert_eq!(bcp[0..4], [0xFF, 0x7F, 0x1F, 0x42]);
+    assert_eq!(bcp[6..8], [0x00, 0x00]);
+    assert_eq!(bcp[8..], [0; 0x38][..]);
+    // OBJ1 of Up+A is blue: 0x0000FF -> (0, 0, 31)
+    assert_eq!(ocp[12..14], [0x00, 0x7C]);
+    assert_eq!(ocp[16..], [0; 0x30][..]);
+}
+
+#[test]
+fn test_unlicensed_carts_get_default_palette() {
+    assert_eq!(
+        title_palettes(false, 0, 0),
+        CompatPalette::RightA.palettes()
+    );
+    // BALLOON KID, but from another publisher
+    assert_eq!(
+        title_palettes(false, 0xFF, b'L'),
+        DEFAULT_PALETTE.palettes()
+    );
+    assert_eq!(title_palettes(true, 0x02, b'A'), DEFAULT_PALETTE.palettes());
+}
+
+#[cfg(test)]
+fn palette_ram(palettes: &CompatPalettes) -> Vec<u8> {
+    let mut bcp = [0; 0x40];
+    let mut ocp = [0; 0x40];
+    write_palette_ram(palettes, &mut bcp, &mut ocp);
+    [&bcp[0..8], &ocp[0..16]].concat()
+}
+
+#[test]
+fn test_button_palettes_are_boot_rom_combinations() {
+    for &(palette, combination) in &[
+        (CompatPalette::Up, 5),
+        (CompatPalette::UpA, 43),
+        (CompatPalette::Left, 48),
+        (CompatPalette::LeftA, 40),
+        (CompatPalette::LeftB, 7),
+        (CompatPalette::Down, 8),
+        (CompatPalette::DownA, 3),
+        (CompatPalette::DownB, 49),
+        (CompatPalette::Right, 1),
+        (CompatPalette::RightA, 0),
+        (CompatPalette::RightB, 6),
+    ] {
+        assert_eq!(
+            palette_ram(&palette.palettes()),
+            palette_ram(&combination_palettes(combination))
+        );
+    }
+    // 5 bit white widens to 8 bit white
+    assert_eq!(boot_palette(pal(0))[0], 0xFF_FF_FF);
+}
+
+#[test]
+fn test_title_palettes() {
+    let palettes = |title: &[u8]| {
+        let checksum = title.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        palette_ram(&title_palettes(true, checksum, title[3]))
+    };
+    assert_eq!(
+        palettes(b"TETRIS"),
+        palette_ram(&CompatPalette::DownA.palettes())
+    );
+    assert_eq!(
+        palettes(b"YAKUMAN"),
+        palette_ram(&CompatPalette::Up.palettes())
+    );
+    assert_eq!(
+        palettes(b"SPACE INVADERS"),
+        palette_ram(&CompatPalette::RightB.palettes())
+    );
+
+    // Red, blue and green BGs, with the red palette for OBJ0 on blue and green
+    let red = palette_ram(&[CompatPalette::UpA.palettes()[0]; 3]);
+    let blue = palette_ram(&[CompatPalette::Left.palettes()[0]; 3]);
+    let green = palette_ram(&[CompatPalette::RightA.palettes()[0]; 3]);
+    assert_eq!(palettes(b"POKEMON RED")[0..8], red[0..8]);
+    assert_eq!(palettes(b"POKEMON BLUE")[0..8], blue[0..8]);
+    assert_eq!(palettes(b"POKEMON BLUE")[8..16], red[8..16]);
+    assert_eq!(palettes(b"POKEMON GREEN")[0..8], green[0..8]);
+    assert_eq!(palettes(b"POKEMON GREEN")[8..16], red[8..16]);
+
+    // Both sum to 0x46, only the 4th letter tells them apart
+    assert_eq!(palettes(b"METROID2")[0..8], blue[0..8]);
+    assert_eq!(
+        palettes(b"SUPER MARIOLAND"),
+        palette_ram(&combination_palettes(22))
+    );
+    assert_eq!(title_palettes(true, 0x46, b'X'), DEFAULT_PALETTE.palettes());
+}
@@ -1,6 +1,9 @@
+use std::io::{self, Write};
+
 use crate::system::SystemMode;
 
 pub const SCREEN_SIZE: (usize, usize) = (160, 144);
+pub const SGB_SCREEN_SIZE: (usize, usize) = (256, 224);
 pub const DMG_COLOR_WHITE: Pixel = [234, 255, 186];
 pub const DMG_COLOR_LIGHT_GRAY: Pixel = [150, 187, 146];
 pub const DMG_COLOR_DARK_GRAY: Pixel = [68, 106, 81];
@@ -38,10 +41,50 @@ impl Framebuffer {
         self.data[x + y * self.size.0]
     }
 
+    pub fn size(&self) -> (usize, usize) {
+        self.size
+    }
+
     pub fn raw(&self) -> &[Pixel] {
         &self.data
     }
 
+    /// Writes the frame as a binary (P6) PPM
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.size.0, self.size.1)?;
+        for pixel in &self.data {
+            out.write_all(pixel)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the frame as an RGB PNG. The image data is stored without
+    /// compression, which keeps this free of dependencies while still being
+    /// readable by anything that opens PNGs.
+    pub fn write_png<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let (width, height) = self.size;
+        out.write_all(&PNG_SIGNATURE)?;
+
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        // 8 bits per channel, truecolor, default compression, filter and no
+        // interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_png_chunk(out, b"IHDR", &header)?;
+
+        // Every scanline starts with its filter type, 0 being none
+        let mut scanlines = Vec::with_capacity((width * 3 + 1) * height);
+        for row in self.data.chunks(width) {
+            scanlines.push(0);
+            for pixel in row {
+                scanlines.extend_from_slice(pixel);
+            }
+        }
+        write_png_chunk(out, b"IDAT", &zlib_stored(&scanlines))?;
+        write_png_chunk(out, b"IEND", &[])
+    }
+
     pub fn draw_wrapping_vline(&mut self, x: usize, y: usize, len: usize, color: Pixel) {
         for i in 0..len {
             let y = (y + i) % self.size.1;
@@ -72,6 +115,63 @@ impl Framebuffer {
     }
 }
 
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+// Deflate's stored blocks hold at most this many bytes each
+const DEFLATE_MAX_STORED: usize = 0xFFFF;
+
+fn write_png_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data.iter()));
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // 32K window, no preset dictionary, and a check value that makes the
+    // header a multiple of 31
+    let mut out = vec![0x78, 0x01];
+    let blocks = data.chunks(DEFLATE_MAX_STORED);
+    let count = blocks.len();
+    for (i, block) in blocks.enumerate() {
+        out.push(if i + 1 == count { 1 } else { 0 });
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    if count == 0 {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32<'a, I: Iterator<Item = &'a u8>>(data: I) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + u32::from(*byte)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
 #[derive(Copy, Clone)]
 pub struct TentativePixel {
     color: Pixel,
@@ -130,3 +230,35 @@ pub fn resolve_pixel_dmg(oam: Option<TentativePixel>, bg: TentativePixel) -> Pix
         bg.color
     }
 }
+
+#[test]
+fn test_write_ppm() {
+    let mut fb = Framebuffer::new((2, 1));
+    fb.set(1, 0, [1, 2, 3]);
+    let mut out = Vec::new();
+    fb.write_ppm(&mut out).unwrap();
+
+    let mut expected = b"P6\n2 1\n255\n".to_vec();
+    expected.extend_from_slice(&DMG_COLOR_WHITE);
+    expected.extend_from_slice(&[1, 2, 3]);
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn test_write_png() {
+    assert_eq!(crc32(b"IEND".iter()), 0xAE42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+    let mut fb = Framebuffer::new(SCREEN_SIZE);
+    fb.set(5, 5, [1, 2, 3]);
+    let mut out = Vec::new();
+    fb.write_png(&mut out).unwrap();
+
+    assert_eq!(out[..8], PNG_SIGNATURE);
+    assert_eq!(&out[12..16], b"IHDR");
+    assert_eq!(out[16..24], [0, 0, 0, 160, 0, 0, 0, 144]);
+    assert_eq!(
+        out[out.len() - 12..],
+        [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+    );
+}
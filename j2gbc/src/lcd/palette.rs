@@ -0,0 +1,81 @@
+use std::str::FromStr;
+
+use super::fb::{Pixel, DMG_COLORS};
+
+pub type DmgPalette = [Pixel; 4];
+
+/// The colors used for the 4 shades of each DMG palette register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DmgPalettes {
+    pub bg: DmgPalette,
+    pub obj0: DmgPalette,
+    pub obj1: DmgPalette,
+}
+
+impl DmgPalettes {
+    pub fn uniform(palette: DmgPalette) -> DmgPalettes {
+        DmgPalettes {
+            bg: palette,
+            obj0: palette,
+            obj1: palette,
+        }
+    }
+}
+
+impl Default for DmgPalettes {
+    fn default() -> Self {
+        DmgPalettePreset::Green.palettes()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmgPalettePreset {
+    Green,
+    Pocket,
+    Light,
+    HighContrast,
+    ColorBlind,
+}
+
+impl DmgPalettePreset {
+    pub fn palettes(self) -> DmgPalettes {
+        DmgPalettes::uniform(match self {
+            DmgPalettePreset::Green => DMG_COLORS,
+            DmgPalettePreset::Pocket => [
+                [224, 219, 205],
+                [168, 159, 148],
+                [112, 107, 102],
+                [43, 43, 38],
+            ],
+            DmgPalettePreset::Light => {
+                [[140, 247, 228], [75, 198, 174], [31, 131, 113], [6, 58, 49]]
+            }
+            DmgPalettePreset::HighContrast => {
+                [[255, 255, 255], [170, 170, 170], [85, 85, 85], [0, 0, 0]]
+            }
+            // Shades differ in both brightness and blue/orange hue, so they
+            // stay distinct with any of the common kinds of color blindness
+            DmgPalettePreset::ColorBlind => [
+                [255, 245, 221],
+                [244, 162, 89],
+                [59, 110, 168],
+                [16, 16, 48],
+            ],
+        })
+    }
+}
+
+impl FromStr for DmgPalettePreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "green" => Ok(DmgPalettePreset::Green),
+            "pocket" => Ok(DmgPalettePreset::Pocket),
+            "light" => Ok(DmgPalettePreset::Light),
+            "high-contrast" => Ok(DmgPalettePreset::HighContrast),
+            "color-blind" => Ok(DmgPalettePreset::ColorBlind),
+            _ => Err(format!("Unknown DMG palette {}", s)),
+        }
+    }
+}
@@ -1,39 +1,213 @@
-use j2ds::{Timer, TimerEvent};
+use std::mem::replace;
 
-use super::{LINE_CYCLE_TIME, LYC_MATCH_FLAG, LYC_MATCH_INT_FLAG, TOTAL_SCANLINES};
-use crate::cpu::Interrupt;
+use super::{
+    DOTS_PER_LINE, LYC_MATCH_FLAG, LYC_MATCH_INT_FLAG, MODE_00_INT_FLAG, MODE_00_MASK,
+    MODE_01_INT_FLAG, MODE_01_MASK, MODE_10_INT_FLAG, MODE_10_MASK, MODE_11_MASK, OAM_SCAN_DOTS,
+    TOTAL_SCANLINES, TRANSFER_MIN_DOTS,
+};
+
+const STAT_INT_FLAGS: u8 =
+    LYC_MATCH_INT_FLAG | MODE_10_INT_FLAG | MODE_01_INT_FLAG | MODE_00_INT_FLAG;
+
+// LY is compared against LYC a few dots into each line, not right at the start
+const LYC_COMPARE_DOT: u64 = 4;
+// On line 153, LY only reads as 153 briefly before wrapping around to 0 early
+const LY_WRAP_DOT: u64 = 8;
+const LYC_COMPARE_WRAPPED_DOT: u64 = 12;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OamScan,
+    Transfer,
+}
+
+impl Mode {
+    pub fn bits(self) -> u8 {
+        match self {
+            Mode::HBlank => MODE_00_MASK,
+            Mode::VBlank => MODE_01_MASK,
+            Mode::OamScan => MODE_10_MASK,
+            Mode::Transfer => MODE_11_MASK,
+        }
+    }
+
+    fn int_flag(self) -> u8 {
+        match self {
+            Mode::HBlank => MODE_00_INT_FLAG,
+            Mode::VBlank => MODE_01_INT_FLAG,
+            Mode::OamScan => MODE_10_INT_FLAG,
+            Mode::Transfer => 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SweepEvent {
+    LineStart,
+    LycCompare,
+    Transfer,
+    HBlank,
+    VBlank,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Stage {
+    LineStart,
+    LycCompare,
+    LyWrap,
+    LycCompareWrapped,
+    Transfer,
+    HBlank,
+}
 
 pub struct ScanlineSweeper {
+    enabled: bool,
+    line: u8,
     ly: u8,
     lyc: u8,
-    interrupt_enabled: bool,
-    timer: Timer,
+    compare_ly: Option<u8>,
+    mode: Mode,
+
+    stat_enables: u8,
+    stat_line: bool,
+    stat_interrupt_pending: bool,
+
+    line_start_cycle: u64,
+    next_stage: Stage,
+    next_cycle: u64,
 }
 
 impl ScanlineSweeper {
     pub fn new() -> ScanlineSweeper {
-        let mut timer = Timer::new(LINE_CYCLE_TIME, 0, 0);
-        timer.update(0);
-        ScanlineSweeper {
+        let mut sweeper = ScanlineSweeper {
+            enabled: false,
+            line: 0,
             ly: 0,
             lyc: 0,
-            interrupt_enabled: false,
-            timer,
-        }
+            compare_ly: None,
+            mode: Mode::HBlank,
+
+            stat_enables: 0,
+            stat_line: false,
+            stat_interrupt_pending: false,
+
+            line_start_cycle: 0,
+            next_stage: Stage::LineStart,
+            next_cycle: 0,
+        };
+        sweeper.enable(0);
+        sweeper
+    }
+
+    pub fn enable(&mut self, cycle: u64) {
+        self.enabled = true;
+        self.line = 0;
+        self.ly = 0;
+        self.compare_ly = None;
+        self.mode = Mode::OamScan;
+        self.line_start_cycle = cycle;
+        self.schedule(Stage::LycCompare, LYC_COMPARE_DOT);
+        self.update_stat_line();
     }
 
-    pub fn pump_cycle(&mut self, cycle: u64) -> Option<Interrupt> {
-        if self.timer.update(cycle) == Some(TimerEvent::RisingEdge) {
-            assert_eq!(self.timer.update(cycle), None); // We should never end up too far behind
-            self.ly = (self.ly + 1) % TOTAL_SCANLINES as u8;
+    pub fn disable(&mut self) {
+        self.enabled = false;
+        self.line = 0;
+        self.ly = 0;
+        self.compare_ly = None;
+        self.mode = Mode::HBlank;
+        self.update_stat_line();
+    }
 
-            if self.ly == self.lyc && self.interrupt_enabled {
-                Some(Interrupt::LCDC)
-            } else {
-                None
+    /// Advances to the next pending event if it happens at or before `cycle`.
+    /// Call repeatedly until it returns `None` to catch up completely.
+    pub fn step(&mut self, cycle: u64) -> Option<SweepEvent> {
+        if !self.enabled || self.next_cycle > cycle {
+            return None;
+        }
+
+        let event = match self.next_stage {
+            Stage::LineStart => self.start_line(),
+            Stage::LycCompare => {
+                self.compare_ly = Some(self.ly);
+                if self.line == TOTAL_SCANLINES as u8 - 1 {
+                    self.schedule(Stage::LyWrap, LY_WRAP_DOT);
+                } else if self.on_visible_scanline() {
+                    self.schedule(Stage::Transfer, OAM_SCAN_DOTS);
+                } else {
+                    self.schedule(Stage::LineStart, DOTS_PER_LINE);
+                }
+                SweepEvent::LycCompare
+            }
+            Stage::LyWrap => {
+                self.ly = 0;
+                self.compare_ly = None;
+                self.schedule(Stage::LycCompareWrapped, LYC_COMPARE_WRAPPED_DOT);
+                SweepEvent::LycCompare
+            }
+            Stage::LycCompareWrapped => {
+                self.compare_ly = Some(self.ly);
+                self.schedule(Stage::LineStart, DOTS_PER_LINE);
+                SweepEvent::LycCompare
             }
+            Stage::Transfer => {
+                self.mode = Mode::Transfer;
+                self.schedule(Stage::HBlank, OAM_SCAN_DOTS + TRANSFER_MIN_DOTS);
+                SweepEvent::Transfer
+            }
+            Stage::HBlank => {
+                self.mode = Mode::HBlank;
+                self.schedule(Stage::LineStart, DOTS_PER_LINE);
+                SweepEvent::HBlank
+            }
+        };
+
+        self.update_stat_line();
+        Some(event)
+    }
+
+    fn start_line(&mut self) -> SweepEvent {
+        self.line_start_cycle = self.next_cycle;
+        self.line = (self.line + 1) % TOTAL_SCANLINES as u8;
+        self.ly = self.line;
+        // Line 0 keeps the comparison that was already made against the early
+        // wrap on line 153, so LYC=0 doesn't see a gap.
+        if self.line != 0 {
+            self.compare_ly = None;
+        }
+        self.schedule(Stage::LycCompare, LYC_COMPARE_DOT);
+
+        if self.on_visible_scanline() {
+            self.mode = Mode::OamScan;
+            SweepEvent::LineStart
+        } else if self.line as usize == super::fb::SCREEN_SIZE.1 {
+            self.mode = Mode::VBlank;
+            SweepEvent::VBlank
         } else {
-            None
+            SweepEvent::LineStart
+        }
+    }
+
+    fn schedule(&mut self, stage: Stage, dot: u64) {
+        self.next_stage = stage;
+        self.next_cycle = self.line_start_cycle + dot;
+    }
+
+    /// Sets how many dots mode 3 lasts on the current line. Only meaningful
+    /// right after a `SweepEvent::Transfer`.
+    pub fn set_transfer_length(&mut self, dots: u64) {
+        if self.next_stage == Stage::HBlank {
+            self.schedule(Stage::HBlank, OAM_SCAN_DOTS + dots);
+        }
+    }
+
+    pub fn next_event_cycle(&self) -> u64 {
+        if self.enabled {
+            self.next_cycle
+        } else {
+            u64::MAX
         }
     }
 
@@ -47,27 +221,61 @@ impl ScanlineSweeper {
 
     pub fn set_lyc(&mut self, v: u8) {
         self.lyc = v;
+        self.update_stat_line();
+    }
+
+    fn coincidence(&self) -> bool {
+        self.compare_ly == Some(self.lyc)
     }
 
     pub fn stat_flags(&self) -> u8 {
-        if self.ly == self.lyc {
+        let lyc = if self.coincidence() {
             LYC_MATCH_FLAG
         } else {
             0
-        }
+        };
+        lyc | self.mode.bits()
+    }
+
+    pub fn stat(&self) -> u8 {
+        0b1000_0000 | self.stat_enables | self.stat_flags()
     }
 
     pub fn update_stat(&mut self, flags: u8) {
-        self.interrupt_enabled = (flags & LYC_MATCH_INT_FLAG) != 0;
+        self.stat_enables = flags & STAT_INT_FLAGS;
+        self.update_stat_line();
+    }
+
+    // All of the STAT interrupt sources are ORed together into a single line,
+    // and the interrupt is only requested on its rising edge. So one source
+    // going high while another is already holding the line high is blocked.
+    fn update_stat_line(&mut self) {
+        let lyc = self.coincidence() && self.stat_enables & LYC_MATCH_INT_FLAG != 0;
+        let mode = self.stat_enables & self.mode.int_flag() != 0;
+        let line = self.enabled && (lyc || mode);
+
+        if line && !self.stat_line {
+            self.stat_interrupt_pending = true;
+        }
+        self.stat_line = line;
     }
 
-    pub fn timer(&self) -> Timer {
-        self.timer
+    pub fn take_stat_interrupt(&mut self) -> bool {
+        replace(&mut self.stat_interrupt_pending, false)
     }
 
     pub fn on_visible_scanline(&self) -> bool {
-        (self.ly as usize) < super::fb::SCREEN_SIZE.1
+        (self.line as usize) < super::fb::SCREEN_SIZE.1
+    }
+}
+
+#[cfg(test)]
+fn run_until(sweeper: &mut ScanlineSweeper, cycle: u64) -> Vec<SweepEvent> {
+    let mut events = vec![];
+    while let Some(event) = sweeper.step(cycle) {
+        events.push(event);
     }
+    events
 }
 
 #[test]
@@ -75,31 +283,112 @@ fn test_sweep_and_wrap() {
     let mut sweeper = ScanlineSweeper::new();
 
     assert_eq!(sweeper.ly(), 0);
-    sweeper.pump_cycle(LINE_CYCLE_TIME - 1);
+    run_until(&mut sweeper, DOTS_PER_LINE - 1);
     assert_eq!(sweeper.ly(), 0);
 
     for scanline in 1..TOTAL_SCANLINES {
-        sweeper.pump_cycle(LINE_CYCLE_TIME * scanline);
+        run_until(&mut sweeper, DOTS_PER_LINE * scanline);
         assert_eq!(sweeper.ly(), scanline as u8);
     }
 
-    sweeper.pump_cycle(LINE_CYCLE_TIME * TOTAL_SCANLINES);
+    run_until(&mut sweeper, DOTS_PER_LINE * TOTAL_SCANLINES);
+    assert_eq!(sweeper.ly(), 0);
+}
+
+#[test]
+fn test_frame_length() {
+    let mut sweeper = ScanlineSweeper::new();
+
+    let mut vblanks = vec![];
+    for cycle in 0..(DOTS_PER_LINE * TOTAL_SCANLINES * 3) {
+        if run_until(&mut sweeper, cycle).contains(&SweepEvent::VBlank) {
+            vblanks.push(cycle);
+        }
+    }
+
+    assert_eq!(vblanks.len(), 3);
+    assert_eq!(vblanks[1] - vblanks[0], 70_224);
+    assert_eq!(vblanks[2] - vblanks[1], 70_224);
+}
+
+#[test]
+fn test_mode_timing() {
+    let mut sweeper = ScanlineSweeper::new();
+
+    run_until(&mut sweeper, OAM_SCAN_DOTS - 1);
+    assert_eq!(sweeper.mode, Mode::OamScan);
+    assert_eq!(
+        run_until(&mut sweeper, OAM_SCAN_DOTS),
+        [SweepEvent::Transfer]
+    );
+    assert_eq!(sweeper.mode, Mode::Transfer);
+
+    sweeper.set_transfer_length(TRANSFER_MIN_DOTS + 10);
+    run_until(&mut sweeper, OAM_SCAN_DOTS + TRANSFER_MIN_DOTS + 9);
+    assert_eq!(sweeper.mode, Mode::Transfer);
+    assert_eq!(
+        run_until(&mut sweeper, OAM_SCAN_DOTS + TRANSFER_MIN_DOTS + 10),
+        [SweepEvent::HBlank]
+    );
+    assert_eq!(sweeper.mode, Mode::HBlank);
+
+    run_until(&mut sweeper, DOTS_PER_LINE * 144);
+    assert_eq!(sweeper.mode, Mode::VBlank);
+}
+
+#[test]
+fn test_ly_153_quirk() {
+    let mut sweeper = ScanlineSweeper::new();
+    let line_153 = DOTS_PER_LINE * 153;
+
+    run_until(&mut sweeper, line_153 + LY_WRAP_DOT - 1);
+    assert_eq!(sweeper.ly(), 153);
+
+    run_until(&mut sweeper, line_153 + LY_WRAP_DOT);
     assert_eq!(sweeper.ly(), 0);
+
+    sweeper.set_lyc(0);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, 0);
+    run_until(&mut sweeper, line_153 + LYC_COMPARE_WRAPPED_DOT);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, LYC_MATCH_FLAG);
+
+    // The match carries straight through into line 0
+    run_until(&mut sweeper, DOTS_PER_LINE * TOTAL_SCANLINES + 1);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, LYC_MATCH_FLAG);
 }
 
 #[test]
 fn test_lyc() {
     let mut sweeper = ScanlineSweeper::new();
     sweeper.set_lyc(42);
+    sweeper.update_stat(LYC_MATCH_INT_FLAG);
     for scanline in 0..42 {
-        assert_eq!(sweeper.stat_flags(), 0);
-        assert_eq!(sweeper.pump_cycle(LINE_CYCLE_TIME * scanline), None);
+        run_until(&mut sweeper, DOTS_PER_LINE * scanline + LYC_COMPARE_DOT);
+        assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, 0);
+        assert!(!sweeper.take_stat_interrupt());
     }
 
-    sweeper.update_stat(LYC_MATCH_INT_FLAG);
-    assert_eq!(
-        sweeper.pump_cycle(LINE_CYCLE_TIME * 42),
-        Some(Interrupt::LCDC)
-    );
-    assert_eq!(sweeper.stat_flags(), LYC_MATCH_FLAG);
+    run_until(&mut sweeper, DOTS_PER_LINE * 42 + LYC_COMPARE_DOT - 1);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, 0);
+    assert!(!sweeper.take_stat_interrupt());
+
+    run_until(&mut sweeper, DOTS_PER_LINE * 42 + LYC_COMPARE_DOT);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, LYC_MATCH_FLAG);
+    assert!(sweeper.take_stat_interrupt());
+}
+
+#[test]
+fn test_stat_irq_blocking() {
+    let mut sweeper = ScanlineSweeper::new();
+    sweeper.set_lyc(10);
+    sweeper.update_stat(LYC_MATCH_INT_FLAG | MODE_10_INT_FLAG);
+    run_until(&mut sweeper, DOTS_PER_LINE * 10 - 1);
+    sweeper.take_stat_interrupt();
+
+    // Mode 2 raises the line, so the LYC match right after it is blocked
+    run_until(&mut sweeper, DOTS_PER_LINE * 10);
+    assert!(sweeper.take_stat_interrupt());
+    run_until(&mut sweeper, DOTS_PER_LINE * 10 + LYC_COMPARE_DOT);
+    assert_eq!(sweeper.stat_flags() & LYC_MATCH_FLAG, LYC_MATCH_FLAG);
+    assert!(!sweeper.take_stat_interrupt());
 }
@@ -7,6 +7,7 @@ mod cart;
 mod cpu;
 pub mod debug;
 mod error;
+mod gbs;
 mod input;
 mod inst;
 mod lcd;
@@ -14,12 +15,21 @@ mod mbc;
 mod mem;
 mod mmu;
 mod mmu_exceptions;
+mod recorder;
+mod sgb;
 mod system;
 mod timer;
 
 pub use crate::{
-    audio::{AudioSink, NullSink},
-    input::Button,
-    lcd::fb::{Framebuffer, SCREEN_SIZE},
+    audio::{AudioSink, ChannelCaptureSink, ChannelSnapshot, NullSink, TeeSink, WavFileSink},
+    gbs::Gbs,
+    input::{Button, MAX_PLAYERS},
+    lcd::{
+        blend::LcdResponse,
+        color::ColorCorrection,
+        compat::CompatPalette,
+        fb::{Framebuffer, SCREEN_SIZE, SGB_SCREEN_SIZE},
+        palette::{DmgPalette, DmgPalettePreset, DmgPalettes},
+    },
     system::System,
 };
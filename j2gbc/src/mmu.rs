@@ -53,7 +53,7 @@ impl Mmu {
             exceptions: cart.get_mmu_exceptions(),
             cart,
             lcd: Box::new(Lcd::new(cgb_mode)),
-            audio: Audio::new(audio_sink),
+            audio: Audio::new(audio_sink, cgb_mode),
             timer: Timer::new(),
             input: Input::new(),
             pedantic: true,
@@ -224,7 +224,13 @@ impl Mmu {
                     Ok(())
                 }
                 REG_TIMA | REG_DIV | REG_TAC | REG_TMA => self.timer.write(a, v),
-                REG_P1 => self.input.write(a, v),
+                REG_P1 => {
+                    self.input.write(a, v)?;
+                    if let Some(command) = self.input.take_sgb_command() {
+                        self.lcd.handle_sgb_command(&command);
+                    }
+                    Ok(())
+                }
                 REG_SB | REG_SC => Ok(()),
                 _ => {
                     error!("MMU: Unimplemented memory write at address {:?}", a);
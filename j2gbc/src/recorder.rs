@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::cpu::CLOCK_RATE;
+use crate::lcd::{fb::Framebuffer, SCREEN_CYCLE_TIME};
+
+/// Sample rate of recorded audio. It divides the clock exactly, so the audio
+/// can't drift away from the video however long the recording runs.
+pub const RECORD_SAMPLE_RATE: u64 = 65536;
+
+/// Writes every frame to a Y4M stream at the LCD's native ~59.73 Hz.
+///
+/// Frames are placed by the cycle they were completed at rather than just
+/// appended, so stretches without new frames (the LCD turned off, or an SGB
+/// freezing the screen) keep showing the last frame for as long as they
+/// lasted. The first frame is whatever was on screen as recording started.
+pub struct VideoRecorder<W: Write> {
+    out: W,
+    start_cycle: u64,
+    frames_written: u64,
+    frame: Vec<u8>,
+}
+
+impl VideoRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        first: &Framebuffer,
+        start_cycle: u64,
+    ) -> io::Result<VideoRecorder<BufWriter<File>>> {
+        VideoRecorder::new(BufWriter::new(File::create(path)?), first, start_cycle)
+    }
+}
+
+impl<W: Write> VideoRecorder<W> {
+    pub fn new(mut out: W, first: &Framebuffer, start_cycle: u64) -> io::Result<VideoRecorder<W>> {
+        let size = first.size();
+        let gcd = gcd(CLOCK_RATE, SCREEN_CYCLE_TIME);
+        writeln!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+            size.0,
+            size.1,
+            CLOCK_RATE / gcd,
+            SCREEN_CYCLE_TIME / gcd
+        )?;
+        let mut frame = Vec::new();
+        to_yuv444(first, &mut frame);
+        Ok(VideoRecorder {
+            out,
+            start_cycle,
+            frames_written: 0,
+            frame,
+        })
+    }
+
+    pub fn push_frame(&mut self, cycle: u64, fb: &Framebuffer) -> io::Result<()> {
+        let slot = cycle.saturating_sub(self.start_cycle) / SCREEN_CYCLE_TIME;
+        // Two frames completing within one slot only happens around the LCD
+        // being turned off and on, only the first of them is kept
+        if slot < self.frames_written {
+            return Ok(());
+        }
+
+        while self.frames_written < slot {
+            self.write_frame()?;
+        }
+
+        to_yuv444(fb, &mut self.frame);
+        self.write_frame()
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&self.frame)?;
+        self.frames_written += 1;
+        Ok(())
+    }
+}
+
+// Planar BT.601, limited range, which is what players assume for Y4M
+fn to_yuv444(fb: &Framebuffer, out: &mut Vec<u8>) {
+    let pixels = fb.raw();
+    out.clear();
+    out.resize(pixels.len() * 3, 0);
+    let (y_plane, rest) = out.split_at_mut(pixels.len());
+    let (u_plane, v_plane) = rest.split_at_mut(pixels.len());
+    for (i, p) in pixels.iter().enumerate() {
+        let (r, g, b) = (f32::from(p[0]), f32::from(p[1]), f32::from(p[2]));
+        y_plane[i] = (16. + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+        u_plane[i] = (128. - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+        v_plane[i] = (128. + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+// The RIFF chunk's size has to fit in 32 bits too, and it covers the rest of
+// the header as well as the data
+const WAV_MAX_DATA_SIZE: u64 = (u32::MAX - (WAV_HEADER_SIZE - 8)) as u64;
+
+/// Writes samples to a 32-bit float WAV file.
+///
+/// The header gets patched with the current length every second of audio,
+/// so a recording that never got to finish cleanly is still playable. WAV
+/// files can't hold more than 4 GiB, frames past that are refused with an
+/// error.
+pub struct AudioRecorder<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    sample_rate: u32,
+    samples: u64,
+    samples_since_header: u32,
+}
+
+impl AudioRecorder<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        channels: u16,
+    ) -> io::Result<AudioRecorder<BufWriter<File>>> {
+        AudioRecorder::new(BufWriter::new(File::create(path)?), sample_rate, channels)
+    }
+}
+
+impl<W: Write + Seek> AudioRecorder<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<AudioRecorder<W>> {
+        let bytes_per_frame = u32::from(channels) * 4;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // IEEE float
+        out.write_all(&3u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * bytes_per_frame).to_le_bytes())?;
+        out.write_all(&(bytes_per_frame as u16).to_le_bytes())?;
+        out.write_all(&32u16.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(AudioRecorder {
+            out,
+            channels,
+            sample_rate,
+            samples: 0,
+            samples_since_header: 0,
+        })
+    }
+
+    /// Writes one sample for every channel
+    pub fn push_frame(&mut self, frame: &[f32]) -> io::Result<()> {
+        debug_assert_eq!(frame.len(), usize::from(self.channels));
+        if self.data_size() + self.bytes_per_frame() > WAV_MAX_DATA_SIZE {
+            return Err(io::Error::other("WAV file size limit reached"));
+        }
+        for s in frame {
+            self.out.write_all(&s.to_le_bytes())?;
+        }
+        self.samples += 1;
+        self.samples_since_header += 1;
+        if self.samples_since_header == self.sample_rate {
+            self.samples_since_header = 0;
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.update_header()?;
+        self.out.flush()
+    }
+
+    fn bytes_per_frame(&self) -> u64 {
+        u64::from(self.channels) * 4
+    }
+
+    fn data_size(&self) -> u64 {
+        self.samples * self.bytes_per_frame()
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        // Never more than WAV_MAX_DATA_SIZE, so both sizes fit
+        let data_size = self.data_size() as u32;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.out
+            .seek(SeekFrom::Start(u64::from(WAV_HEADER_SIZE) - 4))?;
+        self.out.write_all(&data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for AudioRecorder<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[test]
+fn test_video_fills_gaps() {
+    let mut black = Framebuffer::new(crate::lcd::fb::SCREEN_SIZE);
+    black.set(0, 0, [0, 0, 0]);
+    let white = Framebuffer::new(crate::lcd::fb::SCREEN_SIZE);
+    let frame_size = 6 + 160 * 144 * 3;
+
+    let mut out = Vec::new();
+    {
+        let mut recorder = VideoRecorder::new(&mut out, &white, 1000).unwrap();
+        recorder.push_frame(1000 + 100, &black).unwrap();
+        // The LCD was off for two frames
+        recorder
+            .push_frame(1000 + SCREEN_CYCLE_TIME * 3 + 100, &white)
+            .unwrap();
+        // A second frame in the same slot gets dropped
+        recorder
+            .push_frame(1000 + SCREEN_CYCLE_TIME * 3 + 200, &black)
+            .unwrap();
+    }
+
+    let header = b"YUV4MPEG2 W160 H144 F262144:4389 Ip A1:1 C444\n";
+    assert_eq!(&out[..header.len()], &header[..]);
+    let frames = &out[header.len()..];
+    assert_eq!(frames.len(), frame_size * 4);
+    let y0 = |frame: usize| frames[frame * frame_size + 6];
+    assert_eq!(y0(0), 16);
+    assert_eq!(y0(1), 16);
+    assert_eq!(y0(2), 16);
+    assert_eq!(y0(3), 223);
+}
+
+#[test]
+fn test_audio_header() {
+    let mut out = io::Cursor::new(Vec::new());
+    {
+        let mut recorder = AudioRecorder::new(&mut out, 65536, 2).unwrap();
+        recorder.push_frame(&[0.5, -0.5]).unwrap();
+        recorder.push_frame(&[0.25, 0.]).unwrap();
+    }
+
+    let wav = out.into_inner();
+    assert_eq!(wav.len(), 44 + 16);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(wav[4..8], 52u32.to_le_bytes());
+    assert_eq!(wav[24..28], 65536u32.to_le_bytes());
+    assert_eq!(wav[40..44], 16u32.to_le_bytes());
+    assert_eq!(wav[44..48], 0.5f32.to_le_bytes());
+}
+
+#[test]
+fn test_audio_size_limit() {
+    let mut out = io::Cursor::new(Vec::new());
+    {
+        let mut recorder = AudioRecorder::new(&mut out, 65536, 2).unwrap();
+        // Pretend hours of audio have been written already
+        recorder.samples = WAV_MAX_DATA_SIZE / 8 - 1;
+        recorder.push_frame(&[0.5, -0.5]).unwrap();
+        assert!(recorder.push_frame(&[0.5, -0.5]).is_err());
+    }
+
+    let wav = out.into_inner();
+    let data_size = (WAV_MAX_DATA_SIZE / 8 * 8) as u32;
+    assert_eq!(wav[4..8], (data_size + 36).to_le_bytes());
+    assert_eq!(wav[40..44], data_size.to_le_bytes());
+    // Only the frame that fit was written
+    assert_eq!(wav.len(), 44 + 8);
+}
@@ -0,0 +1,565 @@
+use log::debug;
+
+use crate::lcd::{
+    color::ColorCorrection,
+    fb::{Framebuffer, Pixel, SCREEN_SIZE, SGB_SCREEN_SIZE},
+};
+
+pub const PACKET_SIZE: usize = 16;
+
+const CMD_PAL01: u8 = 0x00;
+const CMD_PAL23: u8 = 0x01;
+const CMD_PAL03: u8 = 0x02;
+const CMD_PAL12: u8 = 0x03;
+const CMD_ATTR_BLK: u8 = 0x04;
+const CMD_ATTR_LIN: u8 = 0x05;
+const CMD_ATTR_DIV: u8 = 0x06;
+const CMD_ATTR_CHR: u8 = 0x07;
+const CMD_PAL_SET: u8 = 0x0A;
+const CMD_PAL_TRN: u8 = 0x0B;
+pub const CMD_MLT_REQ: u8 = 0x11;
+const CMD_CHR_TRN: u8 = 0x13;
+const CMD_PCT_TRN: u8 = 0x14;
+const CMD_MASK_EN: u8 = 0x17;
+
+const PACKET_COUNT_MASK: u8 = 0b0000_0111;
+
+const ATTR_COLS: usize = 20;
+const ATTR_ROWS: usize = 18;
+
+const ATTR_BLK_INSIDE: u8 = 0b001;
+const ATTR_BLK_BORDER: u8 = 0b010;
+const ATTR_BLK_OUTSIDE: u8 = 0b100;
+const ATTR_LIN_HORIZONTAL: u8 = 0b1000_0000;
+const ATTR_DIV_HORIZONTAL: u8 = 0b0100_0000;
+const PAL_SET_CANCEL_MASK: u8 = 0b0100_0000;
+const PAL_SET_APPLY_ATTR_FILE: u8 = 0b1000_0000;
+
+const SYSTEM_PALETTE_COUNT: usize = 512;
+
+// The border is made of SNES 4bpp tiles, 8x8 pixels each
+const BORDER_TILE_COUNT: usize = 256;
+const BORDER_TILE_BYTES: usize = 32;
+const BORDER_COLS: usize = 32;
+const BORDER_ROWS: usize = 28;
+const BORDER_PALETTE_COUNT: usize = 4;
+const BORDER_PALETTES_OFFSET: usize = 0x800;
+const BORDER_TILE_MASK: u16 = 0xFF;
+const BORDER_XFLIP: u16 = 0x4000;
+const BORDER_YFLIP: u16 = 0x8000;
+const CHR_TRN_HIGH_TILES: u8 = 0b1;
+
+const SCREEN_OFFSET: (usize, usize) = (
+    (SGB_SCREEN_SIZE.0 - SCREEN_SIZE.0) / 2,
+    (SGB_SCREEN_SIZE.1 - SCREEN_SIZE.1) / 2,
+);
+
+// Palette 1-A, which the SGB shows until a game sends its own colors
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+/// The number of packets a command is sent in, from its first byte
+pub fn command_packet_count(header: u8) -> usize {
+    usize::from(header & PACKET_COUNT_MASK).max(1)
+}
+
+pub fn command_id(data: &[u8]) -> u8 {
+    data[0] >> 3
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mask {
+    None,
+    /// Keep showing the last frame
+    Freeze,
+    Black,
+    /// Fill the screen with color 0
+    Color0,
+}
+
+/// Data the SGB reads from the screen on the frame after a `*_TRN` command
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Transfer {
+    Palettes,
+    /// Half of the border's tiles, the upper half if set
+    BorderTiles(bool),
+    BorderMap,
+}
+
+pub struct Sgb {
+    palettes: [[u16; 4]; 4],
+    colors: [[Pixel; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    attributes: [u8; ATTR_COLS * ATTR_ROWS],
+    mask: Mask,
+    pending_transfer: Option<Transfer>,
+
+    border_tiles: Vec<u8>,
+    border_map: [u16; BORDER_COLS * BORDER_ROWS],
+    border_palettes: [[u16; 16]; BORDER_PALETTE_COUNT],
+    border_dirty: bool,
+    output: Framebuffer,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Sgb::new()
+    }
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        let mut sgb = Sgb {
+            palettes: [DEFAULT_PALETTE; 4],
+            colors: [[[0; 3]; 4]; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTE_COUNT],
+            attributes: [0; ATTR_COLS * ATTR_ROWS],
+            mask: Mask::None,
+            pending_transfer: None,
+
+            border_tiles: vec![0; BORDER_TILE_COUNT * BORDER_TILE_BYTES],
+            border_map: [0; BORDER_COLS * BORDER_ROWS],
+            border_palettes: [[0; 16]; BORDER_PALETTE_COUNT],
+            border_dirty: true,
+            output: Framebuffer::new(SGB_SCREEN_SIZE),
+        };
+        sgb.update_colors();
+        sgb
+    }
+
+    /// The color of a DMG shade at a screen position, according to the
+    /// attribute map
+    pub fn color(&self, x: usize, y: usize, shade: usize) -> Pixel {
+        let col = (x / 8).min(ATTR_COLS - 1);
+        let row = (y / 8).min(ATTR_ROWS - 1);
+        self.colors[self.attributes[row * ATTR_COLS + col] as usize][shade]
+    }
+
+    /// The game screen surrounded by the border
+    pub fn get_framebuffer(&self) -> &Framebuffer {
+        &self.output
+    }
+
+    pub fn compose(&mut self, screen: &Framebuffer) {
+        if self.border_dirty {
+            self.render_border();
+            self.border_dirty = false;
+        }
+
+        for y in 0..SCREEN_SIZE.1 {
+            for x in 0..SCREEN_SIZE.0 {
+                self.output
+                    .set(x + SCREEN_OFFSET.0, y + SCREEN_OFFSET.1, screen.get(x, y));
+            }
+        }
+    }
+
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+
+    pub fn take_transfer(&mut self) -> Option<Transfer> {
+        self.pending_transfer.take()
+    }
+
+    pub fn complete_transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, data) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    for (color, data) in palette.iter_mut().zip(data.chunks(2)) {
+                        *color = u16::from(data[0]) | (u16::from(data[1]) << 8);
+                    }
+                }
+            }
+            Transfer::BorderTiles(high) => {
+                let half = self.border_tiles.len() / 2;
+                let start = if high { half } else { 0 };
+                let len = half.min(data.len());
+                self.border_tiles[start..start + len].copy_from_slice(&data[..len]);
+                self.border_dirty = true;
+            }
+            Transfer::BorderMap => {
+                for (entry, data) in self.border_map.iter_mut().zip(data.chunks(2)) {
+                    *entry = u16::from(data[0]) | (u16::from(data[1]) << 8);
+                }
+                let palettes = data[BORDER_PALETTES_OFFSET..].chunks(2);
+                for (color, data) in self.border_palettes.iter_mut().flatten().zip(palettes) {
+                    *color = u16::from(data[0]) | (u16::from(data[1]) << 8);
+                }
+                self.border_dirty = true;
+            }
+        }
+    }
+
+    fn render_border(&mut self) {
+        let backdrop = self.colors[0][0];
+        for (i, entry) in self.border_map.iter().enumerate() {
+            let tile =
+                &self.border_tiles[usize::from(entry & BORDER_TILE_MASK) * BORDER_TILE_BYTES..];
+            // The map uses SNES palettes 4-7, which were sent in order
+            let palette = &self.border_palettes[usize::from((entry >> 10) & 0b11)];
+
+            for y in 0..8 {
+                let row = if entry & BORDER_YFLIP != 0 { 7 - y } else { y };
+                let planes = [
+                    tile[row * 2],
+                    tile[row * 2 + 1],
+                    tile[16 + row * 2],
+                    tile[16 + row * 2 + 1],
+                ];
+                for x in 0..8 {
+                    let bit = if entry & BORDER_XFLIP != 0 { x } else { 7 - x };
+                    let index = planes
+                        .iter()
+                        .enumerate()
+                        .fold(0, |index, (p, plane)| index | (((plane >> bit) & 1) << p));
+                    let color = if index == 0 {
+                        backdrop
+                    } else {
+                        rgb555(palette[usize::from(index)])
+                    };
+                    self.output
+                        .set((i % BORDER_COLS) * 8 + x, (i / BORDER_COLS) * 8 + y, color);
+                }
+            }
+        }
+    }
+
+    pub fn handle_command(&mut self, data: &[u8]) {
+        match command_id(data) {
+            CMD_PAL01 => self.set_palette_pair(0, 1, data),
+            CMD_PAL23 => self.set_palette_pair(2, 3, data),
+            CMD_PAL03 => self.set_palette_pair(0, 3, data),
+            CMD_PAL12 => self.set_palette_pair(1, 2, data),
+            CMD_ATTR_BLK => self.attr_blk(data),
+            CMD_ATTR_LIN => self.attr_lin(data),
+            CMD_ATTR_DIV => self.attr_div(data),
+            CMD_ATTR_CHR => self.attr_chr(data),
+            CMD_PAL_SET => self.pal_set(data),
+            CMD_PAL_TRN => self.pending_transfer = Some(Transfer::Palettes),
+            CMD_CHR_TRN => {
+                self.pending_transfer =
+                    Some(Transfer::BorderTiles(data[1] & CHR_TRN_HIGH_TILES != 0))
+            }
+            CMD_PCT_TRN => self.pending_transfer = Some(Transfer::BorderMap),
+            CMD_MASK_EN => {
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::None,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            // Handled by the joypad
+            CMD_MLT_REQ => {}
+            id => debug!("SGB: Unimplemented command {:#04x}", id),
+        }
+    }
+
+    fn update_colors(&mut self) {
+        for (colors, palette) in self.colors.iter_mut().zip(self.palettes.iter()) {
+            for (color, v) in colors.iter_mut().zip(palette.iter()) {
+                *color = rgb555(*v);
+            }
+        }
+        // Transparent parts of the border show color 0
+        self.border_dirty = true;
+    }
+
+    // Color 0 is shared by all of the palettes, so setting it for one sets it
+    // for every palette
+    fn set_palette_pair(&mut self, a: usize, b: usize, data: &[u8]) {
+        let color = |i: usize| u16::from(data[1 + i * 2]) | (u16::from(data[2 + i * 2]) << 8);
+
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+        self.update_colors();
+    }
+
+    fn pal_set(&mut self, data: &[u8]) {
+        for i in 0..4 {
+            let index = u16::from(data[1 + i * 2]) | (u16::from(data[2 + i * 2]) << 8);
+            self.palettes[i] = self.system_palettes[index as usize % SYSTEM_PALETTE_COUNT];
+        }
+        for palette in 1..4 {
+            self.palettes[palette][0] = self.palettes[0][0];
+        }
+        self.update_colors();
+
+        let flags = data[9];
+        if flags & PAL_SET_APPLY_ATTR_FILE != 0 {
+            debug!("SGB: Attribute files are not supported");
+        }
+        if flags & PAL_SET_CANCEL_MASK != 0 {
+            self.mask = Mask::None;
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = usize::from(data[1] & 0b1_1111);
+        for set in data[2..].chunks_exact(6).take(count) {
+            let control = set[0] & 0b111;
+            let inside = set[1] & 0b11;
+            let border = (set[1] >> 2) & 0b11;
+            let outside = (set[1] >> 4) & 0b11;
+            let (x1, y1, x2, y2) = (
+                usize::from(set[2]),
+                usize::from(set[3]),
+                usize::from(set[4]),
+                usize::from(set[5]),
+            );
+
+            // When only one side of the border is changed, the border goes
+            // along with it
+            let border = match control {
+                ATTR_BLK_INSIDE => Some(inside),
+                ATTR_BLK_OUTSIDE => Some(outside),
+                _ if control & ATTR_BLK_BORDER != 0 => Some(border),
+                _ => None,
+            };
+            let inside = Some(inside).filter(|_| control & ATTR_BLK_INSIDE != 0);
+            let outside = Some(outside).filter(|_| control & ATTR_BLK_OUTSIDE != 0);
+
+            for y in 0..ATTR_ROWS {
+                for x in 0..ATTR_COLS {
+                    let in_x = x >= x1 && x <= x2;
+                    let in_y = y >= y1 && y <= y2;
+                    let palette = if x > x1 && x < x2 && y > y1 && y < y2 {
+                        inside
+                    } else if in_x && in_y {
+                        border
+                    } else {
+                        outside
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_COLS + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = usize::from(data[1]);
+        for line in data[2..].iter().take(count) {
+            let index = usize::from(line & 0b1_1111);
+            let palette = (line >> 5) & 0b11;
+            if line & ATTR_LIN_HORIZONTAL != 0 {
+                if index < ATTR_ROWS {
+                    for x in 0..ATTR_COLS {
+                        self.attributes[index * ATTR_COLS + x] = palette;
+                    }
+                }
+            } else if index < ATTR_COLS {
+                for y in 0..ATTR_ROWS {
+                    self.attributes[y * ATTR_COLS + index] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after = data[1] & 0b11;
+        let before = (data[1] >> 2) & 0b11;
+        let on_line = (data[1] >> 4) & 0b11;
+        let horizontal = data[1] & ATTR_DIV_HORIZONTAL != 0;
+        let line = usize::from(data[2]);
+
+        for y in 0..ATTR_ROWS {
+            for x in 0..ATTR_COLS {
+                let position = if horizontal { y } else { x };
+                self.attributes[y * ATTR_COLS + x] = match position.cmp(&line) {
+                    std::cmp::Ordering::Less => before,
+                    std::cmp::Ordering::Equal => on_line,
+                    std::cmp::Ordering::Greater => after,
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = usize::from(data[1]);
+        let mut y = usize::from(data[2]);
+        let count = usize::from(data[3]) | (usize::from(data[4]) << 8);
+        let vertical = data[5] & 1 != 0;
+
+        let palettes = data[6..]
+            .iter()
+            .flat_map(|b| (0..4).rev().map(move |i| (b >> (i * 2)) & 0b11));
+        for palette in palettes.take(count) {
+            if x < ATTR_COLS && y < ATTR_ROWS {
+                self.attributes[y * ATTR_COLS + x] = palette;
+            }
+
+            if vertical {
+                y += 1;
+                if y >= ATTR_ROWS {
+                    y = 0;
+                    x += 1;
+                }
+            } else {
+                x += 1;
+                if x >= ATTR_COLS {
+                    x = 0;
+                    y += 1;
+                }
+            }
+        }
+    }
+}
+
+fn rgb555(v: u16) -> Pixel {
+    ColorCorrection::Raw.convert(
+        (v & 0x1F) as u8,
+        ((v >> 5) & 0x1F) as u8,
+        ((v >> 10) & 0x1F) as u8,
+    )
+}
+
+#[cfg(test)]
+fn test_command(id: u8, body: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; PACKET_SIZE];
+    data[0] = (id << 3) | 1;
+    data[1..=body.len()].copy_from_slice(body);
+    data
+}
+
+#[test]
+fn test_pal_commands_share_color_0() {
+    let mut sgb = Sgb::new();
+    sgb.handle_command(&test_command(
+        CMD_PAL23,
+        &[
+            0x1F, 0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04, 0x00, 0x05, 0x00, 0x06, 0x00,
+        ],
+    ));
+
+    assert_eq!(sgb.palettes[0][0], 0x1F);
+    assert_eq!(sgb.palettes[1][0], 0x1F);
+    assert_eq!(sgb.palettes[2], [0x1F, 1, 2, 3]);
+    assert_eq!(sgb.palettes[3], [0x1F, 4, 5, 6]);
+    assert_eq!(sgb.palettes[0][1], DEFAULT_PALETTE[1]);
+    assert_eq!(sgb.color(0, 0, 0), [255, 0, 0]);
+}
+
+#[test]
+fn test_attr_blk() {
+    let mut sgb = Sgb::new();
+    // Inside only, so the border takes the inside palette too
+    sgb.handle_command(&test_command(
+        CMD_ATTR_BLK,
+        &[1, ATTR_BLK_INSIDE, 0b11_10_01, 2, 2, 4, 4],
+    ));
+
+    assert_eq!(sgb.attributes[2 * ATTR_COLS + 2], 1);
+    assert_eq!(sgb.attributes[3 * ATTR_COLS + 3], 1);
+    assert_eq!(sgb.attributes[4 * ATTR_COLS + 4], 1);
+    assert_eq!(sgb.attributes[5 * ATTR_COLS + 5], 0);
+
+    sgb.handle_command(&test_command(
+        CMD_ATTR_BLK,
+        &[
+            1,
+            ATTR_BLK_BORDER | ATTR_BLK_OUTSIDE,
+            0b11_10_01,
+            2,
+            2,
+            4,
+            4,
+        ],
+    ));
+    assert_eq!(sgb.attributes[2 * ATTR_COLS + 2], 2);
+    assert_eq!(sgb.attributes[3 * ATTR_COLS + 3], 1);
+    assert_eq!(sgb.attributes[0], 3);
+}
+
+#[test]
+fn test_attr_div_and_lin() {
+    let mut sgb = Sgb::new();
+    sgb.handle_command(&test_command(
+        CMD_ATTR_DIV,
+        &[ATTR_DIV_HORIZONTAL | 0b10_01_11, 9],
+    ));
+    assert_eq!(sgb.attributes[8 * ATTR_COLS], 1);
+    assert_eq!(sgb.attributes[9 * ATTR_COLS + 5], 2);
+    assert_eq!(sgb.attributes[10 * ATTR_COLS + 19], 3);
+
+    sgb.handle_command(&test_command(CMD_ATTR_LIN, &[1, (0b10 << 5) | 3]));
+    assert_eq!(sgb.attributes[3], 2);
+    assert_eq!(sgb.attributes[17 * ATTR_COLS + 3], 2);
+}
+
+#[test]
+fn test_attr_chr() {
+    let mut sgb = Sgb::new();
+    sgb.handle_command(&test_command(
+        CMD_ATTR_CHR,
+        &[19, 0, 3, 0, 0, 0b01_10_11_00],
+    ));
+    assert_eq!(sgb.attributes[19], 1);
+    assert_eq!(sgb.attributes[ATTR_COLS], 2);
+    assert_eq!(sgb.attributes[ATTR_COLS + 1], 3);
+    assert_eq!(sgb.attributes[ATTR_COLS + 2], 0);
+}
+
+#[test]
+fn test_pal_trn_and_pal_set() {
+    let mut sgb = Sgb::new();
+    sgb.handle_command(&test_command(CMD_PAL_TRN, &[]));
+    assert_eq!(sgb.take_transfer(), Some(Transfer::Palettes));
+
+    let mut data = vec![0; 4096];
+    data[8..16].copy_from_slice(&[1, 0, 2, 0, 3, 0, 4, 0]);
+    sgb.complete_transfer(Transfer::Palettes, &data);
+
+    sgb.handle_command(&test_command(CMD_MASK_EN, &[2]));
+    assert_eq!(sgb.mask(), Mask::Black);
+    sgb.handle_command(&test_command(
+        CMD_PAL_SET,
+        &[1, 0, 0, 0, 1, 0, 0, 0, PAL_SET_CANCEL_MASK],
+    ));
+    assert_eq!(sgb.palettes[0], [1, 2, 3, 4]);
+    assert_eq!(sgb.palettes[1], [1, 0, 0, 0]);
+    assert_eq!(sgb.palettes[2], [1, 2, 3, 4]);
+    assert_eq!(sgb.mask(), Mask::None);
+}
+
+#[test]
+fn test_border() {
+    let mut sgb = Sgb::new();
+
+    // Tile 0x81's top left pixel uses color 15, the rest are transparent
+    sgb.handle_command(&test_command(CMD_CHR_TRN, &[CHR_TRN_HIGH_TILES]));
+    let transfer = sgb.take_transfer().unwrap();
+    assert_eq!(transfer, Transfer::BorderTiles(true));
+    let mut data = vec![0; 4096];
+    for plane in &[0, 1, 16, 17] {
+        data[BORDER_TILE_BYTES + plane] = 0x80;
+    }
+    sgb.complete_transfer(transfer, &data);
+
+    // Map the tile to the top right corner, flipped horizontally and using
+    // the 2nd border palette
+    sgb.handle_command(&test_command(CMD_PCT_TRN, &[]));
+    let transfer = sgb.take_transfer().unwrap();
+    let mut data = vec![0; 4096];
+    let entry = 0x81 | (5 << 10) | BORDER_XFLIP;
+    data[(BORDER_COLS - 1) * 2] = entry as u8;
+    data[(BORDER_COLS - 1) * 2 + 1] = (entry >> 8) as u8;
+    data[BORDER_PALETTES_OFFSET + 16 * 2 + 15 * 2] = 0x1F;
+    sgb.complete_transfer(transfer, &data);
+
+    let mut screen = Framebuffer::new(SCREEN_SIZE);
+    screen.set(0, 0, [1, 2, 3]);
+    sgb.compose(&screen);
+
+    let output = sgb.get_framebuffer();
+    assert_eq!(output.get(SGB_SCREEN_SIZE.0 - 1, 0), [255, 0, 0]);
+    assert_eq!(output.get(SGB_SCREEN_SIZE.0 - 8, 0), sgb.colors[0][0]);
+    assert_eq!(output.get(0, 0), sgb.colors[0][0]);
+    assert_eq!(output.get(SCREEN_OFFSET.0, SCREEN_OFFSET.1), [1, 2, 3]);
+}
@@ -1,14 +1,33 @@
-use std::io::Read;
+use std::io::{self, Read};
+use std::path::Path;
 use std::time::Duration;
 
 use log::info;
 
 use crate::{
-    audio::AudioSink, cart::Cart, cpu::Cpu, debug::Debugger, input::Button, lcd::fb::Framebuffer,
+    audio::{AudioSink, ChannelSnapshot},
+    cart::Cart,
+    cpu::Cpu,
+    debug::Debugger,
+    gbs::Gbs,
+    input::Button,
+    lcd::{
+        blend::LcdResponse, color::ColorCorrection, compat::CompatPalette, fb::Framebuffer,
+        palette::DmgPalettes,
+    },
 };
 
 pub struct System {
     cpu: Cpu,
+    gbs: Option<GbsPlayback>,
+}
+
+// A GBS played instead of a cart, which is kept around to restart it on
+// other tracks
+struct GbsPlayback {
+    gbs: Gbs,
+    track: u8,
+    allow_cgb_mode: bool,
 }
 
 impl System {
@@ -27,7 +46,64 @@ impl System {
 
         let cpu = Cpu::new(c, audio_sink, allow_cgb_mode);
 
-        Ok(System { cpu })
+        Ok(System { cpu, gbs: None })
+    }
+
+    /// Plays the music in a GBS file instead of running a cart, starting
+    /// from the file's first track
+    pub fn new_gbs<R: Read>(
+        gbs_data: R,
+        audio_sink: Box<dyn AudioSink + Send>,
+        allow_cgb_mode: bool,
+    ) -> std::io::Result<System> {
+        let gbs = Gbs::load(gbs_data)?;
+
+        info!("Title: {}", gbs.title());
+        info!("Author: {}", gbs.author());
+        info!("Copyright: {}", gbs.copyright());
+        info!("Tracks: {}", gbs.track_count());
+
+        let track = gbs.first_track();
+        let cpu = Cpu::new(gbs.cart(track, allow_cgb_mode), audio_sink, allow_cgb_mode);
+
+        Ok(System {
+            cpu,
+            gbs: Some(GbsPlayback {
+                gbs,
+                track,
+                allow_cgb_mode,
+            }),
+        })
+    }
+
+    /// The GBS being played, if the system wasn't started from a cart
+    pub fn gbs(&self) -> Option<&Gbs> {
+        self.gbs.as_ref().map(|p| &p.gbs)
+    }
+
+    /// The GBS track being played, counting from 0
+    pub fn gbs_track(&self) -> Option<u8> {
+        self.gbs.as_ref().map(|p| p.track)
+    }
+
+    /// Restarts the GBS being played on another track, counting from 0. This
+    /// starts over from a fresh system, so any recording stops. Returns false
+    /// if there's no such track or no GBS being played.
+    pub fn play_gbs_track(&mut self, track: u8) -> bool {
+        let playback = match self.gbs.as_mut() {
+            Some(p) if track < p.gbs.track_count() => p,
+            _ => return false,
+        };
+        let sink = self.cpu.mmu.audio.synth.take_sink();
+        let pedantic = self.cpu.mmu.pedantic;
+        self.cpu = Cpu::new(
+            playback.gbs.cart(track, playback.allow_cgb_mode),
+            sink,
+            playback.allow_cgb_mode,
+        );
+        self.cpu.mmu.pedantic = pedantic;
+        playback.track = track;
+        true
     }
 
     pub fn run_for_duration(&mut self, duration: &Duration) {
@@ -38,6 +114,119 @@ impl System {
         self.cpu.mmu.lcd.get_framebuffer()
     }
 
+    /// The screen without frame blending, so only colors the game actually
+    /// drew show up. Best for screenshots and clips.
+    pub fn get_raw_framebuffer(&self) -> &Framebuffer {
+        self.cpu.mmu.lcd.get_raw_framebuffer()
+    }
+
+    /// Smears frames together like a real LCD, which some games rely on for
+    /// transparency by flickering sprites
+    pub fn set_frame_blend(&mut self, response: Option<LcdResponse>) {
+        self.cpu.mmu.lcd.set_frame_blend(response);
+    }
+
+    /// Records every frame and every mixed sample, to a Y4M video and a WAV
+    /// file that line up exactly
+    pub fn start_av_recording<P: AsRef<Path>>(
+        &mut self,
+        video_path: P,
+        audio_path: P,
+    ) -> io::Result<()> {
+        let cycle = self.cpu.cycle();
+        self.cpu.mmu.lcd.start_recording(video_path, cycle)?;
+        if let Err(e) = self.cpu.mmu.audio.synth.start_recording(audio_path, cycle) {
+            self.cpu.mmu.lcd.stop_recording();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Finishes any recording, which also happens when the system is dropped
+    pub fn stop_av_recording(&mut self) {
+        self.cpu.mmu.lcd.stop_recording();
+        self.cpu.mmu.audio.synth.stop_recording();
+    }
+
+    /// Logs every write to the sound registers to a VGM file, which plays
+    /// the music back in standard players
+    pub fn start_vgm_logging<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.cpu.mmu.audio.start_vgm_logging(path)
+    }
+
+    /// Finishes any VGM log, which also happens when the system is dropped
+    pub fn stop_vgm_logging(&mut self) {
+        self.cpu.mmu.audio.stop_vgm_logging();
+    }
+
+    /// The screen with the SGB border around it, when running in SGB mode
+    pub fn get_sgb_framebuffer(&self) -> Option<&Framebuffer> {
+        self.cpu.mmu.lcd.get_sgb_framebuffer()
+    }
+
+    pub fn set_color_correction(&mut self, color_correction: ColorCorrection) {
+        self.cpu.mmu.lcd.set_color_correction(color_correction);
+    }
+
+    pub fn set_dmg_palette(&mut self, palettes: DmgPalettes) {
+        self.cpu.mmu.lcd.set_dmg_palettes(palettes);
+    }
+
+    /// Runs an SGB enhanced cart with the Super Game Boy's colorization.
+    /// Returns false if the cart doesn't support it or is running in CGB mode.
+    pub fn enable_sgb(&mut self) -> bool {
+        if !self.cpu.mmu.cart.supports_sgb() || !self.cpu.mmu.lcd.enable_sgb() {
+            return false;
+        }
+        self.cpu.mmu.input.enable_sgb();
+        true
+    }
+
+    /// Overrides the palette a DMG-only cart got from its title when running
+    /// on CGB hardware. Has no effect otherwise.
+    pub fn set_compat_palette(&mut self, palette: CompatPalette) {
+        if self.cpu.mmu.lcd.is_dmg_compat() {
+            self.cpu.mmu.lcd.set_compat_palettes(&palette.palettes());
+        }
+    }
+
+    /// Mutes or unmutes one of the four sound channels, numbered from 0 in
+    /// register order. Unlike NR51 this is left alone by the game. Channels
+    /// past the fourth are ignored.
+    pub fn set_channel_enabled(&mut self, channel: usize, enabled: bool) {
+        self.cpu
+            .mmu
+            .audio
+            .synth
+            .mixer
+            .set_channel_enabled(channel, enabled);
+    }
+
+    pub fn is_channel_enabled(&self, channel: usize) -> bool {
+        self.cpu.mmu.audio.synth.mixer.is_channel_enabled(channel)
+    }
+
+    /// Mutes every sound channel but one. Unmute the others to undo it.
+    pub fn solo_channel(&mut self, channel: usize) {
+        self.cpu.mmu.audio.synth.mixer.solo_channel(channel);
+    }
+
+    /// What each sound channel is playing right now, along with its latest
+    /// output, for drawing oscilloscopes and the like
+    pub fn audio_snapshot(&self) -> [ChannelSnapshot; 4] {
+        self.cpu.mmu.audio.synth.snapshot()
+    }
+
+    /// Scales a channel's output before it's mixed, where 1 is unchanged
+    pub fn set_channel_gain(&mut self, channel: usize, gain: f32) {
+        self.cpu
+            .mmu
+            .audio
+            .synth
+            .mixer
+            .set_channel_gain(channel, gain);
+    }
+
     pub fn set_mmu_pedantic(&mut self, pedantic: bool) {
         self.cpu.mmu.pedantic = pedantic;
     }
@@ -54,13 +243,16 @@ impl System {
         Debugger::new(&mut self.cpu)
     }
 
-    pub fn activate_button(&mut self, button: Button) {
-        self.cpu.mmu.input.activate_button(button);
-        self.cpu.request_p1_int();
+    /// Players other than the first (0) are only seen by SGB games that
+    /// requested multiple joypads. Players past the fourth are ignored.
+    pub fn activate_button(&mut self, player: usize, button: Button) {
+        if self.cpu.mmu.input.activate_button(player, button) {
+            self.cpu.request_p1_int();
+        }
     }
 
-    pub fn deactivate_button(&mut self, button: Button) {
-        self.cpu.mmu.input.deactivate_button(button);
+    pub fn deactivate_button(&mut self, player: usize, button: Button) {
+        self.cpu.mmu.input.deactivate_button(player, button);
     }
 }
 
@@ -69,3 +261,39 @@ pub enum SystemMode {
     DMG,
     CGB,
 }
+
+#[test]
+fn test_gbs_playback() {
+    use crate::{audio::NullSink, debug::Address, gbs::test_gbs};
+
+    let mut data = vec![
+        0xEA, 0x00, 0xC0, // init: ld (0xC000), a
+        0xC9, // ret
+    ];
+    data.resize(0x10, 0);
+    data.extend_from_slice(&[
+        0x21, 0x01, 0xC0, // play: ld hl, 0xC001
+        0x34, // inc (hl)
+        0xC9, // ret
+    ]);
+
+    // Once a frame, or from the timer at 4096 / 64 = 64 Hz
+    for &(tac, rate) in &[(0, 60), (0b0000_0100, 64)] {
+        let mut file = test_gbs(tac, &data);
+        file[0x0E] = 256u16.wrapping_sub(64) as u8;
+        let mut system = System::new_gbs(&file[..], Box::new(NullSink), false).unwrap();
+        system.set_mmu_pedantic(false);
+        assert_eq!(system.gbs_track(), Some(1));
+
+        system.run_for_duration(&Duration::from_secs(1));
+        let read = |system: &mut System, a| system.debugger().read_mem(Address(a)).unwrap();
+        assert_eq!(read(&mut system, 0xC000), 1);
+        let plays = i32::from(read(&mut system, 0xC001));
+        assert!((plays - rate).abs() <= 1, "{} plays", plays);
+
+        assert!(system.play_gbs_track(2));
+        assert!(!system.play_gbs_track(3));
+        system.run_for_duration(&Duration::from_millis(100));
+        assert_eq!(read(&mut system, 0xC000), 2);
+    }
+}
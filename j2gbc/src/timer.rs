@@ -1,105 +1,166 @@
 use std::cmp::min;
-use std::num::Wrapping;
 
-use super::cpu::{Interrupt, InterruptSet, CLOCK_RATE};
+use super::cpu::{Interrupt, InterruptSet};
 use super::mem::*;
 use crate::error::ExecutionError;
 
-const DIV_INCREMENT_CYCLE_COUNT: u64 = CLOCK_RATE / 16_779;
-const TIMA_INCREMENT_CYCLE_COUNT: [u64; 4] = [
-    CLOCK_RATE / 4_096,
-    CLOCK_RATE / 262_144,
-    CLOCK_RATE / 65_536,
-    CLOCK_RATE / 16_384,
-];
+// The bit of the system counter that clocks TIMA as it falls, for each
+// TAC clock select. That gives 4096, 262144, 65536 and 16384 Hz.
+const TIMA_COUNTER_BITS: [u32; 4] = [9, 3, 5, 7];
 
+// After TIMA overflows it reads 0 for one machine cycle, before TMA is
+// loaded into it and the interrupt is requested
+const RELOAD_DELAY: u64 = 4;
+// The machine cycle of the reload itself, where writes to TIMA are lost and
+// writes to TMA go through to TIMA as well
+const RELOAD_CYCLE: u64 = 4;
+
+/// The 16-bit system counter that DIV shows the upper byte of, along with
+/// TIMA, which counts every time the counter bit picked by TAC falls.
+///
+/// The counter counts every CPU clock, so twice as fast in double speed mode.
+/// Since TIMA is clocked by a falling edge rather than on a schedule of its
+/// own, resetting DIV or changing TAC while the selected bit is set clocks
+/// TIMA an extra time, just like on hardware.
 #[derive(Default)]
 pub struct Timer {
-    div: u8,
+    counter: u16,
     tima: u8,
     tma: u8,
     tac: u8,
 
     double_speed: bool,
 
-    next_div_cycle: u64,
-    next_tima_cycle: u64,
+    // The cycle the counter has been run up to
+    cycle: u64,
+    // CPU clocks left until an overflowed TIMA gets reloaded
+    reload_in: Option<u64>,
+    // CPU clocks left in the cycle TIMA was reloaded in
+    reloading_for: u64,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
-            div: 0,
+            counter: 0,
             tima: 0,
             tma: 0,
             tac: 0,
 
             double_speed: false,
 
-            next_div_cycle: 0,
-            next_tima_cycle: 0,
+            cycle: 0,
+            reload_in: None,
+            reloading_for: 0,
         }
     }
 
+    /// The STOP that switches speeds also resets the counter
     pub fn toggle_double_speed(&mut self) {
         self.double_speed = !self.double_speed;
+        self.reset_counter();
     }
 
     fn tima_enabled(&self) -> bool {
         self.tac & 0b100 != 0
     }
 
-    fn tima_duration(&self) -> u64 {
-        TIMA_INCREMENT_CYCLE_COUNT[(self.tac & 0b11) as usize]
+    // Clocks between falling edges of the selected counter bit
+    fn tima_period(&self) -> u64 {
+        2 << TIMA_COUNTER_BITS[(self.tac & 0b11) as usize]
     }
 
-    pub fn get_next_event_cycle(&self) -> u64 {
-        if self.tima_enabled() {
-            min(self.next_div_cycle, self.next_tima_cycle)
+    // What TIMA watches for falling edges: the selected bit, gated by the
+    // enable bit
+    fn tima_signal(&self) -> bool {
+        self.tima_enabled() && u64::from(self.counter) & (self.tima_period() >> 1) != 0
+    }
+
+    fn clocks_per_cycle(&self) -> u64 {
+        if self.double_speed {
+            2
         } else {
-            self.next_div_cycle
+            1
         }
     }
 
+    /// When the next interrupt is due, or `u64::MAX` if TIMA is stopped
+    pub fn get_next_event_cycle(&self) -> u64 {
+        let clocks = if let Some(reload_in) = self.reload_in {
+            reload_in
+        } else if self.tima_enabled() {
+            let period = self.tima_period();
+            let to_edge = period - u64::from(self.counter) % period;
+            to_edge + u64::from(0xFF - self.tima) * period + RELOAD_DELAY
+        } else {
+            return u64::MAX;
+        };
+        self.cycle + clocks.div_ceil(self.clocks_per_cycle())
+    }
+
     pub fn pump_cycle(&mut self, cycle: u64) -> InterruptSet {
-        if self.next_div_cycle <= cycle {
-            self.next_div_cycle =
-                cycle + maybe_half_cycle(DIV_INCREMENT_CYCLE_COUNT, self.double_speed);
-            self.div = (Wrapping(self.div) + Wrapping(1)).0;
-        }
+        let mut clocks = cycle.saturating_sub(self.cycle) * self.clocks_per_cycle();
+        self.cycle = cycle;
+
+        let mut interrupts = InterruptSet::default();
+        while clocks > 0 {
+            // Step from one event to the next, each being a reload or a
+            // falling edge
+            let mut step = clocks;
+            if let Some(reload_in) = self.reload_in {
+                step = min(step, reload_in);
+            }
+            if self.tima_enabled() {
+                let period = self.tima_period();
+                step = min(step, period - u64::from(self.counter) % period);
+            }
 
-        if self.tima_enabled() && self.next_tima_cycle <= cycle {
-            self.next_tima_cycle =
-                cycle + maybe_half_cycle(self.tima_duration(), self.double_speed);
+            self.counter = self.counter.wrapping_add(step as u16);
+            clocks -= step;
+            self.reloading_for = self.reloading_for.saturating_sub(step);
 
-            if self.tima == 0xFF {
-                self.tima = self.tma;
-                Interrupt::Timer.into()
-            } else {
-                self.tima += 1;
-                InterruptSet::default()
+            if let Some(reload_in) = self.reload_in {
+                if reload_in == step {
+                    self.reload_in = None;
+                    self.reloading_for = RELOAD_CYCLE;
+                    self.tima = self.tma;
+                    interrupts.add_interrupt(Interrupt::Timer);
+                } else {
+                    self.reload_in = Some(reload_in - step);
+                }
             }
+            if self.tima_enabled() && u64::from(self.counter) % self.tima_period() == 0 {
+                self.increment_tima();
+            }
+        }
+        interrupts
+    }
+
+    fn increment_tima(&mut self) {
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.reload_in = Some(RELOAD_DELAY);
         } else {
-            InterruptSet::default()
+            self.tima += 1;
         }
     }
-}
 
-fn maybe_half_cycle(cycle_count: u64, double_speed: bool) -> u64 {
-    if double_speed {
-        cycle_count >> 2
-    } else {
-        cycle_count
+    fn reset_counter(&mut self) {
+        let signal = self.tima_signal();
+        self.counter = 0;
+        if signal {
+            self.increment_tima();
+        }
     }
 }
 
 impl MemDevice for Timer {
     fn read(&self, a: Address) -> Result<u8, ExecutionError> {
         match a {
-            REG_DIV => Ok(self.div),
+            REG_DIV => Ok((self.counter >> 8) as u8),
             REG_TIMA => Ok(self.tima),
             REG_TMA => Ok(self.tma),
-            REG_TAC => Ok(self.tac),
+            REG_TAC => Ok(self.tac | 0b1111_1000),
             _ => unreachable!(),
         }
     }
@@ -107,16 +168,28 @@ impl MemDevice for Timer {
     fn write(&mut self, a: Address, v: u8) -> Result<(), ExecutionError> {
         match a {
             REG_DIV => {
-                self.div = 0;
+                self.reset_counter();
             }
             REG_TIMA => {
-                self.tima = v;
+                // Writing during the delay cancels the reload and the
+                // interrupt, but the reload itself wins over the write
+                if self.reloading_for == 0 {
+                    self.reload_in = None;
+                    self.tima = v;
+                }
             }
             REG_TMA => {
                 self.tma = v;
+                if self.reloading_for != 0 {
+                    self.tima = v;
+                }
             }
             REG_TAC => {
-                self.tac = v;
+                let signal = self.tima_signal();
+                self.tac = v & 0b111;
+                if signal && !self.tima_signal() {
+                    self.increment_tima();
+                }
             }
             _ => unreachable!(),
         }
@@ -124,3 +197,83 @@ impl MemDevice for Timer {
         Ok(())
     }
 }
+
+#[test]
+fn test_div_rate() {
+    let mut timer = Timer::new();
+    timer.pump_cycle(1024);
+    assert_eq!(timer.read(REG_DIV).unwrap(), 4);
+    timer.toggle_double_speed();
+    assert_eq!(timer.read(REG_DIV).unwrap(), 0);
+    timer.pump_cycle(2048);
+    assert_eq!(timer.read(REG_DIV).unwrap(), 8);
+}
+
+#[test]
+fn test_delayed_reload() {
+    let mut timer = Timer::new();
+    timer.write(REG_TMA, 0x80).unwrap();
+    timer.write(REG_TIMA, 0xFF).unwrap();
+    // 262144 Hz, every 16 clocks
+    timer.write(REG_TAC, 0b101).unwrap();
+    assert_eq!(timer.get_next_event_cycle(), 16 + 4);
+
+    assert_eq!(timer.pump_cycle(16).if_(), 0);
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0);
+    assert_eq!(timer.pump_cycle(20).if_(), Interrupt::Timer.bits());
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x80);
+
+    // Unless TIMA gets written in between
+    timer.write(REG_TIMA, 0xFF).unwrap();
+    timer.pump_cycle(32);
+    timer.write(REG_TIMA, 0x12).unwrap();
+    assert_eq!(timer.pump_cycle(40).if_(), 0);
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x12);
+    assert_eq!(timer.get_next_event_cycle(), 48 + 0xED * 16 + 4);
+}
+
+#[test]
+fn test_writes_in_reload_cycle() {
+    let mut timer = Timer::new();
+    timer.write(REG_TMA, 0x80).unwrap();
+    timer.write(REG_TIMA, 0xFF).unwrap();
+    timer.write(REG_TAC, 0b101).unwrap();
+
+    // TIMA gets reloaded at 20, so a write then is lost
+    timer.pump_cycle(20);
+    timer.write(REG_TIMA, 0x12).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x80);
+    // While a new TMA is reloaded as well
+    timer.write(REG_TMA, 0x90).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x90);
+
+    // Both are back to normal a cycle later
+    timer.pump_cycle(24);
+    timer.write(REG_TMA, 0xA0).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x90);
+    timer.write(REG_TIMA, 0x12).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 0x12);
+}
+
+#[test]
+fn test_falling_edge_glitches() {
+    let mut timer = Timer::new();
+    timer.write(REG_TAC, 0b101).unwrap();
+    // Bit 3 is set, so resetting it counts
+    timer.pump_cycle(8);
+    timer.write(REG_DIV, 0).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 1);
+    // But not while it's clear
+    timer.pump_cycle(12);
+    timer.write(REG_DIV, 0).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 1);
+
+    // Turning the timer off, or selecting a clear bit, counts the same way
+    timer.pump_cycle(20);
+    timer.write(REG_TAC, 0b001).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 2);
+    timer.write(REG_TAC, 0b101).unwrap();
+    timer.write(REG_TAC, 0b110).unwrap();
+    assert_eq!(timer.read(REG_TIMA).unwrap(), 3);
+    assert_eq!(timer.read(REG_TAC).unwrap(), 0b1111_1110);
+}
@@ -1,7 +1,10 @@
 use std::fs::File;
 use std::time::Duration;
 
-use j2gbc::{debug::Address, NullSink, System};
+use j2gbc::{
+    debug::{Address, Register8},
+    NullSink, System,
+};
 
 macro_rules! conformance_test {
     {
@@ -17,6 +20,19 @@ macro_rules! conformance_test {
     }
 }
 
+macro_rules! mooneye_test {
+    {
+        name: $test_name:ident,
+        path: $path:expr,
+        max_runtime: $max_runtime:expr,
+    } => {
+        #[test]
+        fn $test_name() {
+            run_mooneye_test($path, $max_runtime);
+        }
+    }
+}
+
 include!(concat!(env!("OUT_DIR"), "/conformance_test_list.rs"));
 
 fn run_conformance_test(path: &str, sec_to_run: u64, expected: &[u8], expected_addr: Address) {
@@ -30,3 +46,26 @@ fn run_conformance_test(path: &str, sec_to_run: u64, expected: &[u8], expected_a
         assert_eq!(system.debugger().read_mem(addr).unwrap(), *e);
     }
 }
+
+// Mooneye's tests load the start of the Fibonacci sequence into B, C, D, E,
+// H and L when they pass
+fn run_mooneye_test(path: &str, sec_to_run: u64) {
+    let cart_file = File::open(path).unwrap();
+    let mut system = System::new(cart_file, Box::new(NullSink), false).unwrap();
+
+    system.run_for_duration(&Duration::from_secs(sec_to_run));
+
+    let debugger = system.debugger();
+    let registers = [
+        Register8::B,
+        Register8::C,
+        Register8::D,
+        Register8::E,
+        Register8::H,
+        Register8::L,
+    ]
+    .iter()
+    .map(|r| debugger.read_reg(*r))
+    .collect::<Vec<u8>>();
+    assert_eq!(registers, [3, 5, 8, 13, 21, 34]);
+}
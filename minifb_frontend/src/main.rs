@@ -3,11 +3,31 @@ use std::fs::File;
 use std::io::Read;
 
 use cpal_audio::CpalSink;
-use frontend_utils::Saver;
-use j2gbc::{AudioSink, Button, NullSink, System, SCREEN_SIZE};
+use frontend_utils::{clip::ClipToggle, scaler::Scaler, Pacer, Saver};
+use j2gbc::{
+    AudioSink, Button, DmgPalettePreset, System, MAX_PLAYERS, SCREEN_SIZE, SGB_SCREEN_SIZE,
+};
 
 fn main() {
-    let mut buffer: [u32; SCREEN_SIZE.0 * SCREEN_SIZE.1] = [255; SCREEN_SIZE.0 * SCREEN_SIZE.1];
+    let args = frontend_utils::parse_args();
+    let rom_path = args.value_of("rom").unwrap();
+    if rom_path.to_lowercase().ends_with(".gbs") {
+        play_gbs(&args);
+        return;
+    }
+    let (mut system, mut saver, mut pacer) = load_system(&args);
+    let show_sgb_border = args.is_present("sgb-border") && system.get_sgb_framebuffer().is_some();
+    let scaler = args
+        .value_of("scaler")
+        .map_or(Scaler::Nearest(1), |s| s.parse().unwrap());
+    let size = scaler.output_size(if show_sgb_border {
+        SGB_SCREEN_SIZE
+    } else {
+        SCREEN_SIZE
+    });
+
+    let mut scaled = Vec::new();
+    let mut buffer = vec![255; size.0 * size.1];
     let options = WindowOptions {
         borderless: false,
         title: true,
@@ -19,72 +39,214 @@ fn main() {
         none: false,
     };
 
-    let mut window = Window::new("j2gbc", SCREEN_SIZE.0, SCREEN_SIZE.1, options).unwrap();
-    let (mut system, mut saver) = load_system(&frontend_utils::parse_args());
+    let mut window = Window::new("j2gbc", size.0, size.1, options).unwrap();
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-    let mut timer = frontend_utils::DeltaTimer::default();
+    let mut clip = ClipToggle::default();
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
         process_input(&window, &mut system);
+        // Screenshots are of the bare screen, without the border, scaling or
+        // frame blending
+        if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+            frontend_utils::save_screenshot(system.get_raw_framebuffer(), rom_path);
+        }
+        if window.is_key_pressed(Key::F11, KeyRepeat::No) {
+            clip.toggle(rom_path);
+        }
 
-        system.run_for_duration(&timer.elapsed());
+        system.run_for_duration(&pacer.next_duration());
+        clip.push_frame(system.get_raw_framebuffer());
 
-        let framebuffer = system.get_framebuffer();
-        for y in 0..SCREEN_SIZE.1 {
-            for x in 0..SCREEN_SIZE.0 {
-                let pixel = framebuffer.get(x, y);
-                buffer[y * SCREEN_SIZE.0 + x] =
-                    (pixel[2] as u32) | ((pixel[1] as u32) << 8) | ((pixel[0] as u32) << 16);
-            }
+        let framebuffer = if show_sgb_border {
+            system.get_sgb_framebuffer().unwrap()
+        } else {
+            system.get_framebuffer()
+        };
+        scaler.scale(framebuffer, &mut scaled);
+        for (out, pixel) in buffer.iter_mut().zip(scaled.iter()) {
+            *out = (pixel[2] as u32) | ((pixel[1] as u32) << 8) | ((pixel[0] as u32) << 16);
         }
-        window
-            .update_with_buffer(&buffer, SCREEN_SIZE.0, SCREEN_SIZE.1)
-            .unwrap();
+        window.update_with_buffer(&buffer, size.0, size.1).unwrap();
 
         saver.maybe_save(&system);
     }
 }
 
+const BUTTONS: [Button; 8] = [
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+    Button::A,
+    Button::B,
+    Button::Start,
+    Button::Select,
+];
+
+// One row per player, in the same order as BUTTONS. Players 2-4 are only used
+// by SGB games.
+const PLAYER_KEYS: [[Key; 8]; MAX_PLAYERS] = [
+    [
+        Key::Up,
+        Key::Down,
+        Key::Left,
+        Key::Right,
+        Key::Z,
+        Key::X,
+        Key::A,
+        Key::S,
+    ],
+    [
+        Key::I,
+        Key::K,
+        Key::J,
+        Key::L,
+        Key::O,
+        Key::U,
+        Key::P,
+        Key::Y,
+    ],
+    [
+        Key::T,
+        Key::G,
+        Key::F,
+        Key::H,
+        Key::V,
+        Key::C,
+        Key::B,
+        Key::N,
+    ],
+    [
+        Key::NumPad8,
+        Key::NumPad5,
+        Key::NumPad4,
+        Key::NumPad6,
+        Key::NumPad3,
+        Key::NumPad1,
+        Key::NumPadEnter,
+        Key::NumPadPlus,
+    ],
+];
+
 fn process_input(window: &Window, system: &mut System) {
-    for (button, key) in &[
-        (Button::Up, Key::Up),
-        (Button::Down, Key::Down),
-        (Button::Left, Key::Left),
-        (Button::Right, Key::Right),
-        (Button::A, Key::Z),
-        (Button::B, Key::X),
-        (Button::Start, Key::A),
-        (Button::Select, Key::S),
-    ] {
-        if window.is_key_pressed(*key, KeyRepeat::No) {
-            system.activate_button(*button);
-        }
-        if window.is_key_released(*key) {
-            system.deactivate_button(*button);
+    for (player, keys) in PLAYER_KEYS.iter().enumerate() {
+        for (button, key) in BUTTONS.iter().zip(keys.iter()) {
+            if window.is_key_pressed(*key, KeyRepeat::No) {
+                system.activate_button(player, *button);
+            }
+            if window.is_key_released(*key) {
+                system.deactivate_button(player, *button);
+            }
         }
     }
 }
 
-fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver) {
-    let cart_path = args.value_of("rom").unwrap();
+// There's no screen for a GBS, so the window just shows the track being
+// played. Left and right change tracks.
+fn play_gbs(args: &clap::ArgMatches<'static>) {
+    let gbs_path = args.value_of("rom").unwrap();
+    let (sink, mut pacer) = load_audio(args);
+    let mut system =
+        System::new_gbs(File::open(gbs_path).unwrap(), sink, allow_cgb_mode(args)).unwrap();
+    system.set_mmu_pedantic(!args.is_present("no-pedantic-mmu"));
 
-    let cart_file = File::open(cart_path).unwrap();
+    let gbs = system.gbs().unwrap();
+    let (title, track_count) = (gbs.title().to_owned(), gbs.track_count());
+    println!("{} by {}, {}", title, gbs.author(), gbs.copyright());
+    if let Some(t) = args.value_of("track") {
+        let t: u8 = t.parse().unwrap();
+        if !system.play_gbs_track(t.wrapping_sub(1)) {
+            println!("There's no track {}, the file has {}", t, track_count);
+        }
+    }
 
-    let sink: Box<dyn AudioSink + Send> = if !args.is_present("no-audio") {
-        let sink = CpalSink::new().unwrap();
-        Box::new(sink)
-    } else {
-        Box::new(NullSink)
-    };
+    let mut window = Window::new(
+        "j2gbc",
+        SCREEN_SIZE.0,
+        SCREEN_SIZE.1,
+        WindowOptions::default(),
+    )
+    .unwrap();
+    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+    let mut shown_track = None;
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let track = system.gbs_track().unwrap();
+        if window.is_key_pressed(Key::Right, KeyRepeat::Yes) {
+            system.play_gbs_track((track + 1) % track_count);
+        }
+        if window.is_key_pressed(Key::Left, KeyRepeat::Yes) {
+            system.play_gbs_track(track.checked_sub(1).unwrap_or(track_count - 1));
+        }
 
-    let cgb_mode = if let Some(m) = args.value_of("mode") {
+        let track = system.gbs_track();
+        if shown_track != track {
+            shown_track = track;
+            let text = format!("{} [{}/{}]", title, track.unwrap() + 1, track_count);
+            window.set_title(&format!("j2gbc -- {}", text));
+            println!("Playing {}", text);
+        }
+
+        system.run_for_duration(&pacer.next_duration());
+        window.update();
+    }
+}
+
+fn allow_cgb_mode(args: &clap::ArgMatches<'static>) -> bool {
+    if let Some(m) = args.value_of("mode") {
         m == "cgb"
     } else {
         true
+    }
+}
+
+fn load_audio(args: &clap::ArgMatches<'static>) -> (Box<dyn AudioSink + Send>, Pacer) {
+    let mut pacer = Pacer::default();
+    let output: Option<Box<dyn AudioSink + Send>> = if !args.is_present("no-audio") {
+        let sink = CpalSink::new().unwrap();
+        if args.is_present("audio-sync") {
+            let audio = sink.pacer();
+            pacer = Pacer::External(Box::new(move || audio.duration_to_run()));
+        }
+        Some(Box::new(sink))
+    } else {
+        None
     };
+    (frontend_utils::with_capture_sinks(args, output), pacer)
+}
 
-    let mut system = System::new(cart_file, sink, cgb_mode).unwrap();
+fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver, Pacer) {
+    let cart_path = args.value_of("rom").unwrap();
+
+    let cart_file = File::open(cart_path).unwrap();
+
+    let (sink, pacer) = load_audio(args);
+    let mut system = System::new(cart_file, sink, allow_cgb_mode(args)).unwrap();
+    if args.value_of("mode") == Some("sgb") && !system.enable_sgb() {
+        println!("Cart doesn't support the SGB, running as a DMG");
+    }
     system.set_mmu_pedantic(!args.is_present("no-pedantic-mmu"));
+    if let Some(c) = args.value_of("color-correction") {
+        system.set_color_correction(c.parse().unwrap());
+    }
+    if let Some(p) = args.value_of("dmg-palette") {
+        system.set_dmg_palette(p.parse::<DmgPalettePreset>().unwrap().palettes());
+    }
+    if let Some(p) = args.value_of("compat-palette") {
+        system.set_compat_palette(p.parse().unwrap());
+    }
+    if let Some(b) = args.value_of("frame-blend") {
+        system.set_frame_blend(Some(b.parse().unwrap()));
+    }
+    if let Some(path) = args.value_of("record-av") {
+        let (video, audio) = (format!("{}.y4m", path), format!("{}.wav", path));
+        system.start_av_recording(&video, &audio).unwrap();
+        println!("Recording to {} and {}", video, audio);
+    }
+    if let Some(path) = args.value_of("log-vgm") {
+        system.start_vgm_logging(path).unwrap();
+        println!("Logging music to {}", path);
+    }
 
     let save_path = format!("{}.sav", cart_path);
     if let Ok(mut f) = File::open(&save_path) {
@@ -96,5 +258,5 @@ fn load_system(args: &clap::ArgMatches<'static>) -> (System, Saver) {
     }
     let saver = Saver::new(save_path.as_str());
 
-    (system, saver)
+    (system, saver, pacer)
 }